@@ -0,0 +1,10 @@
+-- Chats map a user to the OpenAI thread of the conversation
+CREATE DATABASE IF NOT EXISTS buycycle_chatbot;
+
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.chats (
+    id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX idx_chats_user_id_created_at (user_id, created_at)
+);
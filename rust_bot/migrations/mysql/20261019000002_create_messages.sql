@@ -0,0 +1,10 @@
+-- Log of the messages of a chat, role is user, assistant or error
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.messages (
+    id BIGINT NOT NULL AUTO_INCREMENT,
+    chat_id VARCHAR(64) NOT NULL,
+    role VARCHAR(32) NOT NULL,
+    content MEDIUMTEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX idx_messages_chat_id_created_at (chat_id, created_at)
+);
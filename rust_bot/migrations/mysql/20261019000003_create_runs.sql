@@ -0,0 +1,19 @@
+-- Statistics of every chat request, run_id and chat_id are NULL if the request failed before
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.runs (
+    id BIGINT NOT NULL AUTO_INCREMENT,
+    run_id VARCHAR(64) NULL,
+    chat_id VARCHAR(64) NULL,
+    assistant_id VARCHAR(64) NOT NULL,
+    locale VARCHAR(8) NULL,
+    model VARCHAR(64) NULL,
+    prompt_tokens INT NULL,
+    completion_tokens INT NULL,
+    polls INT NOT NULL DEFAULT 0,
+    tool_calls INT NOT NULL DEFAULT 0,
+    status VARCHAR(32) NOT NULL,
+    latency_ms BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX idx_runs_chat_id (chat_id),
+    INDEX idx_runs_created_at (created_at)
+);
@@ -0,0 +1,15 @@
+-- Tool calls executed for a run, output is NULL and error is set if the tool failed
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.tool_calls (
+    id BIGINT NOT NULL AUTO_INCREMENT,
+    run_id VARCHAR(64) NOT NULL,
+    tool_call_id VARCHAR(64) NOT NULL,
+    tool_name VARCHAR(64) NOT NULL,
+    arguments TEXT NOT NULL,
+    output MEDIUMTEXT NULL,
+    error TEXT NULL,
+    duration_ms BIGINT NOT NULL,
+    truncated BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX idx_tool_calls_run_id (run_id)
+);
@@ -0,0 +1,18 @@
+-- Thread message ID of logged messages, so users can give feedback on assistant messages
+ALTER TABLE buycycle_chatbot.messages
+    ADD COLUMN message_id VARCHAR(64) NULL,
+    ADD INDEX idx_messages_message_id (message_id);
+
+-- Feedback of users on assistant messages, rating is up or down
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.message_feedback (
+    id BIGINT NOT NULL AUTO_INCREMENT,
+    message_id BIGINT NOT NULL,
+    rating VARCHAR(8) NOT NULL,
+    reason VARCHAR(32) NULL,
+    comment TEXT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX idx_message_feedback_rating_created_at (rating, created_at),
+    CONSTRAINT fk_message_feedback_message FOREIGN KEY (message_id)
+        REFERENCES buycycle_chatbot.messages (id) ON DELETE CASCADE
+);
@@ -0,0 +1,14 @@
+-- Audit log of erased users, the user ID is stored as SHA-256 hash so no personal data is kept
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.erasures (
+    id BIGINT NOT NULL AUTO_INCREMENT,
+    user_hash CHAR(64) NOT NULL,
+    chats INT NOT NULL,
+    messages INT NOT NULL,
+    runs INT NOT NULL,
+    tool_calls INT NOT NULL,
+    feedback INT NOT NULL,
+    threads_deleted INT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (id),
+    INDEX idx_erasures_user_hash (user_hash)
+);
@@ -0,0 +1,6 @@
+-- Time the OpenAI thread of a chat was deleted by the retention job, new messages start a new chat
+ALTER TABLE buycycle_chatbot.chats
+    ADD COLUMN thread_deleted_at TIMESTAMP NULL;
+
+CREATE INDEX idx_messages_created_at ON buycycle_chatbot.messages (created_at);
+CREATE INDEX idx_tool_calls_created_at ON buycycle_chatbot.tool_calls (created_at);
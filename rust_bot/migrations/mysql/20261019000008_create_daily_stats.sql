@@ -0,0 +1,16 @@
+-- Daily aggregates of the log tables, tool_usage and languages are JSON objects of counts
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.daily_stats (
+    day DATE NOT NULL,
+    conversations BIGINT NOT NULL,
+    new_chats BIGINT NOT NULL,
+    messages BIGINT NOT NULL,
+    messages_per_chat DOUBLE NOT NULL,
+    runs BIGINT NOT NULL,
+    timeouts BIGINT NOT NULL,
+    errors BIGINT NOT NULL,
+    median_latency_ms BIGINT NULL,
+    tool_usage TEXT NOT NULL,
+    languages TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+    PRIMARY KEY (day)
+);
@@ -0,0 +1,9 @@
+-- Rolling summary of the older messages of a completions chat,
+-- it replaces the messages up to last_message_id in the requests
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.chat_summaries (
+    chat_id VARCHAR(64) NOT NULL,
+    summary MEDIUMTEXT NOT NULL,
+    last_message_id BIGINT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id)
+);
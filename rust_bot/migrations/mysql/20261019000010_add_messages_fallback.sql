@@ -0,0 +1,3 @@
+-- Replies of the fallback, given when a run of the assistant failed or did not finish in time
+ALTER TABLE buycycle_chatbot.messages
+    ADD COLUMN fallback BOOLEAN NOT NULL DEFAULT FALSE;
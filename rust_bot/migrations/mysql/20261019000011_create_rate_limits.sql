@@ -0,0 +1,26 @@
+-- Token buckets of the rate limits per user and IP address, shared by all replicas
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.rate_limit_buckets (
+    bucket VARCHAR(300) NOT NULL,
+    tokens DOUBLE NOT NULL,
+    updated_at_ms BIGINT NOT NULL,
+    PRIMARY KEY (bucket),
+    INDEX idx_rate_limit_buckets_updated_at_ms (updated_at_ms)
+);
+
+-- Chat requests of a user per UTC day
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.run_quotas (
+    user_id VARCHAR(255) NOT NULL,
+    day DATE NOT NULL,
+    runs BIGINT NOT NULL,
+    PRIMARY KEY (user_id, day),
+    INDEX idx_run_quotas_day (day)
+);
+
+-- Slots of the requests answered at the same time, leased until released or expired
+CREATE TABLE IF NOT EXISTS buycycle_chatbot.run_slots (
+    slot INT NOT NULL,
+    lease_id VARCHAR(64) NULL,
+    expires_at_ms BIGINT NULL,
+    PRIMARY KEY (slot),
+    INDEX idx_run_slots_lease_id (lease_id)
+);
@@ -0,0 +1,4 @@
+-- User who gave the feedback, each user has one rating per message and can change it
+ALTER TABLE buycycle_chatbot.message_feedback
+    ADD COLUMN user_id VARCHAR(255) NULL,
+    ADD UNIQUE INDEX idx_message_feedback_message_id_user_id (message_id, user_id);
@@ -0,0 +1,4 @@
+-- File ID of the image uploaded with a user message, images are only served to the user of the chat
+ALTER TABLE buycycle_chatbot.messages
+    ADD COLUMN image_file_id VARCHAR(64) NULL,
+    ADD INDEX idx_messages_image_file_id (image_file_id);
@@ -0,0 +1,13 @@
+-- Chunks of the local retrieval index, embedding is a little endian f32 vector of the model.
+-- content_hash lets a rebuild reuse the embeddings of unchanged chunks
+CREATE TABLE IF NOT EXISTS chunks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    source TEXT NOT NULL,
+    content TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    model TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_chunks_model_content_hash ON chunks (model, content_hash);
@@ -0,0 +1,8 @@
+-- Chats map a user to the OpenAI thread of the conversation
+CREATE TABLE IF NOT EXISTS chats (
+    id TEXT NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_chats_user_id_created_at ON chats (user_id, created_at);
@@ -0,0 +1,19 @@
+-- Statistics of every chat request, run_id and chat_id are NULL if the request failed before
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    run_id TEXT NULL,
+    chat_id TEXT NULL,
+    assistant_id TEXT NOT NULL,
+    locale TEXT NULL,
+    model TEXT NULL,
+    prompt_tokens INTEGER NULL,
+    completion_tokens INTEGER NULL,
+    polls INTEGER NOT NULL DEFAULT 0,
+    tool_calls INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_runs_chat_id ON runs (chat_id);
+CREATE INDEX IF NOT EXISTS idx_runs_created_at ON runs (created_at);
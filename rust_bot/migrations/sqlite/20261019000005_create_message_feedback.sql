@@ -0,0 +1,16 @@
+-- Thread message ID of logged messages, so users can give feedback on assistant messages
+ALTER TABLE messages ADD COLUMN message_id TEXT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_messages_message_id ON messages (message_id);
+
+-- Feedback of users on assistant messages, rating is up or down
+CREATE TABLE IF NOT EXISTS message_feedback (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    rating TEXT NOT NULL,
+    reason TEXT NULL,
+    comment TEXT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_feedback_rating_created_at ON message_feedback (rating, created_at);
@@ -0,0 +1,14 @@
+-- Audit log of erased users, the user ID is stored as SHA-256 hash so no personal data is kept
+CREATE TABLE IF NOT EXISTS erasures (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_hash TEXT NOT NULL,
+    chats INTEGER NOT NULL,
+    messages INTEGER NOT NULL,
+    runs INTEGER NOT NULL,
+    tool_calls INTEGER NOT NULL,
+    feedback INTEGER NOT NULL,
+    threads_deleted INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_erasures_user_hash ON erasures (user_hash);
@@ -0,0 +1,5 @@
+-- Time the OpenAI thread of a chat was deleted by the retention job, new messages start a new chat
+ALTER TABLE chats ADD COLUMN thread_deleted_at TIMESTAMP NULL;
+
+CREATE INDEX IF NOT EXISTS idx_messages_created_at ON messages (created_at);
+CREATE INDEX IF NOT EXISTS idx_tool_calls_created_at ON tool_calls (created_at);
@@ -0,0 +1,15 @@
+-- Daily aggregates of the log tables, tool_usage and languages are JSON objects of counts
+CREATE TABLE IF NOT EXISTS daily_stats (
+    day DATE NOT NULL PRIMARY KEY,
+    conversations INTEGER NOT NULL,
+    new_chats INTEGER NOT NULL,
+    messages INTEGER NOT NULL,
+    messages_per_chat REAL NOT NULL,
+    runs INTEGER NOT NULL,
+    timeouts INTEGER NOT NULL,
+    errors INTEGER NOT NULL,
+    median_latency_ms INTEGER NULL,
+    tool_usage TEXT NOT NULL,
+    languages TEXT NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
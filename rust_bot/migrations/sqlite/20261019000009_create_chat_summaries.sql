@@ -0,0 +1,8 @@
+-- Rolling summary of the older messages of a completions chat,
+-- it replaces the messages up to last_message_id in the requests
+CREATE TABLE IF NOT EXISTS chat_summaries (
+    chat_id TEXT NOT NULL PRIMARY KEY,
+    summary TEXT NOT NULL,
+    last_message_id INTEGER NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,27 @@
+-- Token buckets of the rate limits per user and IP address, shared by all replicas
+CREATE TABLE IF NOT EXISTS rate_limit_buckets (
+    bucket TEXT NOT NULL PRIMARY KEY,
+    tokens REAL NOT NULL,
+    updated_at_ms INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_rate_limit_buckets_updated_at_ms ON rate_limit_buckets (updated_at_ms);
+
+-- Chat requests of a user per UTC day
+CREATE TABLE IF NOT EXISTS run_quotas (
+    user_id TEXT NOT NULL,
+    day DATE NOT NULL,
+    runs INTEGER NOT NULL,
+    PRIMARY KEY (user_id, day)
+);
+
+CREATE INDEX IF NOT EXISTS idx_run_quotas_day ON run_quotas (day);
+
+-- Slots of the requests answered at the same time, leased until released or expired
+CREATE TABLE IF NOT EXISTS run_slots (
+    slot INTEGER NOT NULL PRIMARY KEY,
+    lease_id TEXT NULL,
+    expires_at_ms INTEGER NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_run_slots_lease_id ON run_slots (lease_id);
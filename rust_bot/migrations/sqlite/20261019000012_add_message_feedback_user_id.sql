@@ -0,0 +1,4 @@
+-- User who gave the feedback, each user has one rating per message and can change it
+ALTER TABLE message_feedback ADD COLUMN user_id TEXT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_message_feedback_message_id_user_id ON message_feedback (message_id, user_id);
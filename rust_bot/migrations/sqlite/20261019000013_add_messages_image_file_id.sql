@@ -0,0 +1,4 @@
+-- File ID of the image uploaded with a user message, images are only served to the user of the chat
+ALTER TABLE messages ADD COLUMN image_file_id TEXT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_messages_image_file_id ON messages (image_file_id);
@@ -1,43 +1,105 @@
 use axum::{
-    extract::Form as AxumForm,
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    async_trait,
+    body::Body,
+    extract::{Form as AxumForm, FromRequest, Multipart, Path as AxumPath, Query, Request},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     Extension, Json,
 };
-use chrono::Utc;
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::stream::{Stream, StreamExt};
 use log::info;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 
 use reqwest::{multipart::Form, multipart::Part, Client};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use serde_json::json;
-use std::env;
 
+use crate::auth::{can_use_account_tools, Authenticator, GuestSession, ACCOUNT_TOOLS};
+use crate::backend::{reply_channel, ChatBackend, ReplyStream, SseReader, StreamEvent};
+use crate::cache::{cacheable_answer, AnswerCache, CacheKey, CachedAnswer};
+use crate::config::Config;
+use crate::fallback::Fallback;
+use crate::limits::{Admission, RunPermit};
+use crate::redaction::Redactor;
+use crate::retrieval::{RetrievalIndex, SEARCH_TOOL_NAME};
+use crate::store::ConversationStore;
 use sqlx::Pool;
 use sqlx::{mysql::MySqlPoolOptions, FromRow, MySql, MySqlPool};
 
-// Define a constant for the timeout duration of assistant response
-const TIMEOUT_DURATION: u64 = 100;
+// Reasons a user can give for feedback on an answer
+const FEEDBACK_REASONS: &[&str] = &[
+    "incorrect",
+    "unhelpful",
+    "outdated",
+    "inappropriate",
+    "other",
+];
+const MAX_FEEDBACK_COMMENT_LENGTH: usize = 2000;
+// Number of messages of the conversation exported with negative feedback
+pub(crate) const FEEDBACK_CONVERSATION_LENGTH: i64 = 50;
+
+// Maximum number of characters of a tool output submitted to a run, longer outputs are truncated
+const MAX_TOOL_OUTPUT_LENGTH: usize = 32_000;
+
+// Maximum size of an image uploaded by a user
+pub const MAX_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+// Image types accepted by the vision models
+const ALLOWED_IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// Pages of the buycycle website with the content of the uploaded file search files, used to
+// link citations in the market of the user
+const CITATION_PAGES: &[(&str, &str)] = &[("help_articles.json", "help")];
+// Locale path of the website if the market of the user is not known, as in the instruction
+const DEFAULT_SHOP_PATH: &str = "en-de";
 
 // Define a custom error type that can be converted into an HTTP response.
 #[derive(Debug)]
 pub enum AssistantError {
     DatabaseError(String),
     OpenAIError(String),
+    InvalidInput(String),
+    NotFound(String),
+    Unauthorized(String),
+    TooManyRequests(String),
+}
+impl AssistantError {
+    /// The message of the error, without the kind.
+    pub fn message(&self) -> &str {
+        match self {
+            AssistantError::DatabaseError(msg)
+            | AssistantError::OpenAIError(msg)
+            | AssistantError::InvalidInput(msg)
+            | AssistantError::NotFound(msg)
+            | AssistantError::Unauthorized(msg)
+            | AssistantError::TooManyRequests(msg) => msg,
+        }
+    }
 }
 impl IntoResponse for AssistantError {
     fn into_response(self) -> Response {
         let (status, error_message) = match &self {
             AssistantError::DatabaseError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AssistantError::OpenAIError(msg) => (StatusCode::BAD_GATEWAY, msg),
+            AssistantError::InvalidInput(msg) => (StatusCode::BAD_REQUEST, msg),
+            AssistantError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            AssistantError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            AssistantError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
         };
         let body = Json(json!({ "error": error_message }));
         (status, body).into_response()
@@ -56,11 +118,6 @@ impl From<reqwest::Error> for AssistantError {
         AssistantError::OpenAIError(e.to_string())
     }
 }
-// Define the response type for attaching files to an assistant.
-#[derive(Serialize)]
-struct AttachFilesRequest {
-    file_id: String,
-}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatMessage {
@@ -68,6 +125,8 @@ pub struct ChatMessage {
     created_at: i64,
     role: String,
     content: Vec<Content>,
+    // The run that produced the message, None for messages added by the user
+    run_id: Option<String>,
 }
 // Message content in the Chat
 #[derive(Serialize, Deserialize, Debug)]
@@ -75,26 +134,83 @@ pub struct Content {
     #[serde(rename = "type")]
     content_type: String,
     text: Option<TextContent>,
-    // image: Option<ImageContent>,
-    //
+    image_file: Option<ImageFileContent>,
+}
+// ImageFileContent in the Chat, an image uploaded with purpose vision
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageFileContent {
+    file_id: String,
 }
 // TextContent in the Chat
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TextContent {
     value: String,
+    #[serde(default)]
+    annotations: Vec<Annotation>,
+}
+// Annotation of a text, e.g. a file_citation marker like 【4:0†help_articles.json】
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Annotation {
+    #[serde(rename = "type")]
+    annotation_type: String,
+    // The marker in the text that is annotated
+    text: String,
+    file_citation: Option<FileReference>,
+    file_path: Option<FileReference>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileReference {
+    file_id: String,
+}
+/// A source the assistant cited, referenced in the message text as [index]
+#[derive(Serialize, Clone, Debug)]
+pub struct Citation {
+    pub index: usize,
+    pub file_id: String,
+    pub file_name: Option<String>,
+    pub url: Option<String>,
 }
 // List messages in a chat
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatMessageList {
     object: String,
     data: Vec<ChatMessage>,
+    first_id: Option<String>,
+    last_id: Option<String>,
+    has_more: bool,
 }
 
-// Struct for serializing the message to be sent to OpenAI
-#[derive(Serialize)]
-struct UserMessage {
-    role: String,
-    content: String,
+/// Maximum page size of the OpenAI list messages endpoint
+const MESSAGE_PAGE_LIMIT: u32 = 100;
+
+/// Cursor based pagination parameters of the OpenAI list messages endpoint.
+/// order is "asc" or "desc", after and before are message IDs.
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct MessageListQuery {
+    pub limit: Option<u32>,
+    pub order: Option<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+impl MessageListQuery {
+    /// Checks the parameters against the limits of the OpenAI API.
+    pub fn validate(&self) -> Result<(), AssistantError> {
+        if let Some(limit) = self.limit {
+            if limit == 0 || limit > MESSAGE_PAGE_LIMIT {
+                return Err(AssistantError::InvalidInput(format!(
+                    "limit must be between 1 and {}",
+                    MESSAGE_PAGE_LIMIT
+                )));
+            }
+        }
+        match self.order.as_deref() {
+            None | Some("asc") | Some("desc") => Ok(()),
+            Some(order) => Err(AssistantError::InvalidInput(format!(
+                "order must be asc or desc, got {}",
+                order
+            ))),
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -115,11 +231,16 @@ pub struct AssistantChatResponse {
 }
 
 // Struct for serializing the simplified message format to be sent to the client
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, Debug)]
 pub struct SimplifiedMessage {
+    // ID of the thread message, used to give feedback on assistant messages
+    pub id: Option<String>,
     pub created_at: i64,
     pub role: String,
     pub text: String,
+    pub citations: Vec<Citation>,
+    // File IDs of the images of the message, served to the user by the /images/{file_id} route
+    pub image_file_ids: Vec<String>,
 }
 // Define the response type for the file upload response.
 #[derive(Deserialize)]
@@ -136,12 +257,12 @@ pub struct FileInfo {
 #[derive(Clone)]
 pub struct Ressources {
     db_pool: Pool<MySql>,
+    api_key: String,
     vector_store_id: String,
     pub files_info_file_search: Vec<FileInfo>,
     pub files_info_code_interpreter: Vec<FileInfo>,
     folder_path_file_search: String,
     folder_path_code_interpreter: String,
-    scrape_urls: Vec<String>,
     instruction_file_path: String,
     instruction: String,
 }
@@ -158,19 +279,19 @@ struct Bike {
 impl Ressources {
     pub fn new(
         db_pool: Pool<MySql>,
+        api_key: String,
         folder_path_file_search: String,
         folder_path_code_interpreter: String,
-        scrape_urls: Vec<String>,
         instruction_file_path: String,
     ) -> Self {
         Ressources {
             db_pool,
+            api_key,
             vector_store_id: String::new(),
             files_info_file_search: Vec::new(),
             files_info_code_interpreter: Vec::new(),
             folder_path_file_search,
             folder_path_code_interpreter,
-            scrape_urls,
             instruction_file_path,
             instruction: String::new(),
         }
@@ -213,8 +334,7 @@ impl Ressources {
         Ok(())
     }
     pub async fn upload_files_search(&mut self) -> Result<(), AssistantError> {
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let client = Client::new();
         let paths = fs::read_dir(Path::new(&self.folder_path_file_search))
             .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
@@ -248,7 +368,7 @@ impl Ressources {
                 let response = client
                     .post("https://api.openai.com/v1/files")
                     .header("OpenAI-Beta", "assistants=v2")
-                    .bearer_auth(&api_key)
+                    .bearer_auth(api_key)
                     .multipart(form)
                     .send()
                     .await;
@@ -282,8 +402,7 @@ impl Ressources {
         Ok(())
     }
     pub async fn upload_code_interpreter(&mut self) -> Result<(), AssistantError> {
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let client = Client::new();
         let paths = fs::read_dir(Path::new(&self.folder_path_code_interpreter))
             .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
@@ -317,7 +436,7 @@ impl Ressources {
                 let response = client
                     .post("https://api.openai.com/v1/files")
                     .header("OpenAI-Beta", "assistants=v2")
-                    .bearer_auth(&api_key)
+                    .bearer_auth(api_key)
                     .multipart(form)
                     .send()
                     .await;
@@ -368,8 +487,7 @@ impl Ressources {
 
         });
         // Get the OpenAI API key from the environment
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         // Create an HTTP client
         let client = Client::new();
         // Make the POST request to create the vector store
@@ -423,8 +541,7 @@ impl Ressources {
     }
 
     pub async fn delete(&mut self) -> Result<(), AssistantError> {
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let client = Client::new();
         for file_info in self
             .files_info_file_search
@@ -436,7 +553,7 @@ impl Ressources {
                     "https://api.openai.com/v1/files/{}",
                     file_info.file_id
                 ))
-                .bearer_auth(&api_key)
+                .bearer_auth(api_key)
                 .send()
                 .await;
             match response {
@@ -465,6 +582,7 @@ pub struct Assistant {
     name: String,
     model: String,
     instruction: String,
+    api_key: String,
 }
 impl Assistant {
     /// create an OpenAI assistant and set the assistant's ID
@@ -474,8 +592,7 @@ impl Assistant {
         vector_store_id: String,
     ) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         // Extract file_ids from the FileInfo objects
         let file_ids_code_interpreter: Vec<String> = files_info_code_interpreter
             .into_iter()
@@ -502,7 +619,7 @@ impl Assistant {
         let response = client
             .post("https://api.openai.com/v1/assistants")
             .header("OpenAI-Beta", "assistants=v2")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .json(&payload)
             .send()
             .await;
@@ -538,8 +655,7 @@ impl Assistant {
         vector_store_id: String,
     ) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let file_ids_code_interpreter: Vec<String> = files_info_code_interpreter
             .into_iter()
             .map(|file_info| file_info.file_id)
@@ -561,7 +677,7 @@ impl Assistant {
         let response = client
             .post("https://api.openai.com/v1/assistants")
             .header("OpenAI-Beta", "assistants=v2")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .json(&payload)
             .send()
             .await;
@@ -594,13 +710,12 @@ impl Assistant {
 
     /// Delete the OpenAI assistant with the given ID
     pub async fn delete(&self) -> Result<(), AssistantError> {
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let client = Client::new();
         let response = client
             .delete(format!("https://api.openai.com/v1/assistants/{}", self.id))
             .header("OpenAI-Beta", "assistants=v2")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .send()
             .await;
         match response {
@@ -619,8 +734,7 @@ impl Assistant {
     /// this overwrites the assistant's instruction a str
     pub async fn update_instruction(&mut self, instruction: &str) -> Result<(), AssistantError> {
         // Ensure the API key is set
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
 
         let client = Client::new();
 
@@ -631,10 +745,10 @@ impl Assistant {
 
         // Send the request to update the assistant
         let response = client
-            .patch(&format!("https://api.openai.com/v1/assistants/{}", self.id))
+            .patch(format!("https://api.openai.com/v1/assistants/{}", self.id))
             .header("Content-Type", "application/json")
             .header("OpenAI-Beta", "assistants=v2")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .json(&payload)
             .send()
             .await;
@@ -650,24 +764,22 @@ impl Assistant {
         }
     }
 }
-/// scrape urls and upload the resulting files to OpenAI
+/// Export the bikes and upload the resulting files to OpenAI
+/// The folders, the instruction file and the API key are taken from the configuration.
 pub async fn create_ressources(
     db_pool: Pool<MySql>,
-    folder_path_file_search: &str,
-    folder_path_code_interpreter: &str,
-    scrape_urls: Vec<String>,
-    instruction_file_path: &str,
+    config: &Config,
 ) -> Result<Ressources, AssistantError> {
     // Initialize the Files struct directly
     let mut files = Ressources {
-        db_pool: db_pool,
+        db_pool,
+        api_key: config.openai.api_key.clone(),
         vector_store_id: String::new(),
         files_info_file_search: Vec::new(), // Use files_info to store FileInfo objects
         files_info_code_interpreter: Vec::new(), // Use files_info to store FileInfo objects
-        folder_path_file_search: folder_path_file_search.to_string(),
-        folder_path_code_interpreter: folder_path_code_interpreter.to_string(),
-        scrape_urls, // Provided scrape URLs
-        instruction_file_path: instruction_file_path.to_string(),
+        folder_path_file_search: config.assistant.file_search_folder.clone(),
+        folder_path_code_interpreter: config.assistant.code_interpreter_folder.clone(),
+        instruction_file_path: config.assistant.instruction_file.clone(),
         instruction: String::new(),
     };
     // Get bikes from the database and save them to a JSON file
@@ -679,16 +791,18 @@ pub async fn create_ressources(
     files.create_instruction().await?;
     Ok(files)
 }
+/// Creates the assistant with the model of the configuration.
 pub async fn create_assistant(
     assistant_name: &str,
-    model: &str,
+    config: &Config,
     ressources: Ressources,
 ) -> Result<Assistant, AssistantError> {
     let mut assistant = Assistant {
         id: String::new(),
         name: assistant_name.to_string(),
-        model: model.to_string(),
+        model: config.openai.model.clone(),
         instruction: ressources.instruction.clone(),
+        api_key: config.openai.api_key.clone(),
     };
     // Initialize the assistant by creating it on the OpenAI platform
     assistant
@@ -703,7 +817,12 @@ pub async fn create_assistant(
 
 struct Chat {
     id: String,
+    api_key: String,
     messages: Vec<SimplifiedMessage>,
+    // Files the assistant can cite, used to resolve the citations of the messages
+    files: Vec<FileInfo>,
+    // Context of the user, the citations link the pages of their market
+    context: RunContext,
 }
 
 impl Chat {
@@ -711,12 +830,11 @@ impl Chat {
     /// if yes, return chat_id, if no, initialize chat, save user_id, chat_idto db table chats and return chat_id
     pub async fn initialize(&mut self) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let response = client
             .post("https://api.openai.com/v1/threads")
             .header("Content-Type", "application/json")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .header("OpenAI-Beta", "assistants=v2")
             .send()
             .await;
@@ -746,56 +864,45 @@ impl Chat {
             ))),
         }
     }
-    pub async fn get_messages(&mut self, only_last: bool) -> Result<(), AssistantError> {
+    /// Retrieves a single page of messages of the chat.
+    /// If a run_id is given, only the messages produced by that run are listed.
+    pub async fn get_messages_page(
+        &self,
+        query: &MessageListQuery,
+        run_id: Option<&str>,
+    ) -> Result<ChatMessageList, AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
+        let limit = query.limit.unwrap_or(MESSAGE_PAGE_LIMIT).to_string();
+        let mut params = vec![
+            ("limit", limit.as_str()),
+            ("order", query.order.as_deref().unwrap_or("asc")),
+        ];
+        if let Some(after) = &query.after {
+            params.push(("after", after));
+        }
+        if let Some(before) = &query.before {
+            params.push(("before", before));
+        }
+        if let Some(run_id) = run_id {
+            params.push(("run_id", run_id));
+        }
         let response = client
-            .get(&format!(
+            .get(format!(
                 "https://api.openai.com/v1/threads/{}/messages",
                 self.id
             ))
+            .query(&params)
             .header("Content-Type", "application/json")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .header("OpenAI-Beta", "assistants=v2")
             .send()
             .await;
         match response {
             Ok(res) if res.status().is_success() => {
-                let message_list_response = res.json::<ChatMessageList>().await.map_err(|_| {
+                res.json::<ChatMessageList>().await.map_err(|_| {
                     AssistantError::OpenAIError("Failed to parse response from OpenAI".to_string())
-                })?;
-                let mut simplified_messages: Vec<SimplifiedMessage> = message_list_response
-                    .data
-                    .into_iter()
-                    .filter_map(|msg| {
-                        if let Some(content) =
-                            msg.content.into_iter().find(|c| c.content_type == "text")
-                        {
-                            if let Some(text_content) = content.text {
-                                return Some(SimplifiedMessage {
-                                    created_at: msg.created_at,
-                                    role: msg.role,
-                                    text: text_content.value,
-                                });
-                            }
-                        }
-                        None
-                    })
-                    .collect();
-                if only_last {
-                    // Get the last message based on created_at without sorting
-                    if let Some(last_message) =
-                        simplified_messages.iter().max_by_key(|m| m.created_at)
-                    {
-                        simplified_messages = vec![last_message.clone()];
-                    }
-                } else {
-                    // Sort by created_at in ascending order only if we need the full list
-                    simplified_messages.sort_by_key(|m| m.created_at);
-                }
-                self.messages = simplified_messages;
-                Ok(())
+                })
             }
             Ok(res) => {
                 let error_message = res.text().await.unwrap_or_default();
@@ -804,21 +911,60 @@ impl Chat {
             Err(e) => Err(AssistantError::OpenAIError(e.to_string())),
         }
     }
-    pub async fn add_message(&self, message: &str, role: &str) -> Result<(), AssistantError> {
+    /// Retrieves all messages of the chat starting at the cursor of the query,
+    /// following the after cursor until the API reports no more pages.
+    /// If a run_id is given, only the assistant messages produced by that run are kept,
+    /// which can be several messages for a multi-part answer.
+    pub async fn get_messages(
+        &mut self,
+        query: &MessageListQuery,
+        run_id: Option<&str>,
+    ) -> Result<(), AssistantError> {
+        let mut query = query.clone();
+        let mut messages = Vec::new();
+        loop {
+            let page = self.get_messages_page(&query, run_id).await?;
+            messages.extend(page.data);
+            match page.last_id {
+                Some(last_id) if page.has_more => query.after = Some(last_id),
+                _ => break,
+            }
+        }
+        // Keep the order of the API, the created_at timestamps only have second resolution
+        self.messages = messages
+            .into_iter()
+            .filter(|msg| run_id.is_none() || msg.role == "assistant")
+            .filter_map(|msg| simplify_message(msg, &self.files, &self.context))
+            .collect();
+        Ok(())
+    }
+    /// Adds a message to the chat, optionally with an image uploaded with purpose vision.
+    pub async fn add_message(
+        &self,
+        message: &str,
+        role: &str,
+        image_file_id: Option<&str>,
+    ) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
-        let payload = UserMessage {
-            role: role.to_string(),
-            content: message.to_string(),
-        };
+        let api_key = &self.api_key;
+        let mut content = Vec::new();
+        if !message.is_empty() {
+            content.push(json!({ "type": "text", "text": message }));
+        }
+        if let Some(file_id) = image_file_id {
+            content.push(json!({ "type": "image_file", "image_file": { "file_id": file_id } }));
+        }
+        let payload = json!({
+            "role": role,
+            "content": content,
+        });
         let response = client
-            .post(&format!(
+            .post(format!(
                 "https://api.openai.com/v1/threads/{}/messages",
                 self.id
             ))
             .header("Content-Type", "application/json")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .header("OpenAI-Beta", "assistants=v2")
             .json(&payload)
             .send()
@@ -835,6 +981,145 @@ impl Chat {
             ))),
         }
     }
+    /// Deletes the thread of the chat on OpenAI, see `is_thread_id`.
+    /// Returns false if the thread does not exist anymore.
+    pub async fn delete(&self) -> Result<bool, AssistantError> {
+        let client = Client::new();
+        let api_key = &self.api_key;
+        let response = client
+            .delete(format!("https://api.openai.com/v1/threads/{}", self.id))
+            .bearer_auth(api_key)
+            .header("OpenAI-Beta", "assistants=v2")
+            .send()
+            .await;
+        match response {
+            Ok(res) if res.status().is_success() => {
+                info!("Thread deleted with ID: {}", self.id);
+                Ok(true)
+            }
+            Ok(res) if res.status() == reqwest::StatusCode::NOT_FOUND => Ok(false),
+            Ok(res) => {
+                let error_message = res.text().await.unwrap_or_default();
+                Err(AssistantError::OpenAIError(error_message))
+            }
+            Err(e) => Err(AssistantError::OpenAIError(format!(
+                "Failed to send request to OpenAI: {}",
+                e
+            ))),
+        }
+    }
+}
+
+/// Whether a chat ID is the ID of an OpenAI thread, the chats of the completions backend
+/// have no thread.
+pub fn is_thread_id(chat_id: &str) -> bool {
+    chat_id.starts_with("thread_")
+}
+
+/// Convert a thread message into the simplified format, joining all text parts of the message
+/// and replacing the citation markers with numbered references.
+/// Returns None if the message has neither text nor image content.
+pub fn simplify_message(
+    msg: ChatMessage,
+    files: &[FileInfo],
+    context: &RunContext,
+) -> Option<SimplifiedMessage> {
+    let mut texts = Vec::new();
+    let mut citations = Vec::new();
+    let mut image_file_ids = Vec::new();
+    for content in msg.content {
+        match (
+            content.content_type.as_str(),
+            content.text,
+            content.image_file,
+        ) {
+            ("text", Some(text_content), _) => texts.push(resolve_citations(
+                text_content,
+                files,
+                context,
+                &mut citations,
+            )),
+            ("image_file", _, Some(image_file)) => image_file_ids.push(image_file.file_id),
+            _ => {}
+        }
+    }
+    if texts.is_empty() && image_file_ids.is_empty() {
+        return None;
+    }
+    Some(SimplifiedMessage {
+        id: Some(msg.id),
+        created_at: msg.created_at,
+        role: msg.role,
+        text: texts.join("\n\n"),
+        citations,
+        image_file_ids,
+    })
+}
+/// Replace the file_citation markers of a text with [index] references, linked to the page
+/// of the cited file in the market of the user if there is one. The file is identified by the
+/// name in the marker, the IDs change when the files are uploaded again; the files are only
+/// used for markers without a name. Citations of the same file share an index.
+pub fn resolve_citations(
+    text_content: TextContent,
+    files: &[FileInfo],
+    context: &RunContext,
+    citations: &mut Vec<Citation>,
+) -> String {
+    let mut text = text_content.value;
+    for annotation in text_content.annotations {
+        let file_id = match (
+            annotation.annotation_type.as_str(),
+            annotation.file_citation,
+        ) {
+            ("file_citation", Some(file_citation)) => file_citation.file_id,
+            _ => continue,
+        };
+        let file_name = marker_file_name(&annotation.text).or_else(|| {
+            files
+                .iter()
+                .find(|f| f.file_id == file_id)
+                .map(|f| f.file_name.clone())
+        });
+        let cited = citations.iter().find(|c| match &file_name {
+            Some(name) => c.file_name.as_ref() == Some(name),
+            None => c.file_id == file_id,
+        });
+        let citation = match cited {
+            Some(citation) => citation.clone(),
+            None => {
+                let citation = Citation {
+                    index: citations.len() + 1,
+                    file_id,
+                    url: file_name
+                        .as_deref()
+                        .and_then(|name| citation_url(name, context)),
+                    file_name,
+                };
+                citations.push(citation.clone());
+                citation
+            }
+        };
+        let reference = match &citation.url {
+            Some(url) => format!("[{}]({})", citation.index, url),
+            None => format!("[{}]", citation.index),
+        };
+        text = text.replacen(&annotation.text, &reference, 1);
+    }
+    text
+}
+/// File name of a citation marker like 【4:0†help_articles.json】.
+fn marker_file_name(marker: &str) -> Option<String> {
+    let (_, name) = marker.split_once('†')?;
+    let name = name.trim_end_matches('】').trim();
+    (!name.is_empty()).then(|| name.to_string())
+}
+/// URL of the page of the given uploaded file on the website of the market of the user.
+fn citation_url(file_name: &str, context: &RunContext) -> Option<String> {
+    let (_, page) = CITATION_PAGES.iter().find(|(name, _)| *name == file_name)?;
+    let shop_path = context
+        .shop_path()
+        .unwrap_or_else(|| DEFAULT_SHOP_PATH.to_string());
+    Some(format!("https://buycycle.com/{}/{}", shop_path, page))
 }
 
 //add a create_chat function that returns a chat struct
@@ -854,56 +1139,525 @@ impl DB {
     }
 }
 
+/// Feedback of a user on an assistant message.
+#[derive(Deserialize, Debug)]
+pub struct MessageFeedback {
+    // Ignored if authentication is enabled, the user of the session is used then
+    #[serde(default)]
+    pub user_id: Option<String>,
+    // "up" or "down"
+    pub rating: String,
+    // One of FEEDBACK_REASONS
+    pub reason: Option<String>,
+    pub comment: Option<String>,
+}
+impl MessageFeedback {
+    pub fn validate(&self) -> Result<(), AssistantError> {
+        if !["up", "down"].contains(&self.rating.as_str()) {
+            return Err(AssistantError::InvalidInput(
+                "rating must be up or down".to_string(),
+            ));
+        }
+        if let Some(reason) = self.reason.as_deref() {
+            if !FEEDBACK_REASONS.contains(&reason) {
+                return Err(AssistantError::InvalidInput(format!(
+                    "reason must be one of {}",
+                    FEEDBACK_REASONS.join(", ")
+                )));
+            }
+        }
+        if self
+            .comment
+            .as_ref()
+            .is_some_and(|comment| comment.chars().count() > MAX_FEEDBACK_COMMENT_LENGTH)
+        {
+            return Err(AssistantError::InvalidInput(format!(
+                "comment must be at most {} characters",
+                MAX_FEEDBACK_COMMENT_LENGTH
+            )));
+        }
+        Ok(())
+    }
+}
+/// A message as logged in the messages table.
+#[derive(Serialize, FromRow, Debug)]
+pub struct LoggedMessage {
+    pub id: i64,
+    // ID of the message in the thread or of the completion, None for messages of the user
+    pub message_id: Option<String>,
+    pub role: String,
+    pub content: String,
+    // Reply of the fallback after a run of the assistant failed
+    pub fallback: bool,
+    pub created_at: DateTime<Utc>,
+}
+/// Summary of the older messages of a completions chat, up to and including last_message_id.
+#[derive(Serialize, FromRow, Debug, Clone)]
+pub struct ChatSummary {
+    pub summary: String,
+    pub last_message_id: i64,
+}
+/// Negative feedback with the conversation that led to the rated message.
+#[derive(Serialize, FromRow, Debug)]
+pub struct NegativeFeedback {
+    pub feedback_id: i64,
+    pub reason: Option<String>,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // Database ID of the rated message
+    pub message_id: i64,
+    pub chat_id: String,
+    #[sqlx(skip)]
+    pub conversation: Vec<LoggedMessage>,
+}
+
+/// A chat of a user with everything logged about it, exported for data subject requests.
+#[derive(Serialize, FromRow, Debug)]
+pub struct ChatExport {
+    pub chat_id: String,
+    pub created_at: DateTime<Utc>,
+    #[sqlx(skip)]
+    pub messages: Vec<LoggedMessage>,
+    #[sqlx(skip)]
+    pub runs: Vec<LoggedRun>,
+    #[sqlx(skip)]
+    pub tool_calls: Vec<LoggedToolCall>,
+    #[sqlx(skip)]
+    pub feedback: Vec<LoggedFeedback>,
+    // Summary of the older messages, kept by the completions backend
+    #[sqlx(skip)]
+    pub summary: Option<String>,
+    // Messages of the OpenAI thread, empty if the thread does not exist anymore
+    #[sqlx(skip)]
+    pub thread: Vec<SimplifiedMessage>,
+}
+/// A run as logged in the runs table.
+#[derive(Serialize, FromRow, Debug)]
+pub struct LoggedRun {
+    pub run_id: Option<String>,
+    pub locale: Option<String>,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<i32>,
+    pub completion_tokens: Option<i32>,
+    pub status: String,
+    pub created_at: DateTime<Utc>,
+}
+/// A tool call as logged in the tool_calls table.
+#[derive(Serialize, FromRow, Debug)]
+pub struct LoggedToolCall {
+    pub run_id: String,
+    pub tool_name: String,
+    pub arguments: String,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+/// Feedback as logged in the message_feedback table.
+#[derive(Serialize, FromRow, Debug)]
+pub struct LoggedFeedback {
+    // Database ID of the rated message
+    pub message_id: i64,
+    pub rating: String,
+    pub reason: Option<String>,
+    pub comment: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+/// Everything stored about a user, returned by the export of the admin API.
+#[derive(Serialize, Debug)]
+pub struct UserDataExport {
+    pub user_id: String,
+    pub exported_at: DateTime<Utc>,
+    pub chats: Vec<ChatExport>,
+}
+/// Number of rows and threads deleted by the erasure of a user.
+#[derive(Serialize, Debug, Default)]
+pub struct ErasureReport {
+    pub chats: u64,
+    pub messages: u64,
+    pub runs: u64,
+    pub tool_calls: u64,
+    pub feedback: u64,
+    pub threads_deleted: u64,
+    // IDs of the threads that could not be deleted on OpenAI, the logged data is erased anyway
+    pub threads_failed: Vec<String>,
+}
+/// Number of rows and threads deleted by a run of the retention job.
+#[derive(Debug, Default)]
+pub struct RetentionReport {
+    pub messages: u64,
+    pub tool_calls: u64,
+    pub threads_deleted: u64,
+    // Threads that could not be deleted, they are retried in the next run
+    pub threads_failed: u64,
+    // Rate limit buckets and daily run counts
+    pub rate_limits: u64,
+}
+/// Aggregates of the log tables for a single day.
+#[derive(Serialize, Debug)]
+pub struct DailyStats {
+    pub day: NaiveDate,
+    // Chats with at least one run on the day
+    pub conversations: i64,
+    pub new_chats: i64,
+    pub messages: i64,
+    pub messages_per_chat: f64,
+    pub runs: i64,
+    pub timeouts: i64,
+    // Runs that neither completed nor timed out
+    pub errors: i64,
+    pub median_latency_ms: Option<i64>,
+    // Number of calls per tool
+    pub tool_usage: BTreeMap<String, i64>,
+    // Number of runs per language of the locale, "unknown" without a locale
+    pub languages: BTreeMap<String, i64>,
+}
+// Row of the daily_stats table, the counts per tool and language are stored as JSON
+#[derive(FromRow)]
+pub(crate) struct DailyStatsRow {
+    day: NaiveDate,
+    conversations: i64,
+    new_chats: i64,
+    messages: i64,
+    messages_per_chat: f64,
+    runs: i64,
+    timeouts: i64,
+    errors: i64,
+    median_latency_ms: Option<i64>,
+    tool_usage: String,
+    languages: String,
+}
+impl From<DailyStatsRow> for DailyStats {
+    fn from(row: DailyStatsRow) -> Self {
+        DailyStats {
+            day: row.day,
+            conversations: row.conversations,
+            new_chats: row.new_chats,
+            messages: row.messages,
+            messages_per_chat: row.messages_per_chat,
+            runs: row.runs,
+            timeouts: row.timeouts,
+            errors: row.errors,
+            median_latency_ms: row.median_latency_ms,
+            tool_usage: serde_json::from_str(&row.tool_usage).unwrap_or_default(),
+            languages: serde_json::from_str(&row.languages).unwrap_or_default(),
+        }
+    }
+}
+impl DailyStats {
+    /// Formats the stats as CSV with a header line,
+    /// the counts per tool and language are JSON encoded columns.
+    pub fn to_csv(stats: &[DailyStats]) -> String {
+        let mut csv = String::from(
+            "day,conversations,new_chats,messages,messages_per_chat,runs,timeouts,errors,median_latency_ms,tool_usage,languages\n",
+        );
+        for day in stats {
+            csv.push_str(&format!(
+                "{},{},{},{},{:.2},{},{},{},{},{},{}\n",
+                day.day,
+                day.conversations,
+                day.new_chats,
+                day.messages,
+                day.messages_per_chat,
+                day.runs,
+                day.timeouts,
+                day.errors,
+                day.median_latency_ms
+                    .map(|latency| latency.to_string())
+                    .unwrap_or_default(),
+                csv_field(&json!(day.tool_usage).to_string()),
+                csv_field(&json!(day.languages).to_string()),
+            ));
+        }
+        csv
+    }
+}
+/// Quotes a CSV field, doubling the quotes inside.
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+#[derive(Clone)]
 pub struct LOG {
-    db_pool: Pool<MySql>,
+    store: Arc<dyn ConversationStore>,
+    // Personal data in texts is protected before it is persisted
+    redactor: Arc<Redactor>,
 }
 impl LOG {
-   /// Retrieves the chat ID for a given user ID from the database.
+    pub fn new(store: Arc<dyn ConversationStore>, redactor: Arc<Redactor>) -> Self {
+        LOG { store, redactor }
+    }
+    /// Masks the personal data of a text before it is written to the application log.
+    pub fn mask(&self, text: &str) -> String {
+        self.redactor.mask(text)
+    }
+    /// Whether the logged texts are returned with their personal data, else it is masked.
+    pub fn reveals_personal_data(&self) -> bool {
+        self.redactor.reversible()
+    }
+    /// Retrieves the chat ID for a given user ID from the database.
+    /// Chats whose thread was deleted by the retention job are skipped.
     pub async fn get_chat_id(&self, user_id: &str) -> Result<Option<String>, AssistantError> {
-        let result = sqlx::query!(
-            "SELECT id FROM buycycle_chatbot.chats WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
-            user_id
-        )
-        .fetch_optional(&self.db_pool)
-        .await
-        .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
-        Ok(result.map(|row| row.id))
+        self.store.get_chat_id(user_id).await
     }
     /// Saves a new chat ID for a user into the database.
     pub async fn save_chat_id(&self, user_id: &str, chat_id: &str) -> Result<(), AssistantError> {
-        sqlx::query!(
-            "INSERT INTO buycycle_chatbot.chats (id, user_id) VALUES (?, ?)",
-            chat_id,
-            user_id
-        )
-        .execute(&self.db_pool)
-        .await
-        .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
-        Ok(())
+        self.store.save_chat_id(user_id, chat_id).await
     }
     /// Saves a message to the database for a given chat ID.
+    /// message_id is the ID of the thread message, if the message was added to the thread.
     pub async fn save_message_to_db(
         &self,
         chat_id: &str,
         role: &str,
         message: &str,
+        message_id: Option<&str>,
     ) -> Result<(), AssistantError> {
-        sqlx::query!(
-            "INSERT INTO buycycle_chatbot.messages (chat_id, role, content) VALUES (?, ?, ?)",
-            chat_id,
-            role,
-            message
-        )
-        .execute(&self.db_pool)
-        .await
-        .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
-        Ok(())
+        let message = self.redactor.protect(message);
+        self.store
+            .save_message(chat_id, role, &message, message_id)
+            .await
+    }
+    /// Saves a message of a user with an uploaded image, only the user can view the image.
+    pub async fn save_image_message_to_db(
+        &self,
+        chat_id: &str,
+        message: &str,
+        image_file_id: &str,
+    ) -> Result<(), AssistantError> {
+        let message = self.redactor.protect(message);
+        self.store
+            .save_image_message(chat_id, &message, image_file_id)
+            .await
+    }
+    /// Saves a reply of the fallback, personal data is protected like in other messages.
+    pub async fn save_fallback_message_to_db(
+        &self,
+        chat_id: &str,
+        message: &str,
+    ) -> Result<(), AssistantError> {
+        let message = self.redactor.protect(message);
+        self.store.save_fallback_message(chat_id, &message).await
+    }
+    /// Retrieves the database ID of a logged assistant message in a chat of the user by its
+    /// thread message ID.
+    pub async fn get_assistant_message_id(
+        &self,
+        user_id: &str,
+        message_id: &str,
+    ) -> Result<Option<i64>, AssistantError> {
+        self.store
+            .get_assistant_message_id(user_id, message_id)
+            .await
+    }
+    /// Saves the feedback of a user on a logged assistant message, replacing the previous
+    /// feedback of the user on it.
+    pub async fn save_feedback(
+        &self,
+        message_db_id: i64,
+        user_id: &str,
+        feedback: &MessageFeedback,
+    ) -> Result<(), AssistantError> {
+        let feedback = MessageFeedback {
+            user_id: None,
+            rating: feedback.rating.clone(),
+            reason: feedback.reason.clone(),
+            comment: feedback
+                .comment
+                .as_ref()
+                .map(|comment| self.redactor.protect(comment)),
+        };
+        self.store
+            .save_feedback(message_db_id, user_id, &feedback)
+            .await
+    }
+    /// Retrieves the negative feedback given since the given date, with the conversation
+    /// up to and including the rated message. Personal data protected by the redactor is
+    /// revealed again.
+    pub async fn get_negative_feedback(
+        &self,
+        since: NaiveDate,
+    ) -> Result<Vec<NegativeFeedback>, AssistantError> {
+        let mut entries = self.store.get_negative_feedback(since).await?;
+        for entry in entries.iter_mut() {
+            entry.comment = entry.comment.as_deref().map(|c| self.redactor.reveal(c));
+            for message in entry.conversation.iter_mut() {
+                message.content = self.redactor.reveal(&message.content);
+            }
+        }
+        Ok(entries)
+    }
+    /// Saves an executed tool call into the database.
+    pub async fn save_tool_call(&self, record: &ToolCallRecord) -> Result<(), AssistantError> {
+        let record = ToolCallRecord {
+            arguments: self.redactor.protect(&record.arguments),
+            output: record
+                .output
+                .as_ref()
+                .map(|output| self.redactor.protect(output)),
+            error: record
+                .error
+                .as_ref()
+                .map(|error| self.redactor.protect(error)),
+            ..record.clone()
+        };
+        self.store.save_tool_call(&record).await
+    }
+    /// Saves the statistics of a chat request into the database.
+    pub async fn save_run(&self, record: &RunRecord) -> Result<(), AssistantError> {
+        self.store.save_run(record).await
+    }
+    /// Retrieves the logged messages of a chat, personal data protected by the redactor
+    /// is revealed again.
+    pub async fn get_messages(&self, chat_id: &str) -> Result<Vec<LoggedMessage>, AssistantError> {
+        let mut messages = self.store.get_messages(chat_id).await?;
+        for message in messages.iter_mut() {
+            message.content = self.redactor.reveal(&message.content);
+        }
+        Ok(messages)
+    }
+    /// Retrieves the summary of the older messages of a chat, revealing its personal data.
+    pub async fn get_chat_summary(
+        &self,
+        chat_id: &str,
+    ) -> Result<Option<ChatSummary>, AssistantError> {
+        let summary = self.store.get_chat_summary(chat_id).await?;
+        Ok(summary.map(|summary| ChatSummary {
+            summary: self.redactor.reveal(&summary.summary),
+            ..summary
+        }))
+    }
+    /// Saves the summary of the older messages of a chat, protecting its personal data.
+    pub async fn save_chat_summary(
+        &self,
+        chat_id: &str,
+        summary: &ChatSummary,
+    ) -> Result<(), AssistantError> {
+        let summary = ChatSummary {
+            summary: self.redactor.protect(&summary.summary),
+            ..summary.clone()
+        };
+        self.store.save_chat_summary(chat_id, &summary).await
+    }
+    /// Retrieves all chats of a user with their logged messages, runs, tool calls and feedback.
+    /// Personal data protected by the redactor is revealed again.
+    pub async fn get_user_chats(&self, user_id: &str) -> Result<Vec<ChatExport>, AssistantError> {
+        let mut chats = self.store.get_user_chats(user_id).await?;
+        for chat in chats.iter_mut() {
+            for message in chat.messages.iter_mut() {
+                message.content = self.redactor.reveal(&message.content);
+            }
+            for tool_call in chat.tool_calls.iter_mut() {
+                tool_call.arguments = self.redactor.reveal(&tool_call.arguments);
+                tool_call.output = tool_call.output.as_deref().map(|o| self.redactor.reveal(o));
+                tool_call.error = tool_call.error.as_deref().map(|e| self.redactor.reveal(e));
+            }
+            for feedback in chat.feedback.iter_mut() {
+                feedback.comment = feedback.comment.as_deref().map(|c| self.redactor.reveal(c));
+            }
+            chat.summary = chat.summary.as_deref().map(|s| self.redactor.reveal(s));
+        }
+        Ok(chats)
+    }
+}
+/// Aggregates the log tables of a day into the daily_stats table and returns the stats.
+/// Aggregating a day again replaces its stats, so the current day can be updated repeatedly.
+pub async fn aggregate_daily_stats(
+    store: &dyn ConversationStore,
+    day: NaiveDate,
+) -> Result<DailyStats, AssistantError> {
+    let stats = store.compute_daily_stats(day).await?;
+    store.save_daily_stats(&stats).await?;
+    Ok(stats)
+}
+/// Applies the retention policy: deletes old messages and tool calls from the log database
+/// and the OpenAI threads of inactive chats. Runs are kept for the statistics.
+pub async fn apply_retention(
+    store: &dyn ConversationStore,
+    config: &Config,
+) -> Result<RetentionReport, AssistantError> {
+    let policy = &config.retention;
+    let mut report = RetentionReport::default();
+    if policy.threads_days > 0 {
+        let cutoff = Utc::now() - chrono::Days::new(policy.threads_days);
+        loop {
+            let chat_ids = store
+                .get_inactive_chat_ids(cutoff, policy.batch_size)
+                .await?;
+            let batch_len = chat_ids.len() as u64;
+            for chat_id in chat_ids {
+                // Chats of the completions backend have no thread on OpenAI
+                if is_thread_id(&chat_id) {
+                    let chat = Chat {
+                        id: chat_id.clone(),
+                        api_key: config.openai.api_key.clone(),
+                        messages: Vec::new(),
+                        files: Vec::new(),
+                        context: RunContext::default(),
+                    };
+                    // A thread that does not exist anymore is marked as deleted as well
+                    match chat.delete().await {
+                        Ok(true) => report.threads_deleted += 1,
+                        Ok(false) => {}
+                        Err(e) => {
+                            log::error!("Failed to delete thread {}: {:?}", chat_id, e);
+                            report.threads_failed += 1;
+                            continue;
+                        }
+                    }
+                }
+                store.mark_thread_deleted(&chat_id).await?;
+            }
+            // The chats of failed threads would be fetched again, they are retried in the
+            // next run
+            if batch_len < policy.batch_size || report.threads_failed > 0 {
+                break;
+            }
+        }
+    }
+    if policy.messages_days > 0 {
+        let cutoff = Utc::now() - chrono::Days::new(policy.messages_days);
+        report.messages = store
+            .delete_messages_before(cutoff, policy.batch_size)
+            .await?;
+        report.tool_calls = store
+            .delete_tool_calls_before(cutoff, policy.batch_size)
+            .await?;
     }
+    // Buckets refill within minutes and the quotas count by day, older ones are not needed
+    report.rate_limits = store
+        .delete_rate_limits_before(Utc::now() - chrono::Days::new(2))
+        .await?;
+    Ok(report)
+}
+/// Statistics of a single chat request, saved to the runs table of the log database.
+#[derive(Debug, Default)]
+pub struct RunRecord {
+    pub run_id: Option<String>,
+    pub chat_id: Option<String>,
+    pub assistant_id: String,
+    pub locale: Option<String>,
+    pub model: Option<String>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    // Number of times the run status was polled
+    pub polls: i64,
+    pub tool_calls: i64,
+    // Final status of the run, "timeout" if it did not finish in time and "error" if the request failed
+    pub status: String,
+    pub latency_ms: i64,
 }
 struct Run {
     id: String,
+    api_key: String,
     status: String,
     required_action: Option<RequiredAction>,
+    // Model and token usage, reported by OpenAI once the run is in a terminal state
+    model: String,
+    usage: Option<RunUsage>,
+}
+#[derive(Deserialize, Debug, Clone)]
+struct RunUsage {
+    prompt_tokens: i64,
+    completion_tokens: i64,
 }
 #[derive(Deserialize, Debug)]
 struct RequiredAction {
@@ -914,37 +1668,142 @@ struct SubmitToolOutputs {
     tool_calls: Vec<ToolCall>,
 }
 #[derive(Deserialize, Debug)]
-struct ToolCall {
-    id: String,
-    function: FunctionCall,
-    #[serde(rename = "type")]
-    call_type: String,
+pub(crate) struct ToolCall {
+    pub id: String,
+    pub function: FunctionCall,
 }
 #[derive(Deserialize, Debug)]
-struct FunctionCall {
-    arguments: Value,
-    name: String,
+pub(crate) struct FunctionCall {
+    pub arguments: Value,
+    pub name: String,
+}
+/// A tool call executed for a run, saved to the tool_calls table of the log database.
+#[derive(Debug, Clone)]
+pub struct ToolCallRecord {
+    pub run_id: String,
+    pub tool_call_id: String,
+    pub tool_name: String,
+    pub arguments: String,
+    // Output submitted to the run, None if the tool failed
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+    // Whether the output was cut to MAX_TOOL_OUTPUT_LENGTH characters
+    pub truncated: bool,
+}
+impl ToolCallRecord {
+    /// The output submitted to the run, errors are passed on so the assistant can react to them.
+    pub(crate) fn submitted_output(&self) -> String {
+        match (&self.output, &self.error) {
+            (Some(output), _) => output.clone(),
+            (None, Some(error)) => format!("Error: {}", error),
+            (None, None) => String::new(),
+        }
+    }
 }
 impl Run {
+    // Payload of a new run, the context of the user is passed as additional instructions
+    fn payload(assistant_id: &str, context: &RunContext) -> Value {
+        json!({
+            "assistant_id": assistant_id,
+            "additional_instructions": context.additional_instructions(),
+            "metadata": context.metadata(),
+        })
+    }
+    /// Updates the run from a run object, returned by the API or sent as a stream event.
+    fn update(&mut self, run_response: &Value) -> Result<(), AssistantError> {
+        if let Some(id) = run_response.get("id").and_then(|id| id.as_str()) {
+            self.id = id.to_string();
+        }
+        // Extract the status
+        if let Some(status) = run_response.get("status").and_then(|s| s.as_str()) {
+            self.status = status.to_string();
+        }
+        if let Some(model) = run_response.get("model").and_then(|m| m.as_str()) {
+            self.model = model.to_string();
+        }
+        // The usage is null until the run is in a terminal state
+        self.usage = run_response
+            .get("usage")
+            .and_then(|usage| serde_json::from_value(usage.clone()).ok());
+        // Extract and parse the required_action if present
+        if let Some(required_action_value) = run_response.get("required_action") {
+            self.required_action =
+                serde_json::from_value(required_action_value.clone()).map_err(|_| {
+                    AssistantError::OpenAIError("Failed to parse RequiredAction".to_string())
+                })?;
+        } else {
+            self.required_action = None;
+        }
+        Ok(())
+    }
+    /// Creates a run with streaming enabled, the events are read from the returned response.
+    async fn create_stream(
+        &self,
+        chat_id: &str,
+        assistant_id: &str,
+        context: &RunContext,
+    ) -> Result<reqwest::Response, AssistantError> {
+        let mut payload = Run::payload(assistant_id, context);
+        payload["stream"] = json!(true);
+        self.send_stream_request(
+            format!("https://api.openai.com/v1/threads/{}/runs", chat_id),
+            payload,
+        )
+        .await
+    }
+    /// Submits the tool outputs of a streamed run, the run continues in the returned response.
+    async fn submit_tool_outputs_stream(
+        &self,
+        chat_id: &str,
+        tool_outputs: Vec<Value>,
+    ) -> Result<reqwest::Response, AssistantError> {
+        self.send_stream_request(
+            format!(
+                "https://api.openai.com/v1/threads/{}/runs/{}/submit_tool_outputs",
+                chat_id, self.id
+            ),
+            json!({ "tool_outputs": tool_outputs, "stream": true }),
+        )
+        .await
+    }
+    async fn send_stream_request(
+        &self,
+        url: String,
+        payload: Value,
+    ) -> Result<reqwest::Response, AssistantError> {
+        let response = Client::new()
+            .post(url)
+            .bearer_auth(&self.api_key)
+            .header("OpenAI-Beta", "assistants=v2")
+            .json(&payload)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            let error_message = response.text().await.unwrap_or_default();
+            Err(AssistantError::OpenAIError(error_message))
+        }
+    }
     /// Creates a run for a given thread and assistant and assigns the ID and status to the struct.
+    /// The context of the user is passed as additional_instructions and metadata of the run.
     pub async fn create(
         &mut self,
         chat_id: &str,
         assistant_id: &str,
+        context: &RunContext,
     ) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
-        let payload = json!({
-            "assistant_id": assistant_id,
-        });
+        let api_key = &self.api_key;
+        let payload = Run::payload(assistant_id, context);
         let response = client
-            .post(&format!(
+            .post(format!(
                 "https://api.openai.com/v1/threads/{}/runs",
                 chat_id
             ))
             .header("Content-Type", "application/json")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .header("OpenAI-Beta", "assistants=v2")
             .json(&payload)
             .send()
@@ -972,10 +1831,9 @@ impl Run {
     /// Retrieves the status of the run for the given thread.
     pub async fn get_response(&mut self, chat_id: &str) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         let response = client
-            .get(&format!(
+            .get(format!(
                 "https://api.openai.com/v1/threads/{}/runs/{}",
                 chat_id, self.id
             ))
@@ -990,22 +1848,7 @@ impl Run {
                     AssistantError::OpenAIError("Failed to parse response from OpenAI".to_string())
                 })?;
                 log::debug!("Run response: {:?}", run_response);
-                // Extract the status
-                if let Some(status) = run_response.get("status").and_then(|s| s.as_str()) {
-                    self.status = status.to_string();
-                }
-                // Extract and parse the required_action if present
-                if let Some(required_action_value) = run_response.get("required_action") {
-                    self.required_action = serde_json::from_value(required_action_value.clone())
-                        .map_err(|_| {
-                            AssistantError::OpenAIError(
-                                "Failed to parse RequiredAction".to_string(),
-                            )
-                        })?;
-                } else {
-                    self.required_action = None;
-                }
-                Ok(())
+                self.update(&run_response)
             }
             Ok(res) => {
                 let error_message = res.text().await.unwrap_or_default();
@@ -1023,20 +1866,19 @@ impl Run {
         tool_outputs: Vec<serde_json::Value>,
     ) -> Result<(), AssistantError> {
         let client = Client::new();
-        let api_key = env::var("OPENAI_API_KEY")
-            .map_err(|_| AssistantError::OpenAIError("OPENAI_API_KEY not set".to_string()))?;
+        let api_key = &self.api_key;
         // Construct the payload with the tool outputs
         let payload = json!({
             "tool_outputs": tool_outputs
         });
         // Send the request to submit the tool outputs
         let response = client
-            .post(&format!(
+            .post(format!(
                 "https://api.openai.com/v1/threads/{}/runs/{}/submit_tool_outputs",
                 chat_id, self.id
             ))
             .header("Content-Type", "application/json")
-            .bearer_auth(&api_key)
+            .bearer_auth(api_key)
             .header("OpenAI-Beta", "assistants=v2")
             .json(&payload)
             .send()
@@ -1069,193 +1911,1460 @@ impl Run {
 /// Handles chat interactions with an OpenAI assistant.
 
 // Define a struct that represents the form data.
-#[derive(Deserialize)]
+// The optional fields describe the context of the user and are passed to the run.
+// The user ID is replaced by the one of the session if authentication is enabled.
+#[derive(Deserialize, Default, Clone)]
 pub struct AssistantChatForm {
+    #[serde(default)]
     pub user_id: String,
     pub message: String,
+    pub locale: Option<String>,
+    pub country: Option<String>,
+    pub currency: Option<String>,
+    pub page_url: Option<String>,
+    pub logged_in: Option<bool>,
 }
-
-// Handles chat interactions with an OpenAI assistant using form data.
-pub async fn assistant_chat_handler_form(
-    Extension(db_pool_buycycle): Extension<MySqlPool>,
-    Extension(db_pool_log): Extension<MySqlPool>,
-    Extension(assistant_id): Extension<Arc<RwLock<String>>>,
-    AxumForm(assistant_chat_form): AxumForm<AssistantChatForm>,
-) -> Result<Json<AssistantChatResponse>, AssistantError> {
-    let log = LOG {
-        db_pool: db_pool_log.clone(),
-    };
-    let user_id = &assistant_chat_form.user_id;
-    let message = &assistant_chat_form.message;
-    // Initialize chat or get existing chat_id
-    let chat_id = match log.get_chat_id(user_id).await? {
-        Some(id) => id,
-        None => {
-            let mut chat = Chat {
-                id: String::new(),
-                messages: Vec::new(),
-            };
-            chat.initialize().await?;
-            let new_chat_id = chat.id;
-            log.save_chat_id(user_id, &new_chat_id).await?;
-            new_chat_id
+impl AssistantChatForm {
+    /// The context of the user sending the message.
+    pub fn context(&self) -> RunContext {
+        RunContext {
+            locale: self.locale.clone(),
+            country: self.country.clone(),
+            currency: self.currency.clone(),
+            page_url: self.page_url.clone(),
+            logged_in: self.logged_in,
         }
-    };
-    // Log user_id and message
-    info!("chat_id: {}, message: {}", chat_id, message);
-    // Save the user's message to the database
-    log.save_message_to_db(&chat_id.to_string(), "user", message)
-        .await?;
-    // Initialize the chat struct with the correct chat_id type
-    let mut chat = Chat {
-        id: chat_id.to_string(),
-        messages: Vec::new(),
-    };
-    // Send the user's message to the chat
-    chat.add_message(message, "user").await?;
-    // Create a run for the assistant to process the message
-    let mut run = Run {
-        id: String::new(),
-        status: String::new(),
-        required_action: None,
-    };
-    // Acquire a read lock when you only need to read the value
-    let assistant_id_read_guard = assistant_id.read().await;
-    let assistant_id_string = assistant_id_read_guard.clone();
-    run.create(&chat.id, &assistant_id_string).await?;
-    // Check the status of the run until it's completed or a timeout occurs
-    let start_time = std::time::Instant::now();
-    while start_time.elapsed().as_secs() < TIMEOUT_DURATION {
-        // Log the current status of the run
-        log::info!("Checking run status for chat ID: {}", chat.id);
-        run.get_response(&chat.id).await?;
-        if run.status == "requires_action" {
-            log::info!("Run requires action for chat ID: {}", chat.id);
-            if let Some(required_action) = &run.required_action {
-                if let Some(submit_tool_outputs) = &required_action.submit_tool_outputs {
-                    for tool_call in &submit_tool_outputs.tool_calls {
-                        log::info!("Processing tool call with ID: {}", tool_call.id);
-                        if tool_call.function.name == "get_order_status_dummy" {
-                            // Existing logic for get_order_status_dummy
-                            if let Some(arguments_str) = tool_call.function.arguments.as_str() {
-                                if let Ok(arguments_json) =
-                                    serde_json::from_str::<serde_json::Value>(arguments_str)
-                                {
-                                    if let Some(order_id) =
-                                        arguments_json.get("order_id").and_then(|v| v.as_str())
-                                    {
-                                        log::info!(
-                                            "Fetching order status for order ID: {}",
-                                            order_id
-                                        );
-                                        let order_status = get_order_status_dummy(
-                                            &db_pool_buycycle,
-                                            user_id,
-                                            order_id,
-                                        )
-                                        .await?;
-                                        log::info!(
-                                            "Order status for user ID {}, order ID {}: {:?}",
-                                            user_id,
-                                            order_id,
-                                            order_status
-                                        );
-                                        let tool_output = json!({
-                                            "tool_call_id": tool_call.id,
-                                            "output": order_status.unwrap_or("Unknown order ID".to_string())
-                                        });
-                                        log::info!(
-                                            "Submitting tool output {} for tool call ID: {}",
-                                            tool_output,
-                                            tool_call.id
-                                        );
-                                        run.submit_tool_outputs(&chat.id, vec![tool_output])
-                                            .await?;
-                                    } else {
-                                        log::error!("Order ID is not a string or not found for tool call ID: {}", tool_call.id);
-                                    }
-                                } else {
-                                    log::error!(
-                                        "Failed to parse arguments for tool call ID: {}",
-                                        tool_call.id
-                                    );
-                                }
-                            } else {
-                                log::error!(
-                                    "Arguments are not a string for tool call ID: {}",
-                                    tool_call.id
-                                );
-                            }
-                        } else if tool_call.function.name == "get_orders" {
-                            // New logic for get_orders
-                            if let Some(arguments_str) = tool_call.function.arguments.as_str() {
-                                if let Ok(arguments_json) =
-                                    serde_json::from_str::<serde_json::Value>(arguments_str)
-                                {
-                                    log::info!("Fetching orders for user ID: {}", user_id);
-                                    let orders = get_orders(user_id, &db_pool_buycycle).await?;
-                                    log::info!("Orders for user ID {}: {:?}", user_id, orders);
-                                    let tool_output = json!({
-                                        "tool_call_id": tool_call.id,
-                                        "output": orders.unwrap_or("No orders found".to_string())
-                                    });
-                                    log::info!(
-                                        "Submitting tool output {} for tool call ID: {}",
-                                        tool_output,
-                                        tool_call.id
-                                    );
-                                    run.submit_tool_outputs(&chat.id, vec![tool_output]).await?;
-                                } else {
-                                    log::error!(
-                                        "Failed to parse arguments for tool call ID: {}",
-                                        tool_call.id
-                                    );
-                                }
-                            } else {
-                                log::error!(
-                                    "Arguments are not a string for tool call ID: {}",
-                                    tool_call.id
-                                );
-                            }
-                        }
+    }
+}
+/// Context of the user for a single run, e.g. where the user is and which page they are on.
+/// Passed to the run as additional_instructions and metadata.
+#[derive(Default, Clone, Debug)]
+pub struct RunContext {
+    // Language of the user, e.g. "en" or "en-CA"
+    pub locale: Option<String>,
+    // ISO 3166 country code of the market, e.g. "CA"
+    pub country: Option<String>,
+    // ISO 4217 currency code, derived from the country if not given
+    pub currency: Option<String>,
+    // Page of the website the user is on
+    pub page_url: Option<String>,
+    pub logged_in: Option<bool>,
+}
+impl RunContext {
+    /// Checks the format of the fields, they end up in the instructions of the run.
+    pub fn validate(&self) -> Result<(), AssistantError> {
+        let is_code = |value: &str, len: usize| {
+            value.len() == len && value.chars().all(|c| c.is_ascii_alphabetic())
+        };
+        if let Some(locale) = &self.locale {
+            let mut parts = locale.split(['-', '_']);
+            let valid = parts.next().is_some_and(|language| is_code(language, 2))
+                && parts.next().is_none_or(|region| is_code(region, 2))
+                && parts.next().is_none();
+            if !valid {
+                return Err(AssistantError::InvalidInput(format!(
+                    "Invalid locale: {}",
+                    locale
+                )));
+            }
+        }
+        if let Some(country) = self.country.as_deref().filter(|c| !is_code(c, 2)) {
+            return Err(AssistantError::InvalidInput(format!(
+                "Invalid country: {}",
+                country
+            )));
+        }
+        if let Some(currency) = self.currency.as_deref().filter(|c| !is_code(c, 3)) {
+            return Err(AssistantError::InvalidInput(format!(
+                "Invalid currency: {}",
+                currency
+            )));
+        }
+        if let Some(page_url) = &self.page_url {
+            if !page_url.starts_with("https://")
+                || page_url.len() > 512
+                || page_url.chars().any(|c| c.is_whitespace())
+            {
+                return Err(AssistantError::InvalidInput(format!(
+                    "Invalid page_url: {}",
+                    page_url
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// Language of the locale, e.g. "en" for "en-CA".
+    pub fn language(&self) -> Option<String> {
+        self.locale
+            .as_ref()
+            .map(|locale| locale[..2].to_lowercase())
+    }
+    /// Country of the market, from the country field or else from the region of the locale.
+    pub fn market(&self) -> Option<String> {
+        self.country
+            .clone()
+            .or_else(|| {
+                self.locale
+                    .as_ref()
+                    .filter(|l| l.len() == 5)
+                    .map(|l| l[3..].to_string())
+            })
+            .map(|country| country.to_uppercase())
+    }
+    /// Currency of the market, prices are shown in the local currency.
+    pub fn currency(&self) -> Option<String> {
+        if let Some(currency) = &self.currency {
+            return Some(currency.to_uppercase());
+        }
+        let currency = match self.market()?.as_str() {
+            "US" => "USD",
+            "CA" => "CAD",
+            "GB" => "GBP",
+            "CH" => "CHF",
+            "DK" => "DKK",
+            "SE" => "SEK",
+            "NO" => "NOK",
+            "PL" => "PLN",
+            "CZ" => "CZK",
+            _ => "EUR",
+        };
+        Some(currency.to_string())
+    }
+    /// Locale path of the buycycle website, e.g. "en-ca" in https://buycycle.com/en-ca/shop/search/
+    pub fn shop_path(&self) -> Option<String> {
+        let market = self.market()?.to_lowercase();
+        let language = self.language().unwrap_or_else(|| "en".to_string());
+        Some(format!("{}-{}", language, market))
+    }
+    /// Instructions appended to the assistant's instruction for this run only.
+    pub fn additional_instructions(&self) -> Option<String> {
+        let mut lines = Vec::new();
+        if let Some(language) = self.language() {
+            lines.push(format!(
+                "The language setting of the user is {}, use it if the message does not make the language clear.",
+                language
+            ));
+        }
+        if let Some(market) = self.market() {
+            lines.push(format!("The user is in the {} market.", market));
+        }
+        if let Some(currency) = self.currency() {
+            lines.push(format!("Show prices and budgets in {}.", currency));
+        }
+        if let Some(shop_path) = self.shop_path() {
+            lines.push(format!(
+                "Use https://buycycle.com/{}/shop/search/ as the shop search url and https://buycycle.com/{}/contact-us as the contact url.",
+                shop_path, shop_path
+            ));
+        }
+        if let Some(page_url) = &self.page_url {
+            lines.push(format!("The user is currently on the page {}.", page_url));
+        }
+        match self.logged_in {
+            Some(true) => lines.push("The user is logged in.".to_string()),
+            Some(false) => lines.push(
+                "The user is not logged in, to look up orders they need to log in first."
+                    .to_string(),
+            ),
+            None => {}
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "Context of this conversation:\n{}",
+            lines.join("\n")
+        ))
+    }
+    /// Metadata of the run, OpenAI only accepts string values.
+    pub fn metadata(&self) -> Value {
+        let mut metadata = serde_json::Map::new();
+        let fields = [
+            ("locale", self.locale.clone()),
+            ("market", self.market()),
+            ("currency", self.currency()),
+            ("page_url", self.page_url.clone()),
+            ("logged_in", self.logged_in.map(|l| l.to_string())),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                metadata.insert(key.to_string(), Value::String(value));
+            }
+        }
+        Value::Object(metadata)
+    }
+}
+/// An image uploaded by the user together with a message.
+#[derive(Clone)]
+pub struct ImageUpload {
+    pub file_name: String,
+    pub bytes: Vec<u8>,
+}
+impl ImageUpload {
+    /// Checks the size limit and that the content is one of the allowed image types.
+    /// The type is detected from the content, the declared content type is not trusted.
+    /// Returns the detected content type.
+    pub fn validate(&self) -> Result<&'static str, AssistantError> {
+        if self.bytes.len() > MAX_IMAGE_SIZE {
+            return Err(AssistantError::InvalidInput(format!(
+                "Image is larger than {} MB",
+                MAX_IMAGE_SIZE / 1024 / 1024
+            )));
+        }
+        match detect_image_type(&self.bytes) {
+            Some(detected) if ALLOWED_IMAGE_TYPES.contains(&detected) => Ok(detected),
+            _ => Err(AssistantError::InvalidInput(format!(
+                "Unsupported image, allowed types are {}",
+                ALLOWED_IMAGE_TYPES.join(", ")
+            ))),
+        }
+    }
+}
+/// Detect the image type from the magic bytes of the content.
+fn detect_image_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+/// The chat request, either a JSON body, an url encoded form or a multipart form
+/// with an optional image field, sent by the user of the session if authentication is enabled.
+#[derive(Clone)]
+pub struct AssistantChatInput {
+    pub form: AssistantChatForm,
+    pub image: Option<ImageUpload>,
+}
+#[async_trait]
+impl<S> FromRequest<S> for AssistantChatInput
+where
+    S: Send + Sync,
+{
+    type Rejection = AssistantError;
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // The session is checked before the body is read
+        let auth = req
+            .extensions()
+            .get::<Arc<Authenticator>>()
+            .cloned()
+            .ok_or_else(|| {
+                AssistantError::DatabaseError("Authentication is not configured".to_string())
+            })?;
+        let session = auth.authenticate(req.headers())?;
+        let mut input = Self::from_body(req, state).await?;
+        match session {
+            Some(session) => input.form.user_id = session.user_id,
+            None if input.form.user_id.is_empty() => {
+                return Err(AssistantError::InvalidInput("Missing user_id".to_string()))
+            }
+            None => {}
+        }
+        Ok(input)
+    }
+}
+impl AssistantChatInput {
+    async fn from_body<S: Send + Sync>(req: Request, state: &S) -> Result<Self, AssistantError> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if content_type.starts_with("application/json") {
+            let Json(form) = Json::<AssistantChatForm>::from_request(req, state)
+                .await
+                .map_err(|e| AssistantError::InvalidInput(e.body_text()))?;
+            return Ok(AssistantChatInput { form, image: None });
+        }
+        if !content_type.starts_with("multipart/form-data") {
+            let AxumForm(form) = AxumForm::<AssistantChatForm>::from_request(req, state)
+                .await
+                .map_err(|e| AssistantError::InvalidInput(e.body_text()))?;
+            return Ok(AssistantChatInput { form, image: None });
+        }
+        let mut multipart = Multipart::from_request(req, state)
+            .await
+            .map_err(|e| AssistantError::InvalidInput(e.body_text()))?;
+        let mut form = AssistantChatForm::default();
+        let mut image = None;
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| AssistantError::InvalidInput(e.body_text()))?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            if name == "image" {
+                let file_name = field.file_name().unwrap_or("image").to_string();
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|e| AssistantError::InvalidInput(e.body_text()))?;
+                // Browsers send an empty file part if no file was selected
+                if !bytes.is_empty() {
+                    image = Some(ImageUpload {
+                        file_name,
+                        bytes: bytes.to_vec(),
+                    });
+                }
+                continue;
+            }
+            let value = field
+                .text()
+                .await
+                .map_err(|e| AssistantError::InvalidInput(e.body_text()))?;
+            match name.as_str() {
+                "user_id" => form.user_id = value,
+                "message" => form.message = value,
+                "locale" => form.locale = Some(value),
+                "country" => form.country = Some(value),
+                "currency" => form.currency = Some(value),
+                "page_url" => form.page_url = Some(value),
+                "logged_in" => {
+                    form.logged_in = Some(value.parse().map_err(|_| {
+                        AssistantError::InvalidInput(format!("Invalid logged_in: {}", value))
+                    })?)
+                }
+                _ => {}
+            }
+        }
+        Ok(AssistantChatInput { form, image })
+    }
+}
+/// Uploads an image with purpose vision so it can be attached to a message.
+/// Returns the file ID of the uploaded image.
+pub async fn upload_image(image: &ImageUpload, api_key: &str) -> Result<String, AssistantError> {
+    let content_type = image.validate()?;
+    let client = Client::new();
+    let part = Part::bytes(image.bytes.clone())
+        .file_name(image.file_name.clone())
+        .mime_str(content_type)?;
+    let form = Form::new().part("file", part).text("purpose", "vision");
+    let response = client
+        .post("https://api.openai.com/v1/files")
+        .bearer_auth(api_key)
+        .multipart(form)
+        .send()
+        .await;
+    match response {
+        Ok(res) if res.status().is_success() => {
+            let file_response = res.json::<FileUploadResponse>().await.map_err(|_| {
+                AssistantError::OpenAIError("Failed to parse response from OpenAI".to_string())
+            })?;
+            Ok(file_response.id)
+        }
+        Ok(res) => {
+            let error_message = res.text().await.unwrap_or_default();
+            Err(AssistantError::OpenAIError(error_message))
+        }
+        Err(e) => Err(AssistantError::OpenAIError(format!(
+            "Failed to send request to OpenAI: {}",
+            e
+        ))),
+    }
+}
+
+// The answer given if a run fails or does not finish in time
+const TECHNICAL_ISSUE_MESSAGE: &str =
+    "Sorry I am currently facing some technical issues, please try again.";
+
+/// Chat backend built on the OpenAI Assistants API: the conversation is kept in a thread
+/// and answered by runs of the current assistant, with file search and the tools.
+#[derive(Clone)]
+pub struct AssistantsBackend {
+    config: Arc<Config>,
+    db_pool_buycycle: MySqlPool,
+    log: LOG,
+    // Replaced when the assistant is rotated, requests hold a read lock until they are finished
+    assistant_id: Arc<RwLock<String>>,
+    // Files the assistant can cite, replaced together with the assistant
+    citation_files: Arc<RwLock<Vec<FileInfo>>>,
+}
+impl AssistantsBackend {
+    pub fn new(
+        config: Arc<Config>,
+        db_pool_buycycle: MySqlPool,
+        log: LOG,
+        assistant_id: Arc<RwLock<String>>,
+        citation_files: Arc<RwLock<Vec<FileInfo>>>,
+    ) -> Self {
+        AssistantsBackend {
+            config,
+            db_pool_buycycle,
+            log,
+            assistant_id,
+            citation_files,
+        }
+    }
+    fn chat(&self, id: String, files: Vec<FileInfo>) -> Chat {
+        Chat {
+            id,
+            api_key: self.config.openai.api_key.clone(),
+            messages: Vec::new(),
+            files,
+            context: RunContext::default(),
+        }
+    }
+    fn run(&self) -> Run {
+        Run {
+            id: String::new(),
+            api_key: self.config.openai.api_key.clone(),
+            status: String::new(),
+            required_action: None,
+            model: String::new(),
+            usage: None,
+        }
+    }
+    /// Validates the input and adds the user's message to the thread of the user,
+    /// a new thread is created if the user has no chat yet. The message is logged as well.
+    async fn prepare_chat(
+        &self,
+        input: &AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<(Chat, RunContext), AssistantError> {
+        let user_id = &input.form.user_id;
+        let message = &input.form.message;
+        if message.trim().is_empty() && input.image.is_none() {
+            return Err(AssistantError::InvalidInput(
+                "Message or image is required".to_string(),
+            ));
+        }
+        let context = input.form.context();
+        context.validate()?;
+        record.locale = context.language();
+        // Upload the user's image first, so invalid images are rejected before the chat is touched
+        let image_file_id = match &input.image {
+            Some(image) => Some(upload_image(image, &self.config.openai.api_key).await?),
+            None => None,
+        };
+        // Initialize chat or get existing chat_id
+        let chat_id = match self.log.get_chat_id(user_id).await? {
+            Some(id) => id,
+            None => {
+                let mut chat = self.chat(String::new(), Vec::new());
+                chat.initialize().await?;
+                self.log.save_chat_id(user_id, &chat.id).await?;
+                chat.id
+            }
+        };
+        record.chat_id = Some(chat_id.clone());
+        // Log user_id and message, personal data is masked
+        info!("chat_id: {}, message: {}", chat_id, self.log.mask(message));
+        // Save the user's message to the database, images are referenced by their file ID
+        match &image_file_id {
+            Some(file_id) => {
+                let logged_message = format!("{}\n[image: {}]", message, file_id);
+                self.log
+                    .save_image_message_to_db(&chat_id, &logged_message, file_id)
+                    .await?
+            }
+            None => {
+                self.log
+                    .save_message_to_db(&chat_id, "user", message, None)
+                    .await?
+            }
+        }
+        let mut chat = self.chat(chat_id, self.citation_files.read().await.clone());
+        chat.context = context.clone();
+        // Send the user's message to the chat
+        chat.add_message(message, "user", image_file_id.as_deref())
+            .await?;
+        Ok((chat, context))
+    }
+    /// Executes the tool calls required by the run and logs them.
+    /// Returns the outputs, all outputs of a run step have to be submitted together.
+    async fn call_required_tools(
+        &self,
+        user_id: &str,
+        run: &Run,
+        record: &mut RunRecord,
+    ) -> Vec<Value> {
+        let mut tool_outputs = Vec::new();
+        let Some(submit_tool_outputs) = run
+            .required_action
+            .as_ref()
+            .and_then(|required_action| required_action.submit_tool_outputs.as_ref())
+        else {
+            return tool_outputs;
+        };
+        record.tool_calls += submit_tool_outputs.tool_calls.len() as i64;
+        for tool_call in &submit_tool_outputs.tool_calls {
+            log::info!("Processing tool call with ID: {}", tool_call.id);
+            let tool_call_record = execute_tool_call(
+                &self.config,
+                &self.db_pool_buycycle,
+                None,
+                user_id,
+                &run.id,
+                tool_call,
+            )
+            .await;
+            if let Err(e) = self.log.save_tool_call(&tool_call_record).await {
+                log::error!("Failed to save tool call {}: {:?}", tool_call.id, e);
+            }
+            tool_outputs.push(json!({
+                "tool_call_id": tool_call.id,
+                "output": tool_call_record.submitted_output(),
+            }));
+        }
+        tool_outputs
+    }
+    /// Creates a streaming run and forwards the text deltas until the run is finished,
+    /// required tool calls are executed and their outputs submitted as a new stream.
+    async fn stream_run(
+        &self,
+        user_id: &str,
+        chat: &Chat,
+        context: &RunContext,
+        run: &mut Run,
+        record: &mut RunRecord,
+        tx: &mpsc::Sender<Result<StreamEvent, AssistantError>>,
+    ) -> Result<(), AssistantError> {
+        let mut events = SseReader::new(
+            run.create_stream(&chat.id, &record.assistant_id, context)
+                .await?,
+        );
+        while let Some(event) = events.next_event().await? {
+            let name = event.event.as_deref().unwrap_or_default();
+            if name == "done" {
+                break;
+            } else if name == "error" {
+                return Err(AssistantError::OpenAIError(event.data));
+            }
+            let data: Value = match serde_json::from_str(&event.data) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            if name == "thread.message.delta" {
+                for content in data["delta"]["content"].as_array().into_iter().flatten() {
+                    if let Some(text) = content["text"]["value"].as_str() {
+                        // The run is finished and logged also if the client is gone
+                        let _ = tx.send(Ok(StreamEvent::Delta(text.to_string()))).await;
                     }
                 }
+            } else if name.starts_with("thread.run.") && !name.starts_with("thread.run.step.") {
+                run.update(&data)?;
+                record.run_id = Some(run.id.clone());
+                if run.status == "requires_action" {
+                    log::info!("Run requires action for chat ID: {}", chat.id);
+                    let tool_outputs = self.call_required_tools(user_id, run, record).await;
+                    events = SseReader::new(
+                        run.submit_tool_outputs_stream(&chat.id, tool_outputs)
+                            .await?,
+                    );
+                }
             }
-        } else if run.status == "completed" {
-            info!("Run completed, status: {}", run.status);
-            break;
-        }
-        info!("Run not completed, current status: {}", run.status);
-        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
-    }
-    // If run is not finished, save and return a sorry message with the role "error"
-    if run.status != "completed" {
-        log.save_message_to_db(
-            &chat_id,
-            "error",
-            "Sorry I am currently facing some technical issues, please try again.",
-        )
-        .await?;
-        return Ok(Json(AssistantChatResponse {
-            messages: vec![SimplifiedMessage {
+        }
+        Ok(())
+    }
+    /// Completes the record with the result of the run and returns the reply:
+    /// the messages produced by the run, or an error message if it did not complete.
+    async fn finish_run(
+        &self,
+        chat: &mut Chat,
+        run: &Run,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+        record.model = Some(run.model.clone()).filter(|model| !model.is_empty());
+        record.prompt_tokens = run.usage.as_ref().map(|usage| usage.prompt_tokens);
+        record.completion_tokens = run.usage.as_ref().map(|usage| usage.completion_tokens);
+        record.status = match run.status.as_str() {
+            "" | "queued" | "in_progress" | "requires_action" | "cancelling" => {
+                "timeout".to_string()
+            }
+            status => status.to_string(),
+        };
+        // If run is not finished, save and return a sorry message with the role "error"
+        if run.status != "completed" {
+            self.log
+                .save_message_to_db(&chat.id, "error", TECHNICAL_ISSUE_MESSAGE, None)
+                .await?;
+            return Ok(vec![SimplifiedMessage {
+                id: None,
                 created_at: Utc::now().timestamp(),
                 role: "error".to_string(),
-                text: "Sorry I am currently facing some technical issues, please try again."
-                    .to_string(),
-            }],
-        }));
+                text: TECHNICAL_ISSUE_MESSAGE.to_string(),
+                citations: Vec::new(),
+                image_file_ids: Vec::new(),
+            }]);
+        }
+        // Retrieve the assistant's response, i.e. all messages produced by this run
+        chat.get_messages(&MessageListQuery::default(), Some(&run.id))
+            .await?;
+        for message in &chat.messages {
+            self.log
+                .save_message_to_db(&chat.id, "assistant", &message.text, message.id.as_deref())
+                .await?;
+        }
+        Ok(std::mem::take(&mut chat.messages))
+    }
+}
+#[async_trait]
+impl ChatBackend for AssistantsBackend {
+    /// Sends the user's message to the assistant and polls the run until it is finished
+    /// or the run timeout is reached.
+    async fn send_message(
+        &self,
+        input: AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+        // Acquire a read lock for the whole request, the assistant is only replaced once it is released
+        let assistant_id = self.assistant_id.read().await;
+        record.assistant_id = assistant_id.clone();
+        let (mut chat, context) = self.prepare_chat(&input, record).await?;
+        // Create a run for the assistant to process the message
+        let mut run = self.run();
+        run.create(&chat.id, &assistant_id, &context).await?;
+        record.run_id = Some(run.id.clone());
+        // Check the status of the run until it's completed or a timeout occurs
+        let start_time = std::time::Instant::now();
+        while start_time.elapsed().as_secs() < self.config.assistant.run_timeout_secs {
+            // Log the current status of the run
+            log::info!("Checking run status for chat ID: {}", chat.id);
+            run.get_response(&chat.id).await?;
+            record.polls += 1;
+            if run.status == "requires_action" {
+                log::info!("Run requires action for chat ID: {}", chat.id);
+                let tool_outputs = self
+                    .call_required_tools(&input.form.user_id, &run, record)
+                    .await;
+                if !tool_outputs.is_empty() {
+                    run.submit_tool_outputs(&chat.id, tool_outputs).await?;
+                }
+            } else if run.status == "completed" {
+                info!("Run completed, status: {}", run.status);
+                break;
+            } else if ["failed", "cancelled", "expired", "incomplete"]
+                .contains(&run.status.as_str())
+            {
+                log::error!("Run ended with status: {}", run.status);
+                break;
+            }
+            info!("Run not completed, current status: {}", run.status);
+            tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        }
+        self.finish_run(&mut chat, &run, record).await
+    }
+    /// Sends the user's message to the assistant and streams the events of the run.
+    async fn stream_reply(
+        &self,
+        input: AssistantChatInput,
+        mut record: RunRecord,
+        permit: Option<RunPermit>,
+    ) -> Result<ReplyStream, AssistantError> {
+        let start_time = std::time::Instant::now();
+        // The read lock is held until the stream is finished
+        let assistant_id = self.assistant_id.clone().read_owned().await;
+        record.assistant_id = assistant_id.clone();
+        let (mut chat, context) = match self.prepare_chat(&input, &mut record).await {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                record.status = "error".to_string();
+                record.latency_ms = start_time.elapsed().as_millis() as i64;
+                if let Err(e) = self.log.save_run(&record).await {
+                    log::error!("Failed to save run statistics: {:?}", e);
+                }
+                return Err(e);
+            }
+        };
+        let (tx, stream) = reply_channel();
+        let backend = self.clone();
+        tokio::spawn(async move {
+            let mut run = backend.run();
+            let timeout = std::time::Duration::from_secs(backend.config.assistant.run_timeout_secs);
+            let streamed = tokio::time::timeout(
+                timeout,
+                backend.stream_run(
+                    &input.form.user_id,
+                    &chat,
+                    &context,
+                    &mut run,
+                    &mut record,
+                    &tx,
+                ),
+            )
+            .await;
+            // A timeout leaves the run unfinished, which is answered with the error message
+            let result = match streamed {
+                Ok(Err(e)) => Err(e),
+                _ => backend.finish_run(&mut chat, &run, &mut record).await,
+            };
+            drop(assistant_id);
+            match result {
+                Ok(messages) => {
+                    let _ = tx.send(Ok(StreamEvent::Done(messages))).await;
+                }
+                Err(e) => {
+                    log::error!("Streamed run failed for chat ID {}: {:?}", chat.id, e);
+                    record.status = "error".to_string();
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+            record.latency_ms = start_time.elapsed().as_millis() as i64;
+            if let Err(e) = backend.log.save_run(&record).await {
+                log::error!("Failed to save run statistics: {:?}", e);
+            }
+            drop(permit);
+        });
+        Ok(stream)
     }
-    // Retrieve the last message from the conversation, which should be the assistant's response
-    chat.get_messages(true).await?;
-    if let Some(last_message) = chat.messages.last() {
-        log.save_message_to_db(&chat_id, "assistant", &last_message.text)
+    /// Adds the message and the reply to the thread, so the assistant knows them in the
+    /// next run.
+    async fn save_reply(
+        &self,
+        input: &AssistantChatInput,
+        reply: &str,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+        record.assistant_id = self.assistant_id.read().await.clone();
+        let (chat, _) = self.prepare_chat(input, record).await?;
+        chat.add_message(reply, "assistant", None).await?;
+        self.log
+            .save_message_to_db(&chat.id, "assistant", reply, None)
             .await?;
+        Ok(vec![SimplifiedMessage {
+            id: None,
+            created_at: Utc::now().timestamp(),
+            role: "assistant".to_string(),
+            text: reply.to_string(),
+            citations: Vec::new(),
+            image_file_ids: Vec::new(),
+        }])
+    }
+    /// Lists the messages of the thread of the user.
+    /// Without a limit the full thread is returned, with a limit a single page.
+    async fn list_history(
+        &self,
+        user_id: &str,
+        query: &MessageListQuery,
+    ) -> Result<AssistantHistoryResponse, AssistantError> {
+        let chat_id = match self.log.get_chat_id(user_id).await? {
+            Some(id) => id,
+            None => return Ok(AssistantHistoryResponse::default()),
+        };
+        let mut chat = self.chat(chat_id, self.citation_files.read().await.clone());
+        if query.limit.is_none() {
+            chat.get_messages(query, None).await?;
+            return Ok(AssistantHistoryResponse {
+                messages: chat.messages,
+                ..Default::default()
+            });
+        }
+        let page = chat.get_messages_page(query, None).await?;
+        Ok(AssistantHistoryResponse {
+            first_id: page.first_id,
+            last_id: page.last_id,
+            has_more: page.has_more,
+            messages: page
+                .data
+                .into_iter()
+                .filter_map(|msg| simplify_message(msg, &chat.files, &chat.context))
+                .collect(),
+        })
+    }
+}
+
+/// The cache key of a message. Only the first message of a chat is answered from the cache
+/// and added to it: follow-ups like "yes" depend on the earlier conversation, and their
+/// answers can repeat the personal data of the user.
+async fn cache_key(
+    store: &dyn ConversationStore,
+    cache: &AnswerCache,
+    input: &AssistantChatInput,
+) -> Option<CacheKey> {
+    if !cache.enabled() {
+        return None;
+    }
+    match store.get_chat_id(&input.form.user_id).await {
+        Ok(None) => cache.key(input).await,
+        Ok(Some(_)) => None,
+        Err(e) => {
+            log::error!("Failed to look up the chat for the answer cache: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Adds a cached answer to the conversation instead of creating a run,
+/// the run is logged with the status "cached".
+async fn reply_from_cache(
+    backend: &dyn ChatBackend,
+    input: &AssistantChatInput,
+    answer: CachedAnswer,
+    record: &mut RunRecord,
+) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+    let mut messages = backend.save_reply(input, &answer.text, record).await?;
+    record.status = "cached".to_string();
+    for message in &mut messages {
+        message.citations = answer.citations.clone();
+    }
+    Ok(messages)
+}
+// Answers a chat message with the chat backend of the deployment.
+// Repeated questions are answered from the answer cache before a run is created,
+// failed runs are answered by the fallback within the time left. The statistics of every request are saved to the runs table, also if the request fails.
+async fn answer_message(
+    store: &dyn ConversationStore,
+    backend: &dyn ChatBackend,
+    cache: &AnswerCache,
+    fallback: &Fallback,
+    assistant_chat_input: AssistantChatInput,
+) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+    let start_time = std::time::Instant::now();
+    let mut record = RunRecord::default();
+    let key = cache_key(store, cache, &assistant_chat_input).await;
+    let cached = match &key {
+        Some(key) => cache.get(key).await,
+        None => None,
+    };
+    let result = match cached {
+        Some(answer) => reply_from_cache(backend, &assistant_chat_input, answer, &mut record).await,
+        None => {
+            // The run consumes the input, it is kept for the fallback
+            let fallback_input = fallback.enabled().then(|| assistant_chat_input.clone());
+            let mut result = backend
+                .send_message(assistant_chat_input, &mut record)
+                .await;
+            if result.is_err() {
+                record.status = "error".to_string();
+            }
+            if let Some(input) = fallback_input.filter(|_| fallback.applies(&record, &result)) {
+                if let Some(messages) = fallback.answer(&input, &mut record, start_time).await {
+                    result = Ok(messages);
+                }
+            }
+            if let (Ok(messages), Some(key)) = (&result, key) {
+                if let Some(answer) = cacheable_answer(&record, messages) {
+                    cache.insert(key, answer).await;
+                }
+            }
+            result
+        }
+    };
+    if result.is_err() {
+        record.status = "error".to_string();
+    }
+    record.latency_ms = start_time.elapsed().as_millis() as i64;
+    if let Err(e) = store.save_run(&record).await {
+        log::error!("Failed to save run statistics: {:?}", e);
+    }
+    result
+}
+/// Handles chat interactions of the versioned API. The message is sent as JSON, as an url
+/// encoded form or as a multipart form with an image. The reply is returned as HTML list
+/// items for htmx requests and for clients accepting only HTML, else as JSON.
+/// Errors are always returned as JSON.
+pub async fn chat_handler(
+    headers: HeaderMap,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(backend): Extension<Arc<dyn ChatBackend>>,
+    Extension(cache): Extension<Arc<AnswerCache>>,
+    Extension(fallback): Extension<Arc<Fallback>>,
+    admission: Admission,
+    assistant_chat_input: AssistantChatInput,
+) -> Result<Response, AssistantError> {
+    let _permit = admission.admit(&assistant_chat_input.form.user_id).await?;
+    let messages = answer_message(
+        store.as_ref(),
+        backend.as_ref(),
+        &cache,
+        &fallback,
+        assistant_chat_input,
+    )
+    .await?;
+    let mut response = if wants_html(&headers) {
+        Html(render_messages_html(&messages)).into_response()
+    } else {
+        Json(AssistantChatResponse { messages }).into_response()
+    };
+    response.headers_mut().insert(
+        header::VARY,
+        header::HeaderValue::from_static("Accept, HX-Request"),
+    );
+    Ok(response)
+}
+// Handles chat interactions like the versioned chat handler, but always returns JSON.
+// Kept on /assistant for clients of the unversioned API.
+pub async fn assistant_chat_handler_form(
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(backend): Extension<Arc<dyn ChatBackend>>,
+    Extension(cache): Extension<Arc<AnswerCache>>,
+    Extension(fallback): Extension<Arc<Fallback>>,
+    admission: Admission,
+    assistant_chat_input: AssistantChatInput,
+) -> Result<Json<AssistantChatResponse>, AssistantError> {
+    let _permit = admission.admit(&assistant_chat_input.form.user_id).await?;
+    let messages = answer_message(
+        store.as_ref(),
+        backend.as_ref(),
+        &cache,
+        &fallback,
+        assistant_chat_input,
+    )
+    .await?;
+    // Return the assistant's response
+    Ok(Json(AssistantChatResponse { messages }))
+}
+// Whether the reply is rendered as HTML: requests of htmx, or clients that accept
+// HTML but not JSON. Requests without an Accept header get JSON.
+fn wants_html(headers: &HeaderMap) -> bool {
+    if headers.contains_key("HX-Request") {
+        return true;
     }
-    // Return the updated conversation history including the assistant's response
-    Ok(Json(AssistantChatResponse {
-        messages: chat.messages,
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    accept.contains("text/html") && !accept.contains("application/json")
+}
+// Markdown links and numbered list items of the rendered message texts
+static MARKDOWN_LINK: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\[([^\]]*)\]\((https?://[^)\s]+)\)").unwrap());
+static LIST_NUMBER: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\d+\.\s)").unwrap());
+
+/// Renders the messages of a reply as list items of the chat page, like the page renders
+/// the messages of the history: markdown links become links, and the images are referenced
+/// by their file ID and loaded by the page with the session of the user. The texts are escaped.
+pub fn render_messages_html(messages: &[SimplifiedMessage]) -> String {
+    let mut html = String::new();
+    for message in messages {
+        let (class, sender) = match message.role.as_str() {
+            "user" => ("", "User"),
+            "assistant" => (" class=\"assistant-message\"", "Assistant"),
+            _ => (" class=\"assistant-message\"", "Error"),
+        };
+        let text = escape_html(&message.text);
+        let text = MARKDOWN_LINK.replace_all(
+            &text,
+            r#"<strong><a href="$2" target="_blank">$1</a></strong>"#,
+        );
+        let text = LIST_NUMBER.replace_all(&text, "<br>$1");
+        let images: String = message
+            .image_file_ids
+            .iter()
+            .map(|file_id| {
+                format!(
+                    "<img data-file-id=\"{}\" alt=\"image\">",
+                    escape_html(file_id)
+                )
+            })
+            .collect();
+        let message_id = message
+            .id
+            .as_deref()
+            .map(|id| format!(" data-message-id=\"{}\"", escape_html(id)))
+            .unwrap_or_default();
+        let sent_on = DateTime::from_timestamp(message.created_at, 0).unwrap_or_default();
+        html.push_str(&format!(
+            "<li{}{} data-role=\"{}\"><strong>{}:</strong> {}{}<br><small>Sent on: <time datetime=\"{}\">{}</time></small></li>\n",
+            class,
+            message_id,
+            escape_html(&message.role),
+            sender,
+            text,
+            images,
+            sent_on.to_rfc3339(),
+            sent_on.format("%Y-%m-%d %H:%M UTC"),
+        ));
+    }
+    html
+}
+// Escapes the characters with a meaning in HTML text and attributes
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+/// Handles chat interactions like the chat handler, but streams the reply as server-sent events:
+/// "delta" events with parts of the text, then a "done" event with the messages of the reply,
+/// or an "error" event if the request failed. Invalid input is rejected before the stream starts.
+/// A cached answer is sent as a single delta, streamed replies are not added to the cache.
+pub async fn assistant_chat_stream_handler(
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(backend): Extension<Arc<dyn ChatBackend>>,
+    Extension(cache): Extension<Arc<AnswerCache>>,
+    admission: Admission,
+    assistant_chat_input: AssistantChatInput,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AssistantError> {
+    let permit = admission.admit(&assistant_chat_input.form.user_id).await?;
+    let cached = match cache_key(store.as_ref(), &cache, &assistant_chat_input).await {
+        Some(key) => cache.get(&key).await,
+        None => None,
+    };
+    let stream = match cached {
+        Some(answer) => {
+            let start_time = std::time::Instant::now();
+            let mut record = RunRecord::default();
+            let text = answer.text.clone();
+            let result =
+                reply_from_cache(backend.as_ref(), &assistant_chat_input, answer, &mut record)
+                    .await;
+            if result.is_err() {
+                record.status = "error".to_string();
+            }
+            record.latency_ms = start_time.elapsed().as_millis() as i64;
+            if let Err(e) = store.save_run(&record).await {
+                log::error!("Failed to save run statistics: {:?}", e);
+            }
+            let events = vec![Ok(StreamEvent::Delta(text)), Ok(StreamEvent::Done(result?))];
+            futures::stream::iter(events).boxed()
+        }
+        None => {
+            backend
+                .stream_reply(assistant_chat_input, RunRecord::default(), permit)
+                .await?
+        }
+    };
+    // The data is sent as JSON, so line breaks in the texts are escaped.
+    let events = stream.map(move |item| {
+        let event = match item {
+            Ok(StreamEvent::Delta(text)) => Event::default()
+                .event("delta")
+                .json_data(json!({ "text": text })),
+            Ok(StreamEvent::Done(messages)) => Event::default()
+                .event("done")
+                .json_data(AssistantChatResponse { messages }),
+            Err(e) => Event::default()
+                .event("error")
+                .json_data(json!({ "error": e.message() })),
+        };
+        Ok(event.unwrap_or_else(|_| Event::default().event("error")))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+// Define a struct that represents the history query parameters.
+#[derive(Deserialize)]
+pub struct AssistantHistoryQuery {
+    // Ignored if authentication is enabled, the user of the session is used then
+    pub user_id: Option<String>,
+    pub limit: Option<u32>,
+    pub order: Option<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+// Define the response type for the assistant history handler.
+#[derive(Serialize, Default)]
+pub struct AssistantHistoryResponse {
+    pub messages: Vec<SimplifiedMessage>,
+    pub first_id: Option<String>,
+    pub last_id: Option<String>,
+    pub has_more: bool,
+}
+/// Returns the conversation history of a user.
+/// Without a limit the full thread is returned, with a limit a single page is returned
+/// and the cursors can be used to request the next page.
+pub async fn assistant_history_handler(
+    headers: HeaderMap,
+    Extension(backend): Extension<Arc<dyn ChatBackend>>,
+    Extension(auth): Extension<Arc<Authenticator>>,
+    Query(history_query): Query<AssistantHistoryQuery>,
+) -> Result<Json<AssistantHistoryResponse>, AssistantError> {
+    let user_id = auth.user_id(&headers, history_query.user_id)?;
+    let query = MessageListQuery {
+        limit: history_query.limit,
+        order: history_query.order,
+        after: history_query.after,
+        before: history_query.before,
+    };
+    query.validate()?;
+    Ok(Json(backend.list_history(&user_id, &query).await?))
+}
+/// Starts an anonymous guest session, if authentication is enabled and guests are allowed.
+/// Guests can chat but can not use the tools reading the account of a user.
+/// The IP address of the client is rate limited.
+pub async fn guest_session_handler(
+    Extension(auth): Extension<Arc<Authenticator>>,
+    _admission: Admission,
+) -> Result<Json<GuestSession>, AssistantError> {
+    let session = auth.guest_session()?;
+    info!("Guest session started for user ID: {}", session.user_id);
+    Ok(Json(session))
+}
+/// Saves the feedback of a user on an assistant message, identified by its thread message ID.
+/// Users can only rate the messages of their own chats, a new rating replaces the previous one.
+pub async fn message_feedback_handler(
+    headers: HeaderMap,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(redactor): Extension<Arc<Redactor>>,
+    Extension(auth): Extension<Arc<Authenticator>>,
+    AxumPath(message_id): AxumPath<String>,
+    Json(feedback): Json<MessageFeedback>,
+) -> Result<StatusCode, AssistantError> {
+    feedback.validate()?;
+    let user_id = auth.user_id(&headers, feedback.user_id.clone())?;
+    let log = LOG {
+        store: store.clone(),
+        redactor: redactor.clone(),
+    };
+    // Messages of other users are not found either, so their IDs can not be probed
+    let message_db_id = log
+        .get_assistant_message_id(&user_id, &message_id)
+        .await?
+        .ok_or_else(|| AssistantError::NotFound(format!("Message {} not found", message_id)))?;
+    log.save_feedback(message_db_id, &user_id, &feedback)
+        .await?;
+    info!(
+        "Feedback {} saved for message ID: {}",
+        feedback.rating, message_id
+    );
+    Ok(StatusCode::CREATED)
+}
+/// Checks the bearer token of an admin request against the configured admin token.
+pub fn check_admin_token(headers: &HeaderMap, config: &Config) -> Result<(), AssistantError> {
+    let admin_token = config
+        .admin
+        .api_token
+        .as_deref()
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| AssistantError::Unauthorized("Admin API is disabled".to_string()))?;
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        // Constant time comparison, memcmp::eq requires slices of equal length
+        Some(token)
+            if token.len() == admin_token.len()
+                && openssl::memcmp::eq(token.as_bytes(), admin_token.as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(AssistantError::Unauthorized(
+            "Invalid admin token".to_string(),
+        )),
+    }
+}
+// Define a struct that represents the negative feedback export query parameters.
+#[derive(Deserialize)]
+pub struct FeedbackExportQuery {
+    // Export feedback given on or after this date, defaults to the last 30 days
+    pub since: Option<NaiveDate>,
+}
+/// Exports the negative feedback with the preceding conversation, for admins only.
+pub async fn negative_feedback_export_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(redactor): Extension<Arc<Redactor>>,
+    headers: HeaderMap,
+    Query(export_query): Query<FeedbackExportQuery>,
+) -> Result<Json<Vec<NegativeFeedback>>, AssistantError> {
+    check_admin_token(&headers, &config)?;
+    let log = LOG {
+        store: store.clone(),
+        redactor: redactor.clone(),
+    };
+    let since = export_query
+        .since
+        .unwrap_or_else(|| Utc::now().date_naive() - chrono::Days::new(30));
+    Ok(Json(log.get_negative_feedback(since).await?))
+}
+/// Exports everything stored about a user for a data subject request, for admins only.
+/// Contains the logged data of all chats and the messages of their OpenAI threads.
+pub async fn user_data_export_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(redactor): Extension<Arc<Redactor>>,
+    Extension(citation_files): Extension<Arc<RwLock<Vec<FileInfo>>>>,
+    headers: HeaderMap,
+    AxumPath(user_id): AxumPath<String>,
+) -> Result<Json<UserDataExport>, AssistantError> {
+    check_admin_token(&headers, &config)?;
+    let log = LOG {
+        store: store.clone(),
+        redactor: redactor.clone(),
+    };
+    let mut chats = log.get_user_chats(&user_id).await?;
+    let files = citation_files.read().await.clone();
+    for chat_export in chats.iter_mut() {
+        let mut chat = Chat {
+            id: chat_export.chat_id.clone(),
+            api_key: config.openai.api_key.clone(),
+            messages: Vec::new(),
+            files: files.clone(),
+            context: RunContext::default(),
+        };
+        // Threads can already be deleted, the logged messages are exported anyway
+        match chat.get_messages(&MessageListQuery::default(), None).await {
+            Ok(()) => chat_export.thread = chat.messages,
+            Err(e) => log::warn!("Failed to export thread {}: {:?}", chat.id, e),
+        }
+    }
+    info!("Exported {} chats of a user", chats.len());
+    Ok(Json(UserDataExport {
+        user_id,
+        exported_at: Utc::now(),
+        chats,
     }))
 }
+// Define a struct that represents the stats export query parameters.
+#[derive(Deserialize)]
+pub struct StatsExportQuery {
+    // First and last day of the export, default the last 30 days
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    // "json" (default) or "csv"
+    pub format: Option<String>,
+}
+/// Exports the daily stats as JSON or CSV, for admins only.
+pub async fn daily_stats_export_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    headers: HeaderMap,
+    Query(stats_query): Query<StatsExportQuery>,
+) -> Result<Response, AssistantError> {
+    check_admin_token(&headers, &config)?;
+    let to = stats_query.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = stats_query
+        .from
+        .unwrap_or_else(|| to - chrono::Days::new(30));
+    if from > to {
+        return Err(AssistantError::InvalidInput(
+            "from must not be after to".to_string(),
+        ));
+    }
+    let stats = store.get_daily_stats(from, to).await?;
+    match stats_query.format.as_deref() {
+        None | Some("json") => Ok(Json(stats).into_response()),
+        Some("csv") => Ok((
+            [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+            DailyStats::to_csv(&stats),
+        )
+            .into_response()),
+        Some(format) => Err(AssistantError::InvalidInput(format!(
+            "Invalid format {}, must be json or csv",
+            format
+        ))),
+    }
+}
+/// Erases everything stored about a user for a data subject request, for admins only.
+/// The OpenAI threads are deleted first, then the logged data is deleted and the erasure is
+/// recorded in the audit log. The logged data is deleted also if a thread fails to be
+/// deleted, the failed threads are reported so their deletion can be retried.
+pub async fn user_data_erasure_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    headers: HeaderMap,
+    AxumPath(user_id): AxumPath<String>,
+) -> Result<Json<ErasureReport>, AssistantError> {
+    check_admin_token(&headers, &config)?;
+    let mut threads_deleted = 0;
+    let mut threads_failed = Vec::new();
+    for chat_export in store.get_user_chats(&user_id).await? {
+        // Chats of the completions backend have no thread on OpenAI
+        if !is_thread_id(&chat_export.chat_id) {
+            continue;
+        }
+        let chat = Chat {
+            id: chat_export.chat_id,
+            api_key: config.openai.api_key.clone(),
+            messages: Vec::new(),
+            files: Vec::new(),
+            context: RunContext::default(),
+        };
+        match chat.delete().await {
+            Ok(true) => threads_deleted += 1,
+            Ok(false) => {}
+            Err(e) => {
+                log::error!("Failed to delete thread {}: {:?}", chat.id, e);
+                threads_failed.push(chat.id);
+            }
+        }
+    }
+    let mut report = store.erase_user_data(&user_id, threads_deleted).await?;
+    report.threads_failed = threads_failed;
+    info!("Erased user data: {:?}", report);
+    Ok(Json(report))
+}
+// Define a struct that represents the image query parameters.
+#[derive(Deserialize)]
+pub struct ImageQuery {
+    // Ignored if authentication is enabled, the user of the session is used then
+    pub user_id: Option<String>,
+}
+/// Serves an image uploaded by a user, so image messages can be rendered in the history.
+/// Only the images uploaded in the chats of the user are served, other files are not found.
+pub async fn assistant_image_handler(
+    headers: HeaderMap,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(store): Extension<Arc<dyn ConversationStore>>,
+    Extension(auth): Extension<Arc<Authenticator>>,
+    AxumPath(file_id): AxumPath<String>,
+    Query(image_query): Query<ImageQuery>,
+) -> Result<Response, AssistantError> {
+    // The file ID is part of the OpenAI URL, so it may not contain a path
+    if !is_file_id(&file_id) {
+        return Err(AssistantError::InvalidInput(format!(
+            "Invalid file ID: {}",
+            file_id
+        )));
+    }
+    let user_id = auth.user_id(&headers, image_query.user_id)?;
+    if !store.has_image(&user_id, &file_id).await? {
+        return Err(AssistantError::NotFound(format!(
+            "Image {} not found",
+            file_id
+        )));
+    }
+    let api_key = &config.openai.api_key;
+    let client = Client::new();
+    let file: Value = client
+        .get(format!("https://api.openai.com/v1/files/{}", file_id))
+        .bearer_auth(api_key)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if file["purpose"].as_str() != Some("vision") {
+        return Err(AssistantError::InvalidInput(format!(
+            "File {} is not an image",
+            file_id
+        )));
+    }
+    let bytes = client
+        .get(format!(
+            "https://api.openai.com/v1/files/{}/content",
+            file_id
+        ))
+        .bearer_auth(api_key)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let content_type = detect_image_type(&bytes).unwrap_or("application/octet-stream");
+    Ok(([(header::CONTENT_TYPE, content_type)], Body::from(bytes)).into_response())
+}
+/// Whether a file ID has the format of OpenAI, like "file-abc123".
+pub fn is_file_id(file_id: &str) -> bool {
+    file_id
+        .strip_prefix("file-")
+        .is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+/// Executes a tool call of a run or a completion and measures its duration.
+/// Errors of the tool are recorded instead of failing the request, so the run can continue.
+pub(crate) async fn execute_tool_call(
+    config: &Config,
+    db_pool_buycycle: &MySqlPool,
+    retrieval: Option<&RetrievalIndex>,
+    user_id: &str,
+    run_id: &str,
+    tool_call: &ToolCall,
+) -> ToolCallRecord {
+    let start_time = std::time::Instant::now();
+    // The arguments are a JSON encoded string
+    let arguments = match &tool_call.function.arguments {
+        Value::String(arguments) => arguments.clone(),
+        arguments => arguments.to_string(),
+    };
+    let result = call_tool(
+        config,
+        db_pool_buycycle,
+        retrieval,
+        user_id,
+        &tool_call.function.name,
+        &arguments,
+    )
+    .await;
+    let duration_ms = start_time.elapsed().as_millis() as i64;
+    let mut record = ToolCallRecord {
+        run_id: run_id.to_string(),
+        tool_call_id: tool_call.id.clone(),
+        tool_name: tool_call.function.name.clone(),
+        arguments,
+        output: None,
+        error: None,
+        duration_ms,
+        truncated: false,
+    };
+    match result {
+        Ok(output) => {
+            log::info!(
+                "Tool {} for tool call ID {} returned {} characters",
+                record.tool_name,
+                record.tool_call_id,
+                output.chars().count()
+            );
+            record.truncated = output.chars().count() > MAX_TOOL_OUTPUT_LENGTH;
+            record.output = Some(if record.truncated {
+                output.chars().take(MAX_TOOL_OUTPUT_LENGTH).collect()
+            } else {
+                output
+            });
+        }
+        Err(e) => {
+            log::error!(
+                "Tool {} failed for tool call ID {}: {:?}",
+                record.tool_name,
+                record.tool_call_id,
+                e
+            );
+            record.error = Some(e.message().to_string());
+        }
+    }
+    record
+}
+/// Dispatches a tool call to the Rust implementation of the tool.
+/// The search tool is only available with a retrieval index, the account tools only in verified
+/// sessions that are not guest sessions.
+async fn call_tool(
+    config: &Config,
+    db_pool_buycycle: &MySqlPool,
+    retrieval: Option<&RetrievalIndex>,
+    user_id: &str,
+    name: &str,
+    arguments: &str,
+) -> Result<String, AssistantError> {
+    let arguments: Value = serde_json::from_str(arguments)
+        .map_err(|e| AssistantError::InvalidInput(format!("Failed to parse arguments: {}", e)))?;
+    if ACCOUNT_TOOLS.contains(&name) && !can_use_account_tools(&config.auth, user_id) {
+        return Err(AssistantError::Unauthorized(format!(
+            "{} is not available in a guest session, the user has to log in to buycycle",
+            name
+        )));
+    }
+    match name {
+        "get_order_status" | "get_order_status_dummy" => {
+            let order_id = arguments
+                .get("order_id")
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| AssistantError::InvalidInput("order_id is missing".to_string()))?;
+            log::info!("Fetching order status for order ID: {}", order_id);
+            let order_status = get_order_status_dummy(db_pool_buycycle, user_id, order_id).await?;
+            Ok(order_status.unwrap_or("Unknown order ID".to_string()))
+        }
+        "get_orders" => {
+            log::info!("Fetching orders for user ID: {}", user_id);
+            let orders = get_orders(config, user_id, db_pool_buycycle).await?;
+            Ok(orders.unwrap_or("No orders found".to_string()))
+        }
+        SEARCH_TOOL_NAME => match retrieval {
+            Some(retrieval) => retrieval.call_search_tool(&arguments).await,
+            None => Err(AssistantError::InvalidInput(format!(
+                "Unknown tool: {}",
+                name
+            ))),
+        },
+        _ => Err(AssistantError::InvalidInput(format!(
+            "Unknown tool: {}",
+            name
+        ))),
+    }
+}
 async fn get_order_status_dummy(
     _db_pool: &MySqlPool,
     _user_id: &str,
@@ -1277,31 +3386,27 @@ async fn get_authorization_token(
     let main_query = "
         SELECT custom_auth_token FROM buycycle_2023_01_20.users WHERE id = ?
     ";
-    //xx check why this is necesarry
-    let database_url_buycycle =
-        env::var("DATABASE_URL_BUYCYCLE").expect("DATABASE_URL must be set");
-    // Create a new database connection pool
-    let db_pool_buycycle = match DB::create_pool(&database_url_buycycle).await {
-        Ok(pool) => pool,
-        Err(e) => {
-            log::error!("Failed to create database pool buycycle: {:?}", e);
-            std::process::exit(1);
-        }
-    };
     let authorization_token: Option<String> = sqlx::query_scalar(main_query)
         .bind(user_id_int)
-        .fetch_optional(&db_pool_buycycle)
+        .fetch_optional(db_pool)
         .await
         .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
 
     Ok(authorization_token)
 }
-async fn get_orders(user_id: &str, db_pool: &MySqlPool) -> Result<Option<String>, AssistantError> {
-    let x_proxy_authorization = env::var("X_PROXY_AUTHORIZATION").map_err(|_| {
-        AssistantError::DatabaseError(
-            "X_PROXY_AUTHORIZATION environment variable not set".to_string(),
-        )
-    })?;
+async fn get_orders(
+    config: &Config,
+    user_id: &str,
+    db_pool: &MySqlPool,
+) -> Result<Option<String>, AssistantError> {
+    let x_proxy_authorization =
+        config
+            .buycycle
+            .proxy_authorization
+            .as_deref()
+            .ok_or_else(|| {
+                AssistantError::DatabaseError("X_PROXY_AUTHORIZATION is not configured".to_string())
+            })?;
 
     // Get the authorization token
     let authorization_token = get_authorization_token(db_pool, user_id).await?;
@@ -0,0 +1,235 @@
+use crate::assistant::AssistantError;
+use crate::config::AuthConfig;
+use crate::redaction::decode_hex;
+use axum::http::{header, HeaderMap};
+use chrono::Utc;
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Prefix of the user IDs of guest sessions, they are only issued by the bot.
+pub const GUEST_PREFIX: &str = "guest_";
+/// Tools that read the account of the user, they can not be used in guest sessions.
+pub const ACCOUNT_TOOLS: &[&str] = &["get_orders", "get_order_status", "get_order_status_dummy"];
+// Seconds a token is accepted after it expired, for clocks that are not in sync
+const EXPIRY_LEEWAY_SECS: i64 = 60;
+
+/// The user of an authenticated request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user_id: String,
+}
+impl Session {
+    /// Whether the session is an anonymous guest session.
+    pub fn guest(&self) -> bool {
+        is_guest(&self.user_id)
+    }
+}
+/// Whether a user ID belongs to a guest session.
+pub fn is_guest(user_id: &str) -> bool {
+    user_id.starts_with(GUEST_PREFIX)
+}
+/// Whether the account tools can be used for a user: only with a verified session that is
+/// not a guest session. Without authentication the user ID of a request is not verified,
+/// every caller is treated as a guest.
+pub fn can_use_account_tools(config: &AuthConfig, user_id: &str) -> bool {
+    config.enabled && !is_guest(user_id)
+}
+/// A new guest session, the token is sent as bearer token with the chat requests.
+#[derive(Debug, Serialize)]
+pub struct GuestSession {
+    pub user_id: String,
+    pub token: String,
+    pub expires_at: i64,
+}
+// Claims of a session token, further claims of the platform are ignored
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    exp: i64,
+}
+
+/// Authenticates the chat requests with the bearer token of the session, either an HS256
+/// JWT issued by the buycycle platform or a user ID signed with HMAC-SHA256 with the time it
+/// was signed (`<user_id>.<issued_at>.<hex signature>`), both with the shared secret. Both
+/// expire. Without authentication the user ID of the request is trusted, but the account
+/// tools are not available.
+pub struct Authenticator {
+    config: AuthConfig,
+}
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Self {
+        Authenticator { config }
+    }
+    /// Whether the requests are authenticated.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    /// The session of the bearer token of a request, None if authentication is disabled.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Session>, AssistantError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| AssistantError::Unauthorized("Missing session token".to_string()))?;
+        self.verify_token(token.trim()).map(Some)
+    }
+    /// The user ID of a request: the one of the session if authentication is enabled,
+    /// else the user ID sent by the client.
+    pub fn user_id(
+        &self,
+        headers: &HeaderMap,
+        claimed_user_id: Option<String>,
+    ) -> Result<String, AssistantError> {
+        match self.authenticate(headers)? {
+            Some(session) => Ok(session.user_id),
+            None => claimed_user_id
+                .filter(|user_id| !user_id.is_empty())
+                .ok_or_else(|| AssistantError::InvalidInput("Missing user_id".to_string())),
+        }
+    }
+    /// Verifies a session token and returns its session.
+    pub fn verify_token(&self, token: &str) -> Result<Session, AssistantError> {
+        let invalid = || AssistantError::Unauthorized("Invalid session token".to_string());
+        let parts: Vec<&str> = token.split('.').collect();
+        let user_id = match parts.as_slice() {
+            // The header of a JWT is never a number
+            [user_id, issued_at, signature] if issued_at.bytes().all(|b| b.is_ascii_digit()) => {
+                let signature = decode_hex(signature).map_err(|_| invalid())?;
+                let signed = format!("{}.{}", user_id, issued_at);
+                if !self.signature_matches(signed.as_bytes(), &signature)? {
+                    return Err(invalid());
+                }
+                let issued_at: i64 = issued_at.parse().map_err(|_| invalid())?;
+                let now = Utc::now().timestamp();
+                let expires_at = issued_at + self.config.signed_user_id_ttl_secs as i64;
+                if issued_at > now + EXPIRY_LEEWAY_SECS || expires_at + EXPIRY_LEEWAY_SECS < now {
+                    return Err(AssistantError::Unauthorized(
+                        "Session token expired".to_string(),
+                    ));
+                }
+                user_id.to_string()
+            }
+            [header, claims, signature] => {
+                let header: Value = decode_json(header).ok_or_else(invalid)?;
+                // Only HMAC tokens are accepted, never unsigned ones
+                if header["alg"] != "HS256" {
+                    return Err(invalid());
+                }
+                let signature = decode_base64url(signature).ok_or_else(invalid)?;
+                let signed = format!("{}.{}", parts[0], parts[1]);
+                if !self.signature_matches(signed.as_bytes(), &signature)? {
+                    return Err(invalid());
+                }
+                let claims: Claims = decode_json(claims).ok_or_else(invalid)?;
+                if claims.exp + EXPIRY_LEEWAY_SECS < Utc::now().timestamp() {
+                    return Err(AssistantError::Unauthorized(
+                        "Session token expired".to_string(),
+                    ));
+                }
+                claims.sub
+            }
+            _ => return Err(invalid()),
+        };
+        if user_id.is_empty() {
+            return Err(invalid());
+        }
+        let session = Session { user_id };
+        if session.guest() && !self.config.guest_sessions {
+            return Err(AssistantError::Unauthorized(
+                "Guest sessions are disabled".to_string(),
+            ));
+        }
+        Ok(session)
+    }
+    /// Signs a user ID at the given Unix timestamp, the result is a session token that
+    /// expires `signed_user_id_ttl_secs` later.
+    pub fn sign_user_id(&self, user_id: &str, issued_at: i64) -> Result<String, AssistantError> {
+        let signed = format!("{}.{}", user_id, issued_at);
+        let signature = self.sign(signed.as_bytes())?;
+        let hex: String = signature
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Ok(format!("{}.{}", signed, hex))
+    }
+    /// Issues an HS256 JWT for a user that expires at the given Unix timestamp.
+    pub fn issue_token(&self, user_id: &str, expires_at: i64) -> Result<String, AssistantError> {
+        let header = encode_base64url(json!({ "alg": "HS256", "typ": "JWT" }).to_string());
+        let claims = encode_base64url(
+            json!({ "sub": user_id, "exp": expires_at, "iat": Utc::now().timestamp() }).to_string(),
+        );
+        let signed = format!("{}.{}", header, claims);
+        let signature = encode_base64url(self.sign(signed.as_bytes())?);
+        Ok(format!("{}.{}", signed, signature))
+    }
+    /// Starts an anonymous guest session with a random user ID.
+    pub fn guest_session(&self) -> Result<GuestSession, AssistantError> {
+        if !self.config.enabled || !self.config.guest_sessions {
+            return Err(AssistantError::NotFound(
+                "Guest sessions are disabled".to_string(),
+            ));
+        }
+        let mut bytes = [0u8; 16];
+        rand_bytes(&mut bytes).map_err(|e| {
+            AssistantError::DatabaseError(format!("Failed to create guest session: {}", e))
+        })?;
+        let user_id = format!(
+            "{}{}",
+            GUEST_PREFIX,
+            bytes
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        );
+        let expires_at = Utc::now().timestamp() + self.config.guest_session_ttl_secs as i64;
+        let token = self.issue_token(&user_id, expires_at)?;
+        Ok(GuestSession {
+            user_id,
+            token,
+            expires_at,
+        })
+    }
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>, AssistantError> {
+        let secret = self.config.secret.as_deref().unwrap_or_default();
+        let signature = PKey::hmac(secret.as_bytes()).and_then(|key| {
+            let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+            signer.sign_oneshot_to_vec(data)
+        });
+        signature.map_err(|e| AssistantError::DatabaseError(format!("Failed to sign: {}", e)))
+    }
+    // Constant time comparison, memcmp::eq requires slices of equal length
+    fn signature_matches(&self, data: &[u8], signature: &[u8]) -> Result<bool, AssistantError> {
+        let expected = self.sign(data)?;
+        Ok(expected.len() == signature.len() && openssl::memcmp::eq(&expected, signature))
+    }
+}
+
+fn encode_base64url(data: impl AsRef<[u8]>) -> String {
+    base64::encode_block(data.as_ref())
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+fn decode_base64url(encoded: &str) -> Option<Vec<u8>> {
+    if encoded.contains(['+', '/', '=']) {
+        return None;
+    }
+    let mut standard = encoded.replace('-', "+").replace('_', "/");
+    while !standard.len().is_multiple_of(4) {
+        standard.push('=');
+    }
+    base64::decode_block(&standard).ok()
+}
+
+fn decode_json<T: serde::de::DeserializeOwned>(encoded: &str) -> Option<T> {
+    serde_json::from_slice(&decode_base64url(encoded)?).ok()
+}
@@ -0,0 +1,134 @@
+use crate::assistant::{
+    AssistantChatInput, AssistantError, AssistantHistoryResponse, MessageListQuery, RunRecord,
+    SimplifiedMessage,
+};
+use crate::limits::RunPermit;
+use axum::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use tokio::sync::mpsc;
+
+// Number of stream events buffered until the client reads them
+const STREAM_BUFFER: usize = 64;
+
+/// An event of a streamed reply.
+#[derive(Debug)]
+pub enum StreamEvent {
+    // A part of the text of the reply as it is generated, citations are not resolved yet
+    Delta(String),
+    // The complete messages of the reply, sent once at the end of the stream
+    Done(Vec<SimplifiedMessage>),
+}
+/// A streamed reply, errors end the stream.
+pub type ReplyStream = BoxStream<'static, Result<StreamEvent, AssistantError>>;
+
+/// The API answering the chat requests of a deployment, selected by `chat.backend`.
+/// Both backends log the conversation to the conversation store, so the HTTP routes,
+/// feedback and admin exports work the same for both.
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    /// Sends the message of the user and waits for the reply. The statistics of the request
+    /// are recorded into the record, which is saved by the caller also if the request fails.
+    async fn send_message(
+        &self,
+        input: AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError>;
+    /// Sends the message of the user and streams the reply. Invalid input is rejected before
+    /// the stream starts, the record is completed and saved by the backend once the stream ends.
+    /// The run slot of the permit is held until the reply is generated, also if the client
+    /// is gone before.
+    async fn stream_reply(
+        &self,
+        input: AssistantChatInput,
+        record: RunRecord,
+        permit: Option<RunPermit>,
+    ) -> Result<ReplyStream, AssistantError>;
+    /// Adds a reply that was not generated for this message, e.g. a cached answer, to the
+    /// conversation of the user together with the message. No run is created.
+    async fn save_reply(
+        &self,
+        input: &AssistantChatInput,
+        reply: &str,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError>;
+    /// Lists the conversation history of a user, a single page if the query has a limit.
+    async fn list_history(
+        &self,
+        user_id: &str,
+        query: &MessageListQuery,
+    ) -> Result<AssistantHistoryResponse, AssistantError>;
+}
+
+/// Creates a channel whose receiving end is a reply stream, so a backend can produce
+/// the reply in a spawned task. The task stops early if the send fails, i.e. the client is gone.
+pub(crate) fn reply_channel() -> (
+    mpsc::Sender<Result<StreamEvent, AssistantError>>,
+    ReplyStream,
+) {
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+    let stream = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    (tx, stream.boxed())
+}
+
+/// A server-sent event of a streaming OpenAI response.
+pub(crate) struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+/// Reads the server-sent events of a streaming response, events can span several chunks.
+pub(crate) struct SseReader {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+}
+impl SseReader {
+    pub fn new(response: reqwest::Response) -> Self {
+        SseReader {
+            response,
+            buffer: Vec::new(),
+        }
+    }
+    /// Returns the next event, None once the response is finished.
+    pub async fn next_event(&mut self) -> Result<Option<SseEvent>, AssistantError> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+                let block: Vec<u8> = self.buffer.drain(..end + 2).collect();
+                match parse_event(&String::from_utf8_lossy(&block)) {
+                    Some(event) => return Ok(Some(event)),
+                    None => continue,
+                }
+            }
+            match self.response.chunk().await? {
+                // Line endings can be \r\n, events are separated by an empty line
+                Some(chunk) => self
+                    .buffer
+                    .extend(chunk.iter().filter(|byte| **byte != b'\r')),
+                None => {
+                    let block = String::from_utf8_lossy(&self.buffer).to_string();
+                    self.buffer.clear();
+                    return Ok(parse_event(&block));
+                }
+            }
+        }
+    }
+}
+// Parses the lines of an event, comments and blocks without data are skipped
+fn parse_event(block: &str) -> Option<SseEvent> {
+    let mut event = None;
+    let mut data = Vec::new();
+    for line in block.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    if data.is_empty() {
+        return None;
+    }
+    Some(SseEvent {
+        event,
+        data: data.join("\n"),
+    })
+}
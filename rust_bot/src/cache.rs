@@ -0,0 +1,237 @@
+use crate::assistant::{
+    AssistantChatInput, AssistantError, Citation, RunRecord, SimplifiedMessage,
+};
+use crate::config::{CacheConfig, Config};
+use crate::redaction::Redactor;
+use crate::retrieval::{self, content_hash, cosine, Embedder};
+use log::{error, info};
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// An answer of the assistant that can be given again to the same question.
+#[derive(Debug, Clone)]
+pub struct CachedAnswer {
+    pub text: String,
+    pub citations: Vec<Citation>,
+}
+/// The question of a message as it is looked up in the cache: the normalized text,
+/// the locale and the version of the instruction, with the embedding if similar
+/// questions are matched.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    question: String,
+    locale: String,
+    instruction_version: String,
+    embedding: Option<Vec<f32>>,
+}
+struct CacheEntry {
+    key: CacheKey,
+    answer: CachedAnswer,
+    created_at: Instant,
+}
+struct CacheState {
+    instruction_version: String,
+    // Oldest entry first, the oldest entries are evicted when the cache is full
+    entries: Vec<CacheEntry>,
+}
+
+/// Answers of repeated questions like "how does shipping work", so they are answered
+/// without a run. Only answers that did not call a tool are cached, they do not depend
+/// on the account of the user. Questions and answers with personal data are never cached,
+/// a similar question of another user could be answered with it. The cache is emptied when
+/// the resources are rotated.
+pub struct AnswerCache {
+    config: CacheConfig,
+    instruction_file: String,
+    embedder: Option<Arc<dyn Embedder>>,
+    // Detects the personal data also if the redaction of the logs is disabled
+    detector: Redactor,
+    state: RwLock<CacheState>,
+}
+impl AnswerCache {
+    /// Creates the cache, similar questions are matched with the embedder if one is given.
+    pub fn new(
+        config: CacheConfig,
+        instruction_file: String,
+        embedder: Option<Arc<dyn Embedder>>,
+    ) -> Result<Self, AssistantError> {
+        let instruction_version = instruction_version(&instruction_file)?;
+        let detector = Redactor::new(true, None).map_err(AssistantError::InvalidInput)?;
+        Ok(AnswerCache {
+            config,
+            instruction_file,
+            embedder,
+            detector,
+            state: RwLock::new(CacheState {
+                instruction_version,
+                entries: Vec::new(),
+            }),
+        })
+    }
+    /// Creates the cache of the `cache` settings, with the embedder of the retrieval settings.
+    pub fn from_config(config: &Config) -> Result<Self, AssistantError> {
+        let embedder = config
+            .cache
+            .uses_similarity()
+            .then(|| retrieval::embedder(&config.retrieval, &config.openai.api_key));
+        Self::new(
+            config.cache.clone(),
+            config.assistant.instruction_file.clone(),
+            embedder,
+        )
+    }
+    /// Whether answers are cached.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    /// The key of a message, None if the cache is disabled or the message can not be
+    /// answered from the cache, e.g. because it has an image, personal data or an invalid
+    /// context.
+    pub async fn key(&self, input: &AssistantChatInput) -> Option<CacheKey> {
+        if !self.config.enabled
+            || input.image.is_some()
+            || self.detector.contains_personal_data(&input.form.message)
+        {
+            return None;
+        }
+        let context = input.form.context();
+        if context.validate().is_err() {
+            return None;
+        }
+        let question = normalize_question(&input.form.message);
+        if question.is_empty() {
+            return None;
+        }
+        // Without an embedding only equal questions are matched
+        let embedding = match &self.embedder {
+            Some(embedder) => match embedder.embed(std::slice::from_ref(&question)).await {
+                Ok(mut embeddings) => embeddings.pop(),
+                Err(e) => {
+                    error!("Failed to embed the question for the answer cache: {:?}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        Some(CacheKey {
+            question,
+            locale: context.locale.unwrap_or_default().to_lowercase(),
+            instruction_version: self.state.read().await.instruction_version.clone(),
+            embedding,
+        })
+    }
+    /// The cached answer of an equal question or else of the most similar question
+    /// above the similarity threshold.
+    pub async fn get(&self, key: &CacheKey) -> Option<CachedAnswer> {
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        let state = self.state.read().await;
+        let candidates = state.entries.iter().filter(|entry| {
+            entry.created_at.elapsed() < ttl
+                && entry.key.locale == key.locale
+                && entry.key.instruction_version == key.instruction_version
+        });
+        let mut best: Option<(f32, &CacheEntry)> = None;
+        for entry in candidates {
+            if entry.key.question == key.question {
+                info!("Answer cache hit for an equal question");
+                return Some(entry.answer.clone());
+            }
+            if let (Some(a), Some(b)) = (&key.embedding, &entry.key.embedding) {
+                let similarity = cosine(a, b);
+                if similarity >= self.config.similarity_threshold
+                    && best.is_none_or(|(score, _)| similarity > score)
+                {
+                    best = Some((similarity, entry));
+                }
+            }
+        }
+        best.map(|(similarity, entry)| {
+            info!("Answer cache hit with similarity {:.3}", similarity);
+            entry.answer.clone()
+        })
+    }
+    /// Caches the answer to a question. Answers for an older instruction and answers with
+    /// personal data are dropped.
+    pub async fn insert(&self, key: CacheKey, answer: CachedAnswer) {
+        if self.detector.contains_personal_data(&answer.text) {
+            info!("Answer with personal data not cached");
+            return;
+        }
+        let ttl = Duration::from_secs(self.config.ttl_secs);
+        let mut state = self.state.write().await;
+        if key.instruction_version != state.instruction_version {
+            return;
+        }
+        state.entries.retain(|entry| {
+            entry.created_at.elapsed() < ttl
+                && (entry.key.question != key.question || entry.key.locale != key.locale)
+        });
+        let max_entries = self.config.max_entries as usize;
+        if state.entries.len() >= max_entries {
+            let evicted = state.entries.len() + 1 - max_entries;
+            state.entries.drain(..evicted);
+        }
+        state.entries.push(CacheEntry {
+            key,
+            answer,
+            created_at: Instant::now(),
+        });
+    }
+    /// Empties the cache and reads the version of the instruction again,
+    /// called when the resources and the assistant are rotated.
+    pub async fn invalidate(&self) {
+        let mut state = self.state.write().await;
+        match instruction_version(&self.instruction_file) {
+            Ok(version) => state.instruction_version = version,
+            Err(e) => error!(
+                "Failed to read the instruction for the answer cache: {:?}",
+                e
+            ),
+        }
+        info!(
+            "Answer cache invalidated, {} answers removed",
+            state.entries.len()
+        );
+        state.entries.clear();
+    }
+}
+
+/// The answer of a request, if it can be cached: a completed run with a single
+/// message of the assistant that did not call a tool.
+pub fn cacheable_answer(
+    record: &RunRecord,
+    messages: &[SimplifiedMessage],
+) -> Option<CachedAnswer> {
+    if record.status != "completed" || record.tool_calls > 0 {
+        return None;
+    }
+    match messages {
+        [message] if message.role == "assistant" && !message.text.trim().is_empty() => {
+            Some(CachedAnswer {
+                text: message.text.clone(),
+                citations: message.citations.clone(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Lowercases the question and removes punctuation and repeated whitespace,
+/// so "How does shipping work?" and "how does shipping work" are equal.
+pub fn normalize_question(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Hash of the instruction file, answers given with another instruction are not reused
+fn instruction_version(instruction_file: &str) -> Result<String, AssistantError> {
+    let instruction = fs::read_to_string(instruction_file).map_err(|e| {
+        AssistantError::DatabaseError(format!("Failed to read instruction file: {}", e))
+    })?;
+    Ok(content_hash(&instruction)[..16].to_string())
+}
@@ -1,173 +1,618 @@
-use axum::{extract::Extension, response::IntoResponse, Json};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePool},
-    Error,
+use crate::assistant::{
+    execute_tool_call, get_tool_definition, AssistantChatInput, AssistantError,
+    AssistantHistoryResponse, ChatSummary, LoggedMessage, MessageListQuery, RunContext, RunRecord,
+    SimplifiedMessage, ToolCall, LOG,
 };
-use std::str::FromStr;
-
+use crate::backend::{reply_channel, ChatBackend, ReplyStream, StreamEvent};
+use crate::config::Config;
+use crate::limits::RunPermit;
+use crate::provider::{
+    Completion, CompletionEvent, CompletionRequest, CompletionStream, LlmProvider,
+};
+use crate::retrieval::{self, RetrievalIndex};
+use axum::async_trait;
+use chrono::Utc;
+use futures::StreamExt;
 use log::{error, info};
-use std::env;
+use openssl::rand::rand_bytes;
+use serde_json::{json, Value};
+use sqlx::MySqlPool;
+use std::fs;
+use std::sync::Arc;
 
-/// db.rs
-pub async fn create_db_pool(database_url: &str) -> Result<SqlitePool, Error> {
-    // Remove the `sqlite:` scheme from the `database_url` if it's present
-    let connect_options = SqliteConnectOptions::from_str(database_url)?
-        .create_if_missing(true)
-        .to_owned();
-    let pool = SqlitePool::connect_with(connect_options).await?;
-    Ok(pool)
-}
-// Function to retrieve the conversation history from the database.
-async fn get_conversation_history(pool: &SqlitePool, chat_id: &str) -> Result<Vec<String>, Error> {
-    let messages = sqlx::query!(
-        "SELECT content FROM messages WHERE chat_id = ? ORDER BY created_at ASC",
-        chat_id
-    )
-    .fetch_all(pool)
-    .await?
-    .into_iter()
-    .map(|record| record.content) // Directly map to String, assuming content is NOT NULL
-    .collect();
-    Ok(messages)
-}
-// Function to save a new message to the conversation history in the database.
-async fn save_message_to_history(
-    pool: &SqlitePool,
-    chat_id: &str,
-    message: &str,
-) -> Result<(), Error> {
-    sqlx::query!(
-        "INSERT INTO messages (chat_id, content) VALUES (?, ?)",
-        chat_id,
-        message
-    )
-    .execute(pool)
-    .await?;
-    Ok(())
-}
+// Saved as the assistant ID of the runs, completions are not answered by an assistant
+const COMPLETIONS_ASSISTANT_ID: &str = "chat.completions";
+// Tokens are estimated from the length of the texts, roughly four characters per token
+const CHARS_PER_TOKEN: u64 = 4;
+// Estimated tokens of the role and the separators of a message
+const MESSAGE_OVERHEAD_TOKENS: u64 = 4;
+// Instruction of the summarization request
+const SUMMARY_PROMPT: &str = "Summarize the conversation between a user and the buycycle chat bot \
+    for the bot to continue it. Keep the bikes, sizes, budgets, orders and preferences the user \
+    mentioned and the answers given. Extend the previous summary if there is one. \
+    Reply with the summary only.";
+// Sent with a history whose personal data is masked, so the model does not repeat the placeholders
+const MASKED_HISTORY_NOTE: &str = "Personal data in the earlier conversation is replaced by \
+    placeholders like [EMAIL], [PHONE], [ORDER], [IBAN] or [ADDRESS]. Never repeat a placeholder, \
+    ask the user again if you need the data.";
 
-#[derive(Deserialize)]
-pub struct ChatRequest {
-    pub chat_id: String,
-    pub message: String,
+/// Completion Model based chat backend.
+/// There are no threads, the conversation is kept in the log database and sent with every request.
+/// The newest messages within the history token budget are sent as they are,
+/// the older ones are replaced by a rolling summary. The model can call the tools of the assistant.
+#[derive(Clone)]
+pub struct CompletionsBackend {
+    config: Arc<Config>,
+    // API answering the completion requests, selected by llm.provider
+    provider: Arc<dyn LlmProvider>,
+    // Database of the buycycle platform, used by the tools
+    db_pool_buycycle: MySqlPool,
+    // Local index searched by the search tool, the tool is not offered without it
+    retrieval: Option<RetrievalIndex>,
+    log: LOG,
+    // Instruction file of the assistant, sent as the system prompt
+    system_prompt: Arc<String>,
 }
-#[derive(Serialize)]
-pub struct ChatResponse {
-    pub response: String,
-}
-/// Completion Model based chat handler
-pub async fn chat_handler(
-    Extension(db_pool): Extension<SqlitePool>,
-    Json(chat_request): Json<ChatRequest>,
-) -> impl IntoResponse {
-    let chat_id = chat_request.chat_id;
-    info!("Received chat request for chat_id: {}", chat_id);
-    let client = Client::new();
-    let api_key = match env::var("OPENAI_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            error!("OPENAI_API_KEY not set");
-            return Json(ChatResponse {
-                response: "OPENAI_API_KEY not set".to_string(),
-            });
+impl CompletionsBackend {
+    pub fn new(
+        config: Arc<Config>,
+        provider: Arc<dyn LlmProvider>,
+        db_pool_buycycle: MySqlPool,
+        log: LOG,
+    ) -> Result<Self, AssistantError> {
+        let system_prompt = fs::read_to_string(&config.assistant.instruction_file)
+            .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
+        Ok(CompletionsBackend {
+            config,
+            provider,
+            db_pool_buycycle,
+            retrieval: None,
+            log,
+            system_prompt: Arc::new(system_prompt),
+        })
+    }
+    /// Offers the search tool of the retrieval index to the model.
+    pub fn with_retrieval(mut self, retrieval: RetrievalIndex) -> Self {
+        self.retrieval = Some(retrieval);
+        self
+    }
+    /// Validates the input and returns the chat ID of the user,
+    /// a new chat is created if the user has no chat yet.
+    async fn prepare_chat(
+        &self,
+        input: &AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<(String, RunContext), AssistantError> {
+        record.assistant_id = COMPLETIONS_ASSISTANT_ID.to_string();
+        if input.image.is_some() {
+            return Err(AssistantError::InvalidInput(
+                "Images are not supported by the completions backend".to_string(),
+            ));
         }
-    };
-    // Retrieve the conversation history from the database.
-    let history = match get_conversation_history(&db_pool, &chat_id).await {
-        Ok(history) => {
-            info!(
-                "Successfully retrieved conversation history for chat_id: {}",
+        if input.form.message.trim().is_empty() {
+            return Err(AssistantError::InvalidInput(
+                "Message is required".to_string(),
+            ));
+        }
+        let context = input.form.context();
+        context.validate()?;
+        record.locale = context.language();
+        let user_id = &input.form.user_id;
+        let chat_id = match self.log.get_chat_id(user_id).await? {
+            Some(id) => id,
+            None => {
+                let chat_id = new_chat_id()?;
+                self.log.save_chat_id(user_id, &chat_id).await?;
                 chat_id
-            );
-            history
-        }
-        Err(e) => {
-            error!(
-                "Failed to retrieve conversation history for chat_id: {}: {}",
-                chat_id, e
-            );
-            return Json(ChatResponse {
-                response: "Failed to retrieve conversation history".to_string(),
-            });
+            }
+        };
+        record.chat_id = Some(chat_id.clone());
+        info!(
+            "chat_id: {}, message: {}",
+            chat_id,
+            self.log.mask(&input.form.message)
+        );
+        Ok((chat_id, context))
+    }
+    /// Saves the message of the user and constructs the messages payload
+    /// from the conversation history.
+    async fn start_request(
+        &self,
+        input: &AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<(String, Vec<Value>), AssistantError> {
+        let (chat_id, context) = self.prepare_chat(input, record).await?;
+        self.log
+            .save_message_to_db(&chat_id, "user", &input.form.message, None)
+            .await?;
+        let messages = self
+            .request_messages(&chat_id, &context, &input.form.message)
+            .await?;
+        Ok((chat_id, messages))
+    }
+    /// Constructs the messages payload: the system prompt, the summary of the older messages,
+    /// the newest messages of the conversation within the history token budget and the
+    /// message of the user. The message is already logged as the last message of the chat,
+    /// it is sent as the user wrote it since the logged copy can be masked. Without a way to
+    /// reveal the personal data of the log, the history is sent masked and the model is told
+    /// about the placeholders.
+    async fn request_messages(
+        &self,
+        chat_id: &str,
+        context: &RunContext,
+        message: &str,
+    ) -> Result<Vec<Value>, AssistantError> {
+        let mut summary = self.log.get_chat_summary(chat_id).await?;
+        let mut history = self.log.get_messages(chat_id).await?;
+        let summarized = summary
+            .as_ref()
+            .map_or(0, |summary| summary.last_message_id);
+        history.retain(|message| {
+            message.id > summarized && (message.role == "user" || message.role == "assistant")
+        });
+        if history.last().is_some_and(|message| message.role == "user") {
+            history.pop();
         }
-    };
-    // Construct the messages payload using the conversation history.
-    let mut messages = vec![serde_json::json!({
-        "role": "system",
-        "content": "You are a helpful buyers guide chat bot that helps to find used bikes."
-    })];
-    for message in &history {
-        messages.push(serde_json::json!({
-            "role": "user",
-            "content": message,
-        }));
-    }
-    messages.push(serde_json::json!({
-        "role": "user",
-        "content": chat_request.message,
-    }));
-    let payload = serde_json::json!({
-        "model": "gpt-4",
-        "messages": messages,
-    });
-    // Send the request to OpenAI API
-    let response = match client
-        .post("https://api.openai.com/v1/chat/completions")
-        .bearer_auth(&api_key)
-        .json(&payload)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            info!("Request to OpenAI API sent successfully");
-            res
-        }
-        Err(e) => {
-            error!("Failed to send request to OpenAI: {}", e);
-            return Json(ChatResponse {
-                response: format!("Failed to send request to OpenAI: {}", e),
-            });
+        // The message of the user is always sent, the history gets the rest of the budget
+        let budget = self
+            .config
+            .completions
+            .history_token_budget
+            .saturating_sub(estimate_tokens(message));
+        let older = split_history(&mut history, budget);
+        info!(
+            "Sending {} messages of the conversation history for chat_id: {}, {} summarized",
+            history.len(),
+            chat_id,
+            older.len()
+        );
+        if !older.is_empty() {
+            // Without a new summary the older messages are dropped, the request is still answered
+            match self.summarize(summary.as_ref(), &older).await {
+                Ok(text) => {
+                    let new_summary = ChatSummary {
+                        summary: text,
+                        last_message_id: older[older.len() - 1].id,
+                    };
+                    self.log.save_chat_summary(chat_id, &new_summary).await?;
+                    summary = Some(new_summary);
+                }
+                Err(e) => error!("Failed to summarize chat_id {}: {:?}", chat_id, e),
+            }
         }
-    };
-    if !response.status().is_success() {
-        let error_message = match response.text().await {
-            Ok(text) => text,
-            Err(_) => {
-                error!("Failed to read error message from OpenAI API response");
-                "Failed to read error message from OpenAI API response".to_string()
+        let mut messages = vec![json!({
+            "role": "system",
+            "content": self.system_prompt.as_str(),
+        })];
+        let summary_sent = summary.is_some();
+        if let Some(summary) = summary {
+            messages.push(json!({
+                "role": "system",
+                "content": format!("Summary of the earlier conversation:\n{}", summary.summary),
+            }));
+        }
+        if let Some(instructions) = context.additional_instructions() {
+            messages.push(json!({
+                "role": "system",
+                "content": instructions,
+            }));
+        }
+        if !self.log.reveals_personal_data() && (!history.is_empty() || summary_sent) {
+            messages.push(json!({
+                "role": "system",
+                "content": MASKED_HISTORY_NOTE,
+            }));
+        }
+        for message in &history {
+            messages.push(json!({
+                "role": message.role,
+                "content": message.content,
+            }));
+        }
+        messages.push(json!({ "role": "user", "content": message }));
+        Ok(messages)
+    }
+    /// Summarizes the older messages together with the previous summary.
+    async fn summarize(
+        &self,
+        previous: Option<&ChatSummary>,
+        older: &[LoggedMessage],
+    ) -> Result<String, AssistantError> {
+        let mut conversation = String::new();
+        if let Some(previous) = previous {
+            conversation.push_str(&format!("Previous summary:\n{}\n\n", previous.summary));
+        }
+        conversation.push_str("Conversation:\n");
+        for message in older {
+            conversation.push_str(&format!("{}: {}\n", message.role, message.content));
+        }
+        let request = CompletionRequest {
+            messages: vec![
+                json!({ "role": "system", "content": SUMMARY_PROMPT }),
+                json!({ "role": "user", "content": conversation }),
+            ],
+            max_tokens: Some(self.config.completions.summary_max_tokens),
+            ..Default::default()
+        };
+        let completion = self.provider.complete(&request).await?;
+        Some(completion.content)
+            .filter(|summary| !summary.trim().is_empty())
+            .ok_or_else(|| AssistantError::OpenAIError("Empty summary from the model".to_string()))
+    }
+    /// Request with the tools of the assistant. In the last round of tool calls
+    /// the tools are disabled, so the model has to answer.
+    fn request(&self, messages: &[Value], round: u64) -> CompletionRequest {
+        let mut tools = get_tool_definition()
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        if self.retrieval.is_some() {
+            tools.push(retrieval::tool_definition());
+        }
+        CompletionRequest {
+            messages: messages.to_vec(),
+            tools,
+            allow_tools: round < self.config.completions.max_tool_iterations,
+            max_tokens: None,
+        }
+    }
+    /// Sends a streaming completion request, the reply is read from the returned stream.
+    async fn start_stream(
+        &self,
+        input: &AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<(String, Vec<Value>, CompletionStream), AssistantError> {
+        let (chat_id, messages) = self.start_request(input, record).await?;
+        let stream = self.provider.stream(&self.request(&messages, 0)).await?;
+        Ok((chat_id, messages, stream))
+    }
+    // The model has to answer in the last round, tool calls are not followed anymore
+    fn check_tool_round(&self, round: u64) -> Result<(), AssistantError> {
+        if round >= self.config.completions.max_tool_iterations {
+            return Err(AssistantError::OpenAIError(format!(
+                "No answer after {} rounds of tool calls",
+                round
+            )));
+        }
+        Ok(())
+    }
+    /// Executes the tool calls of a completion and logs them. The message of the model
+    /// and the outputs of the tools are added to the messages of the next request.
+    async fn call_tools(
+        &self,
+        user_id: &str,
+        completion: &Completion,
+        record: &mut RunRecord,
+        messages: &mut Vec<Value>,
+    ) -> Result<(), AssistantError> {
+        let tool_calls: Vec<ToolCall> = serde_json::from_value(json!(completion.tool_calls))
+            .map_err(|e| {
+                AssistantError::OpenAIError(format!("Failed to parse tool calls: {}", e))
+            })?;
+        record.tool_calls += tool_calls.len() as i64;
+        let completion_id = record.run_id.clone().unwrap_or_default();
+        messages.push(completion.message());
+        for tool_call in &tool_calls {
+            info!("Processing tool call with ID: {}", tool_call.id);
+            let tool_call_record = execute_tool_call(
+                &self.config,
+                &self.db_pool_buycycle,
+                self.retrieval.as_ref(),
+                user_id,
+                &completion_id,
+                tool_call,
+            )
+            .await;
+            if let Err(e) = self.log.save_tool_call(&tool_call_record).await {
+                error!("Failed to save tool call {}: {:?}", tool_call.id, e);
+            }
+            messages.push(json!({
+                "role": "tool",
+                "tool_call_id": tool_call.id,
+                "content": tool_call_record.submitted_output(),
+            }));
+        }
+        Ok(())
+    }
+    /// Reads the streamed completions of a request, executing the tool calls of the model
+    /// and streaming the next completion until the model answers. Returns the answer.
+    async fn stream_completions(
+        &self,
+        user_id: &str,
+        mut messages: Vec<Value>,
+        mut stream: CompletionStream,
+        record: &mut RunRecord,
+        tx: &tokio::sync::mpsc::Sender<Result<StreamEvent, AssistantError>>,
+    ) -> Result<String, AssistantError> {
+        let mut round = 0;
+        loop {
+            let completion = read_stream(&mut stream, tx).await?;
+            record_completion(record, &completion);
+            if completion.tool_calls.is_empty() {
+                return Ok(completion.content);
+            }
+            self.check_tool_round(round)?;
+            self.call_tools(user_id, &completion, record, &mut messages)
+                .await?;
+            round += 1;
+            stream = self
+                .provider
+                .stream(&self.request(&messages, round))
+                .await?;
+        }
+    }
+    /// Sends the request and executes the tool calls of the model until it answers.
+    async fn complete_with_tools(
+        &self,
+        user_id: &str,
+        mut messages: Vec<Value>,
+        record: &mut RunRecord,
+    ) -> Result<String, AssistantError> {
+        let mut round = 0;
+        loop {
+            let completion = self
+                .provider
+                .complete(&self.request(&messages, round))
+                .await?;
+            record_completion(record, &completion);
+            if completion.tool_calls.is_empty() {
+                return Ok(completion.content);
+            }
+            self.check_tool_round(round)?;
+            self.call_tools(user_id, &completion, record, &mut messages)
+                .await?;
+            round += 1;
+        }
+    }
+    /// Answers a message another backend failed to answer, e.g. after a run of the assistant
+    /// timed out. The message is already logged in the chat, so the recent history of the chat
+    /// is sent with the message of the input like for a request of this backend; without a
+    /// chat only the message is sent. The reply is not saved.
+    pub async fn fallback_reply(
+        &self,
+        input: &AssistantChatInput,
+        chat_id: Option<&str>,
+        record: &mut RunRecord,
+    ) -> Result<String, AssistantError> {
+        let context = input.form.context();
+        let messages = match chat_id {
+            Some(chat_id) => {
+                self.request_messages(chat_id, &context, &input.form.message)
+                    .await?
+            }
+            None => vec![
+                json!({ "role": "system", "content": self.system_prompt.as_str() }),
+                json!({ "role": "user", "content": input.form.message }),
+            ],
+        };
+        self.complete_with_tools(&input.form.user_id, messages, record)
+            .await
+    }
+    /// Saves the reply of the model to the conversation history in the database and returns it.
+    /// The completion ID is the message ID of the reply, so feedback can be given on it.
+    async fn finish_reply(
+        &self,
+        chat_id: &str,
+        record: &RunRecord,
+        reply: String,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+        let completion_id = record.run_id.clone();
+        self.log
+            .save_message_to_db(chat_id, "assistant", &reply, completion_id.as_deref())
+            .await?;
+        info!("Reply saved to history for chat_id: {}", chat_id);
+        Ok(vec![SimplifiedMessage {
+            id: completion_id,
+            created_at: Utc::now().timestamp(),
+            role: "assistant".to_string(),
+            text: reply,
+            citations: Vec::new(),
+            image_file_ids: Vec::new(),
+        }])
+    }
+}
+#[async_trait]
+impl ChatBackend for CompletionsBackend {
+    async fn send_message(
+        &self,
+        input: AssistantChatInput,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+        let (chat_id, messages) = self.start_request(&input, record).await?;
+        let reply = self
+            .complete_with_tools(&input.form.user_id, messages, record)
+            .await?;
+        record.status = "completed".to_string();
+        self.finish_reply(&chat_id, record, reply).await
+    }
+    async fn stream_reply(
+        &self,
+        input: AssistantChatInput,
+        mut record: RunRecord,
+        permit: Option<RunPermit>,
+    ) -> Result<ReplyStream, AssistantError> {
+        let start_time = std::time::Instant::now();
+        let (chat_id, messages, completions) = match self.start_stream(&input, &mut record).await {
+            Ok(started) => started,
+            Err(e) => {
+                record.status = "error".to_string();
+                record.latency_ms = start_time.elapsed().as_millis() as i64;
+                if let Err(e) = self.log.save_run(&record).await {
+                    error!("Failed to save run statistics: {:?}", e);
+                }
+                return Err(e);
             }
         };
-        return Json(ChatResponse {
-            response: error_message,
+        let (tx, stream) = reply_channel();
+        let backend = self.clone();
+        tokio::spawn(async move {
+            let user_id = input.form.user_id;
+            let result = match backend
+                .stream_completions(&user_id, messages, completions, &mut record, &tx)
+                .await
+            {
+                Ok(reply) => backend.finish_reply(&chat_id, &record, reply).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(messages) => {
+                    record.status = "completed".to_string();
+                    let _ = tx.send(Ok(StreamEvent::Done(messages))).await;
+                }
+                Err(e) => {
+                    error!(
+                        "Streamed completion failed for chat_id {}: {:?}",
+                        chat_id, e
+                    );
+                    record.status = "error".to_string();
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+            record.latency_ms = start_time.elapsed().as_millis() as i64;
+            if let Err(e) = backend.log.save_run(&record).await {
+                error!("Failed to save run statistics: {:?}", e);
+            }
+            drop(permit);
         });
+        Ok(stream)
+    }
+    /// Logs the message and the reply in the chat of the user, like a completed request.
+    async fn save_reply(
+        &self,
+        input: &AssistantChatInput,
+        reply: &str,
+        record: &mut RunRecord,
+    ) -> Result<Vec<SimplifiedMessage>, AssistantError> {
+        let (chat_id, _) = self.prepare_chat(input, record).await?;
+        self.log
+            .save_message_to_db(&chat_id, "user", &input.form.message, None)
+            .await?;
+        self.finish_reply(&chat_id, record, reply.to_string()).await
     }
-    let openai_response: serde_json::Value = match response.json().await {
-        Ok(res) => res,
-        Err(_) => {
-            error!("Failed to parse response from OpenAI");
-            return Json(ChatResponse {
-                response: "Failed to parse response from OpenAI".to_string(),
-            });
+    /// Lists the logged messages of the chat of the user, the database IDs are the cursors.
+    async fn list_history(
+        &self,
+        user_id: &str,
+        query: &MessageListQuery,
+    ) -> Result<AssistantHistoryResponse, AssistantError> {
+        let chat_id = match self.log.get_chat_id(user_id).await? {
+            Some(id) => id,
+            None => return Ok(AssistantHistoryResponse::default()),
+        };
+        let mut messages = self.log.get_messages(&chat_id).await?;
+        let descending = query.order.as_deref() == Some("desc");
+        if descending {
+            messages.reverse();
         }
-    };
-    let response_text = openai_response["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or_default()
-        .to_string();
-    // Save the new message to the conversation history in the database.
-    if let Err(e) = save_message_to_history(&db_pool, &chat_id, &chat_request.message).await {
-        error!(
-            "Failed to save message to history for chat_id: {}: {}",
-            chat_id, e
-        );
-        return Json(ChatResponse {
-            response: "Failed to save message to history".to_string(),
+        // Keep the messages after and before the cursors in the order of the listing
+        let after = parse_cursor(query.after.as_deref())?;
+        let before = parse_cursor(query.before.as_deref())?;
+        messages.retain(|message| {
+            let is_after = |cursor: i64| (message.id > cursor) != descending;
+            after.is_none_or(|cursor| message.id != cursor && is_after(cursor))
+                && before.is_none_or(|cursor| message.id != cursor && !is_after(cursor))
         });
+        let has_more = query
+            .limit
+            .is_some_and(|limit| messages.len() > limit as usize);
+        if let Some(limit) = query.limit {
+            messages.truncate(limit as usize);
+        }
+        Ok(AssistantHistoryResponse {
+            first_id: messages.first().map(|message| message.id.to_string()),
+            last_id: messages.last().map(|message| message.id.to_string()),
+            has_more,
+            messages: messages.into_iter().map(simplify_logged_message).collect(),
+        })
+    }
+}
+
+/// Splits the history into the older messages to summarize and the newest messages
+/// within the token budget, which are kept in the history.
+fn split_history(history: &mut Vec<LoggedMessage>, token_budget: u64) -> Vec<LoggedMessage> {
+    let mut tokens = 0;
+    let mut kept = 0;
+    for message in history.iter().rev() {
+        tokens += estimate_tokens(&message.content);
+        if tokens > token_budget {
+            break;
+        }
+        kept += 1;
+    }
+    let older = history.len() - kept;
+    history.drain(..older).collect()
+}
+
+fn estimate_tokens(text: &str) -> u64 {
+    (text.chars().count() as u64).div_ceil(CHARS_PER_TOKEN) + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// Reads a streamed completion, forwards the text deltas and returns the complete message.
+async fn read_stream(
+    stream: &mut CompletionStream,
+    tx: &tokio::sync::mpsc::Sender<Result<StreamEvent, AssistantError>>,
+) -> Result<Completion, AssistantError> {
+    while let Some(event) = stream.next().await {
+        match event? {
+            CompletionEvent::Delta(text) => {
+                // The reply is saved also if the client is gone
+                let _ = tx.send(Ok(StreamEvent::Delta(text))).await;
+            }
+            CompletionEvent::Done(completion) => return Ok(completion),
+        }
+    }
+    Err(AssistantError::OpenAIError(
+        "The completion stream ended without a message".to_string(),
+    ))
+}
+
+/// Copies the ID, model and token usage of a completion to the record. The first
+/// completion of a request identifies the run, the usage of all completions is summed.
+fn record_completion(record: &mut RunRecord, completion: &Completion) {
+    if !completion.id.is_empty() {
+        record.run_id.get_or_insert_with(|| completion.id.clone());
+    }
+    if !completion.model.is_empty() {
+        record.model = Some(completion.model.clone());
+    }
+    let add = |total: Option<i64>, tokens: Option<i64>| match (total, tokens) {
+        (Some(total), Some(tokens)) => Some(total + tokens),
+        (total, tokens) => total.or(tokens),
+    };
+    record.prompt_tokens = add(record.prompt_tokens, completion.prompt_tokens);
+    record.completion_tokens = add(record.completion_tokens, completion.completion_tokens);
+}
+
+fn simplify_logged_message(message: LoggedMessage) -> SimplifiedMessage {
+    SimplifiedMessage {
+        id: message.message_id,
+        created_at: message.created_at.timestamp(),
+        role: message.role,
+        text: message.content,
+        citations: Vec::new(),
+        image_file_ids: Vec::new(),
     }
-    info!("Message saved to history for chat_id: {}", chat_id);
-    Json(ChatResponse {
-        response: response_text,
-    })
+}
+
+fn parse_cursor(cursor: Option<&str>) -> Result<Option<i64>, AssistantError> {
+    cursor
+        .map(|cursor| {
+            cursor
+                .parse()
+                .map_err(|_| AssistantError::InvalidInput(format!("Invalid cursor: {}", cursor)))
+        })
+        .transpose()
+}
+
+/// Creates a random chat ID, chats of the completions backend have no thread on OpenAI.
+fn new_chat_id() -> Result<String, AssistantError> {
+    let mut bytes = [0u8; 12];
+    rand_bytes(&mut bytes).map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
+    Ok(format!(
+        "chat_{}",
+        bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    ))
 }
@@ -0,0 +1,1062 @@
+use crate::redaction::decode_hex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+// Configuration file read if CONFIG_FILE is not set, it is optional
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+// Minimum length of the secret signing the session tokens
+const MIN_AUTH_SECRET_LENGTH: usize = 32;
+
+/// Configuration of the bot, loaded from a TOML file and overridden by environment variables.
+/// Every subsystem gets its settings from here instead of reading the environment itself.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub openai: OpenAiConfig,
+    pub llm: LlmConfig,
+    pub chat: ChatConfig,
+    pub completions: CompletionsConfig,
+    pub retrieval: RetrievalConfig,
+    pub cache: CacheConfig,
+    pub fallback: FallbackConfig,
+    pub assistant: AssistantConfig,
+    pub privacy: PrivacyConfig,
+    pub retention: RetentionPolicy,
+    pub stats: StatsConfig,
+    pub admin: AdminConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub buycycle: BuycycleConfig,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    // Folder of the static chat frontend served at the root
+    pub static_folder: String,
+}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    // MySQL database of the buycycle platform with bikes, users and orders
+    pub buycycle_url: String,
+    // Conversation log, a mysql:// or sqlite: URL
+    pub log_url: String,
+    // Apply pending migrations of the log database on startup
+    pub run_migrations: bool,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenAiConfig {
+    pub api_key: String,
+    pub model: String,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    // API of the completions backend, the assistants backend always uses OpenAI
+    pub provider: ProviderKind,
+    // Base URL of the API, e.g. http://localhost:11434/v1 for a local server
+    pub base_url: Option<String>,
+    // API key of an openai-compatible or the anthropic provider, openai uses openai.api_key
+    pub api_key: Option<String>,
+    // Model of the provider, defaults to openai.model
+    pub model: Option<String>,
+    // Maximum tokens of a reply, required by the Anthropic API
+    pub max_tokens: u64,
+}
+/// The API answering the chat completion requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ProviderKind {
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    // A server implementing the Chat Completions API, like vLLM or Ollama
+    #[serde(rename = "openai-compatible")]
+    OpenAiCompatible,
+    #[serde(rename = "anthropic")]
+    Anthropic,
+}
+impl FromStr for ProviderKind {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "openai" => Ok(ProviderKind::OpenAi),
+            "openai-compatible" => Ok(ProviderKind::OpenAiCompatible),
+            "anthropic" => Ok(ProviderKind::Anthropic),
+            _ => Err(format!(
+                "must be openai, openai-compatible or anthropic, got '{}'",
+                value
+            )),
+        }
+    }
+}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    // Backend answering the chat requests of this deployment
+    pub backend: ChatBackendKind,
+}
+/// The API a deployment uses to answer chat requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatBackendKind {
+    // OpenAI Assistants with threads, file search and the code interpreter
+    #[default]
+    Assistants,
+    // Chat Completions with the conversation kept in the log database
+    Completions,
+}
+impl FromStr for ChatBackendKind {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "assistants" => Ok(ChatBackendKind::Assistants),
+            "completions" => Ok(ChatBackendKind::Completions),
+            _ => Err(format!(
+                "must be assistants or completions, got '{}'",
+                value
+            )),
+        }
+    }
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompletionsConfig {
+    // Estimated tokens of the conversation history sent with a request,
+    // older messages are replaced by a summary
+    pub history_token_budget: u64,
+    // Maximum length of the summary of the older messages
+    pub summary_max_tokens: u64,
+    // Rounds of tool calls of a request, the model has to answer after the last one
+    pub max_tool_iterations: u64,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrievalConfig {
+    // Index the file search documents and the bikes locally, searched by the completions backend
+    pub enabled: bool,
+    // SQLite database of the index
+    pub database_url: String,
+    pub embedder: EmbedderKind,
+    // Embedding model of the OpenAI embedder
+    pub embedding_model: String,
+    // Number of chunks returned by a search
+    pub top_k: u64,
+    // Maximum characters of a chunk and characters repeated from the previous chunk
+    pub chunk_size: u64,
+    pub chunk_overlap: u64,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    // Answer repeated questions from the answer cache instead of creating a run
+    pub enabled: bool,
+    // Seconds a cached answer is used
+    pub ttl_secs: u64,
+    pub max_entries: u64,
+    // Minimum cosine similarity of the embeddings of two questions to share an answer,
+    // 0 matches only equal questions. The embedder of the retrieval settings is used.
+    pub similarity_threshold: f32,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConfig {
+    // Answer when a run of the assistant fails or times out, instead of the technical issue message
+    pub enabled: bool,
+    // Try Chat Completions with the instruction and the recent history before the canned answers
+    pub completions: bool,
+    // Seconds a request may take with the fallback, the fallback gets the time the run left
+    pub time_budget_secs: u64,
+    // JSON file with the canned answers of common intents
+    pub canned_answers_file: String,
+}
+/// The embedder computing the vectors of the retrieval index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbedderKind {
+    // Embeddings API of OpenAI
+    #[default]
+    OpenAi,
+    // Hashed word counts computed locally, works offline but only matches words
+    Hashing,
+}
+impl FromStr for EmbedderKind {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "openai" => Ok(EmbedderKind::OpenAi),
+            "hashing" => Ok(EmbedderKind::Hashing),
+            _ => Err(format!("must be openai or hashing, got '{}'", value)),
+        }
+    }
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AssistantConfig {
+    pub file_search_folder: String,
+    pub code_interpreter_folder: String,
+    pub instruction_file: String,
+    // Time a run has to finish before the user gets a timeout answer
+    pub run_timeout_secs: u64,
+    // Interval in which the resources and the assistant are recreated
+    pub rotation_interval_hours: u64,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivacyConfig {
+    // Redact personal data before it is logged or persisted
+    pub pii_redaction: bool,
+    // Hex encoded 32 byte AES key, personal data in the database is masked without it
+    pub pii_encryption_key: Option<String>,
+}
+// Retention windows of the logged data and the OpenAI threads
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionPolicy {
+    // Messages and tool calls older than this are deleted, 0 keeps them forever
+    pub messages_days: u64,
+    // Threads without activity for this long are deleted, 0 keeps them forever
+    pub threads_days: u64,
+    // Maximum number of rows deleted per statement, so tables are not locked for long
+    pub batch_size: u64,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsConfig {
+    // Interval in which the stats of the previous and the current day are aggregated
+    pub interval_minutes: u64,
+}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // Bearer token of the admin endpoints, they are disabled without it
+    pub api_token: Option<String>,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // Authenticate the chat requests with session tokens, the user ID of a request is trusted without it
+    pub enabled: bool,
+    // Secret shared with the buycycle platform, signs the session tokens and user IDs with HMAC-SHA256
+    pub secret: Option<String>,
+    // Anonymous guest sessions without the account tools
+    pub guest_sessions: bool,
+    // Seconds a guest session is valid
+    pub guest_session_ttl_secs: u64,
+    // Seconds a signed user ID is accepted after it was signed
+    pub signed_user_id_ttl_secs: u64,
+}
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Limit the chat requests, shared by the replicas through the log database
+    pub enabled: bool,
+    // Messages a user can send per minute, and at once after a pause
+    pub user_per_minute: u32,
+    pub user_burst: u32,
+    // Requests an IP address can send per minute, and at once after a pause
+    pub ip_per_minute: u32,
+    pub ip_burst: u32,
+    // Messages a user can send per day (UTC)
+    pub daily_runs_per_user: u32,
+    // Runs answered at the same time by all replicas
+    pub max_concurrent_runs: u32,
+    // Take the client IP address from the X-Forwarded-For header of a trusted proxy
+    pub trust_forwarded_for: bool,
+}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuycycleConfig {
+    // Proxy authorization of the buycycle API, used by the order tools
+    pub proxy_authorization: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 3000,
+            static_folder: "static".to_string(),
+        }
+    }
+}
+impl Default for OpenAiConfig {
+    fn default() -> Self {
+        OpenAiConfig {
+            api_key: String::new(),
+            model: "gpt-4o".to_string(),
+        }
+    }
+}
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            provider: ProviderKind::OpenAi,
+            base_url: None,
+            api_key: None,
+            model: None,
+            max_tokens: 1024,
+        }
+    }
+}
+impl Default for CompletionsConfig {
+    fn default() -> Self {
+        CompletionsConfig {
+            history_token_budget: 3000,
+            summary_max_tokens: 500,
+            max_tool_iterations: 5,
+        }
+    }
+}
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        RetrievalConfig {
+            enabled: false,
+            database_url: "sqlite:context/retrieval.db".to_string(),
+            embedder: EmbedderKind::OpenAi,
+            embedding_model: "text-embedding-3-small".to_string(),
+            top_k: 5,
+            chunk_size: 1000,
+            chunk_overlap: 200,
+        }
+    }
+}
+impl CacheConfig {
+    /// Whether similar questions are matched by their embeddings.
+    pub fn uses_similarity(&self) -> bool {
+        self.enabled && self.similarity_threshold > 0.0
+    }
+}
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            ttl_secs: 24 * 3600,
+            max_entries: 1000,
+            similarity_threshold: 0.0,
+        }
+    }
+}
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        FallbackConfig {
+            enabled: false,
+            completions: true,
+            time_budget_secs: 120,
+            canned_answers_file: "instruction/fallback_answers.json".to_string(),
+        }
+    }
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            enabled: false,
+            secret: None,
+            guest_sessions: true,
+            guest_session_ttl_secs: 30 * 24 * 3600,
+            signed_user_id_ttl_secs: 24 * 3600,
+        }
+    }
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            user_per_minute: 10,
+            user_burst: 5,
+            ip_per_minute: 30,
+            ip_burst: 20,
+            daily_runs_per_user: 200,
+            max_concurrent_runs: 50,
+            trust_forwarded_for: false,
+        }
+    }
+}
+impl Default for AssistantConfig {
+    fn default() -> Self {
+        AssistantConfig {
+            file_search_folder: "context/file_search".to_string(),
+            code_interpreter_folder: "context/code_interpreter".to_string(),
+            instruction_file: "instruction/instruction.txt".to_string(),
+            run_timeout_secs: 100,
+            rotation_interval_hours: 24,
+        }
+    }
+}
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        PrivacyConfig {
+            pii_redaction: true,
+            pii_encryption_key: None,
+        }
+    }
+}
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            messages_days: 90,
+            threads_days: 30,
+            batch_size: 1000,
+        }
+    }
+}
+impl Default for StatsConfig {
+    fn default() -> Self {
+        StatsConfig {
+            interval_minutes: 60,
+        }
+    }
+}
+
+/// All problems found while loading the configuration, reported together.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for error in &self.errors {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration from the file of CONFIG_FILE (default config.toml, optional
+    /// if CONFIG_FILE is not set) and overrides it with the environment variables.
+    pub fn load() -> Result<Config, ConfigError> {
+        let (file, env) = read_sources()?;
+        Config::from_sources(file.as_deref(), &env)
+    }
+    /// Loads only the URL of the log database from the same sources as `load`, for the
+    /// migrate subcommand. The other settings are not validated.
+    pub fn load_log_url() -> Result<String, ConfigError> {
+        let (file, env) = read_sources()?;
+        Config::log_url_from_sources(file.as_deref(), &env)
+    }
+    /// Parses the configuration file, applies the overrides of the environment and validates
+    /// the result, all problems are collected into a single error.
+    pub fn from_sources(
+        file: Option<&str>,
+        env: &HashMap<String, String>,
+    ) -> Result<Config, ConfigError> {
+        let mut config = parse_file(file)?;
+        let mut errors = Vec::new();
+        config.apply_env(env, &mut errors);
+        config.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+    /// Parses the configuration file and applies the overrides of the environment like
+    /// `from_sources`, but only the URL of the log database is validated and returned.
+    pub fn log_url_from_sources(
+        file: Option<&str>,
+        env: &HashMap<String, String>,
+    ) -> Result<String, ConfigError> {
+        let mut config = parse_file(file)?;
+        // Invalid values of the other settings do not matter here
+        config.apply_env(env, &mut Vec::new());
+        let mut errors = Vec::new();
+        config.validate_log_url(&mut errors);
+        if errors.is_empty() {
+            Ok(config.database.log_url)
+        } else {
+            Err(ConfigError { errors })
+        }
+    }
+    fn apply_env(&mut self, env: &HashMap<String, String>, errors: &mut Vec<String>) {
+        let string = |name: &str, target: &mut String| {
+            if let Some(value) = env.get(name) {
+                *target = value.clone();
+            }
+        };
+        let optional = |name: &str, target: &mut Option<String>| {
+            if let Some(value) = env.get(name) {
+                *target = Some(value.clone()).filter(|value| !value.is_empty());
+            }
+        };
+        string("HOST", &mut self.server.host);
+        parse(env, "PORT", &mut self.server.port, errors);
+        string("STATIC_FOLDER", &mut self.server.static_folder);
+        string("DATABASE_URL_BUYCYCLE", &mut self.database.buycycle_url);
+        string("DATABASE_URL_LOG", &mut self.database.log_url);
+        parse_bool(
+            env,
+            "RUN_MIGRATIONS",
+            &mut self.database.run_migrations,
+            errors,
+        );
+        string("OPENAI_API_KEY", &mut self.openai.api_key);
+        string("OPENAI_MODEL", &mut self.openai.model);
+        if let Some(value) = env.get("LLM_PROVIDER") {
+            match value.parse() {
+                Ok(provider) => self.llm.provider = provider,
+                Err(e) => errors.push(format!("LLM_PROVIDER {}", e)),
+            }
+        }
+        optional("LLM_BASE_URL", &mut self.llm.base_url);
+        optional("LLM_API_KEY", &mut self.llm.api_key);
+        optional("LLM_MODEL", &mut self.llm.model);
+        parse(env, "LLM_MAX_TOKENS", &mut self.llm.max_tokens, errors);
+        if let Some(value) = env.get("CHAT_BACKEND") {
+            match value.parse() {
+                Ok(backend) => self.chat.backend = backend,
+                Err(e) => errors.push(format!("CHAT_BACKEND {}", e)),
+            }
+        }
+        parse(
+            env,
+            "COMPLETIONS_HISTORY_TOKEN_BUDGET",
+            &mut self.completions.history_token_budget,
+            errors,
+        );
+        parse(
+            env,
+            "COMPLETIONS_SUMMARY_MAX_TOKENS",
+            &mut self.completions.summary_max_tokens,
+            errors,
+        );
+        parse(
+            env,
+            "COMPLETIONS_MAX_TOOL_ITERATIONS",
+            &mut self.completions.max_tool_iterations,
+            errors,
+        );
+        parse_bool(
+            env,
+            "RETRIEVAL_ENABLED",
+            &mut self.retrieval.enabled,
+            errors,
+        );
+        string("RETRIEVAL_DATABASE_URL", &mut self.retrieval.database_url);
+        if let Some(value) = env.get("RETRIEVAL_EMBEDDER") {
+            match value.parse() {
+                Ok(embedder) => self.retrieval.embedder = embedder,
+                Err(e) => errors.push(format!("RETRIEVAL_EMBEDDER {}", e)),
+            }
+        }
+        string(
+            "RETRIEVAL_EMBEDDING_MODEL",
+            &mut self.retrieval.embedding_model,
+        );
+        parse(env, "RETRIEVAL_TOP_K", &mut self.retrieval.top_k, errors);
+        parse(
+            env,
+            "RETRIEVAL_CHUNK_SIZE",
+            &mut self.retrieval.chunk_size,
+            errors,
+        );
+        parse(
+            env,
+            "RETRIEVAL_CHUNK_OVERLAP",
+            &mut self.retrieval.chunk_overlap,
+            errors,
+        );
+        parse_bool(env, "ANSWER_CACHE_ENABLED", &mut self.cache.enabled, errors);
+        parse(
+            env,
+            "ANSWER_CACHE_TTL_SECS",
+            &mut self.cache.ttl_secs,
+            errors,
+        );
+        parse(
+            env,
+            "ANSWER_CACHE_MAX_ENTRIES",
+            &mut self.cache.max_entries,
+            errors,
+        );
+        parse(
+            env,
+            "ANSWER_CACHE_SIMILARITY_THRESHOLD",
+            &mut self.cache.similarity_threshold,
+            errors,
+        );
+        parse_bool(env, "FALLBACK_ENABLED", &mut self.fallback.enabled, errors);
+        parse_bool(
+            env,
+            "FALLBACK_COMPLETIONS",
+            &mut self.fallback.completions,
+            errors,
+        );
+        parse(
+            env,
+            "FALLBACK_TIME_BUDGET_SECS",
+            &mut self.fallback.time_budget_secs,
+            errors,
+        );
+        string(
+            "FALLBACK_CANNED_ANSWERS_FILE",
+            &mut self.fallback.canned_answers_file,
+        );
+        string("FILE_SEARCH_FOLDER", &mut self.assistant.file_search_folder);
+        string(
+            "CODE_INTERPRETER_FOLDER",
+            &mut self.assistant.code_interpreter_folder,
+        );
+        string("INSTRUCTION_FILE", &mut self.assistant.instruction_file);
+        parse(
+            env,
+            "RUN_TIMEOUT_SECS",
+            &mut self.assistant.run_timeout_secs,
+            errors,
+        );
+        parse(
+            env,
+            "ROTATION_INTERVAL_HOURS",
+            &mut self.assistant.rotation_interval_hours,
+            errors,
+        );
+        parse_bool(
+            env,
+            "PII_REDACTION",
+            &mut self.privacy.pii_redaction,
+            errors,
+        );
+        optional("PII_ENCRYPTION_KEY", &mut self.privacy.pii_encryption_key);
+        parse(
+            env,
+            "RETENTION_MESSAGES_DAYS",
+            &mut self.retention.messages_days,
+            errors,
+        );
+        parse(
+            env,
+            "RETENTION_THREADS_DAYS",
+            &mut self.retention.threads_days,
+            errors,
+        );
+        parse(
+            env,
+            "RETENTION_BATCH_SIZE",
+            &mut self.retention.batch_size,
+            errors,
+        );
+        parse(
+            env,
+            "STATS_INTERVAL_MINUTES",
+            &mut self.stats.interval_minutes,
+            errors,
+        );
+        optional("ADMIN_API_TOKEN", &mut self.admin.api_token);
+        parse_bool(env, "AUTH_ENABLED", &mut self.auth.enabled, errors);
+        optional("AUTH_SECRET", &mut self.auth.secret);
+        parse_bool(
+            env,
+            "AUTH_GUEST_SESSIONS",
+            &mut self.auth.guest_sessions,
+            errors,
+        );
+        parse(
+            env,
+            "AUTH_GUEST_SESSION_TTL_SECS",
+            &mut self.auth.guest_session_ttl_secs,
+            errors,
+        );
+        parse(
+            env,
+            "AUTH_SIGNED_USER_ID_TTL_SECS",
+            &mut self.auth.signed_user_id_ttl_secs,
+            errors,
+        );
+        parse_bool(
+            env,
+            "RATE_LIMIT_ENABLED",
+            &mut self.rate_limit.enabled,
+            errors,
+        );
+        parse(
+            env,
+            "RATE_LIMIT_USER_PER_MINUTE",
+            &mut self.rate_limit.user_per_minute,
+            errors,
+        );
+        parse(
+            env,
+            "RATE_LIMIT_USER_BURST",
+            &mut self.rate_limit.user_burst,
+            errors,
+        );
+        parse(
+            env,
+            "RATE_LIMIT_IP_PER_MINUTE",
+            &mut self.rate_limit.ip_per_minute,
+            errors,
+        );
+        parse(
+            env,
+            "RATE_LIMIT_IP_BURST",
+            &mut self.rate_limit.ip_burst,
+            errors,
+        );
+        parse(
+            env,
+            "RATE_LIMIT_DAILY_RUNS",
+            &mut self.rate_limit.daily_runs_per_user,
+            errors,
+        );
+        parse(
+            env,
+            "RATE_LIMIT_MAX_CONCURRENT_RUNS",
+            &mut self.rate_limit.max_concurrent_runs,
+            errors,
+        );
+        parse_bool(
+            env,
+            "RATE_LIMIT_TRUST_FORWARDED_FOR",
+            &mut self.rate_limit.trust_forwarded_for,
+            errors,
+        );
+        optional(
+            "X_PROXY_AUTHORIZATION",
+            &mut self.buycycle.proxy_authorization,
+        );
+    }
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.database.buycycle_url.is_empty() {
+            errors.push("database.buycycle_url (DATABASE_URL_BUYCYCLE) is required".to_string());
+        } else if !self.database.buycycle_url.starts_with("mysql:") {
+            errors.push(
+                "database.buycycle_url (DATABASE_URL_BUYCYCLE) must be a mysql:// URL".to_string(),
+            );
+        }
+        self.validate_log_url(errors);
+        // A deployment on another provider needs no OpenAI key, unless OpenAI embeddings are used
+        let embeds = self.retrieval.enabled || self.cache.uses_similarity();
+        let uses_openai = self.chat.backend == ChatBackendKind::Assistants
+            || self.llm.provider == ProviderKind::OpenAi
+            || (embeds && self.retrieval.embedder == EmbedderKind::OpenAi);
+        if uses_openai && self.openai.api_key.is_empty() {
+            errors.push("openai.api_key (OPENAI_API_KEY) is required".to_string());
+        }
+        self.validate_llm(errors);
+        if self.cache.enabled {
+            self.validate_cache(errors);
+        }
+        if self.fallback.enabled {
+            self.validate_fallback(errors);
+        }
+        if self.auth.enabled {
+            self.validate_auth(errors);
+        }
+        if self.rate_limit.enabled {
+            self.validate_rate_limit(errors);
+        }
+        if self.openai.model.is_empty() {
+            errors.push("openai.model (OPENAI_MODEL) must not be empty".to_string());
+        }
+        if self.server.port == 0 {
+            errors.push("server.port (PORT) must be greater than 0".to_string());
+        }
+        // The code interpreter folder is created when the bikes are exported
+        for (name, folder) in [
+            (
+                "server.static_folder (STATIC_FOLDER)",
+                &self.server.static_folder,
+            ),
+            (
+                "assistant.file_search_folder (FILE_SEARCH_FOLDER)",
+                &self.assistant.file_search_folder,
+            ),
+        ] {
+            if !Path::new(folder).is_dir() {
+                errors.push(format!("{}: folder {} does not exist", name, folder));
+            }
+        }
+        if !Path::new(&self.assistant.instruction_file).is_file() {
+            errors.push(format!(
+                "assistant.instruction_file (INSTRUCTION_FILE): file {} does not exist",
+                self.assistant.instruction_file
+            ));
+        }
+        for (name, value) in [
+            (
+                "assistant.run_timeout_secs (RUN_TIMEOUT_SECS)",
+                self.assistant.run_timeout_secs,
+            ),
+            (
+                "assistant.rotation_interval_hours (ROTATION_INTERVAL_HOURS)",
+                self.assistant.rotation_interval_hours,
+            ),
+            (
+                "completions.history_token_budget (COMPLETIONS_HISTORY_TOKEN_BUDGET)",
+                self.completions.history_token_budget,
+            ),
+            (
+                "completions.summary_max_tokens (COMPLETIONS_SUMMARY_MAX_TOKENS)",
+                self.completions.summary_max_tokens,
+            ),
+            (
+                "completions.max_tool_iterations (COMPLETIONS_MAX_TOOL_ITERATIONS)",
+                self.completions.max_tool_iterations,
+            ),
+            (
+                "retention.batch_size (RETENTION_BATCH_SIZE)",
+                self.retention.batch_size,
+            ),
+            (
+                "stats.interval_minutes (STATS_INTERVAL_MINUTES)",
+                self.stats.interval_minutes,
+            ),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+        if self.retrieval.enabled {
+            self.validate_retrieval(errors);
+        }
+        if let Some(key) = &self.privacy.pii_encryption_key {
+            match decode_hex(key) {
+                Ok(bytes) if bytes.len() == 32 => {}
+                Ok(_) => errors.push(
+                    "privacy.pii_encryption_key (PII_ENCRYPTION_KEY) must be 32 bytes (64 hex characters)"
+                        .to_string(),
+                ),
+                Err(e) => errors.push(format!(
+                    "privacy.pii_encryption_key (PII_ENCRYPTION_KEY): {}",
+                    e
+                )),
+            }
+        }
+    }
+    fn validate_log_url(&self, errors: &mut Vec<String>) {
+        if self.database.log_url.is_empty() {
+            errors.push("database.log_url (DATABASE_URL_LOG) is required".to_string());
+        } else if !self.database.log_url.starts_with("mysql:")
+            && !self.database.log_url.starts_with("sqlite:")
+        {
+            errors.push(
+                "database.log_url (DATABASE_URL_LOG) must be a mysql:// or sqlite: URL".to_string(),
+            );
+        }
+    }
+    fn validate_llm(&self, errors: &mut Vec<String>) {
+        let llm = &self.llm;
+        if llm.provider == ProviderKind::OpenAi {
+            return;
+        }
+        // The assistants backend only sends completion requests for the fallback
+        let completes = self.chat.backend == ChatBackendKind::Completions
+            || (self.fallback.enabled && self.fallback.completions);
+        if !completes {
+            errors.push(
+                "llm.provider (LLM_PROVIDER) other than openai requires the completions backend or fallback"
+                    .to_string(),
+            );
+        }
+        if llm.model.is_none() {
+            errors.push("llm.model (LLM_MODEL) is required for this provider".to_string());
+        }
+        match llm.provider {
+            ProviderKind::OpenAiCompatible if llm.base_url.is_none() => errors.push(
+                "llm.base_url (LLM_BASE_URL) is required for the openai-compatible provider"
+                    .to_string(),
+            ),
+            ProviderKind::Anthropic if llm.api_key.is_none() => errors.push(
+                "llm.api_key (LLM_API_KEY) is required for the anthropic provider".to_string(),
+            ),
+            _ => {}
+        }
+        if llm.max_tokens == 0 {
+            errors.push("llm.max_tokens (LLM_MAX_TOKENS) must be greater than 0".to_string());
+        }
+    }
+    fn validate_cache(&self, errors: &mut Vec<String>) {
+        let cache = &self.cache;
+        if cache.ttl_secs == 0 {
+            errors
+                .push("cache.ttl_secs (ANSWER_CACHE_TTL_SECS) must be greater than 0".to_string());
+        }
+        if cache.max_entries == 0 {
+            errors.push(
+                "cache.max_entries (ANSWER_CACHE_MAX_ENTRIES) must be greater than 0".to_string(),
+            );
+        }
+        if !(0.0..=1.0).contains(&cache.similarity_threshold) {
+            errors.push(
+                "cache.similarity_threshold (ANSWER_CACHE_SIMILARITY_THRESHOLD) must be between 0 and 1"
+                    .to_string(),
+            );
+        }
+    }
+    fn validate_fallback(&self, errors: &mut Vec<String>) {
+        let fallback = &self.fallback;
+        if fallback.time_budget_secs <= self.assistant.run_timeout_secs {
+            errors.push(
+                "fallback.time_budget_secs (FALLBACK_TIME_BUDGET_SECS) must be greater than assistant.run_timeout_secs"
+                    .to_string(),
+            );
+        }
+        if !Path::new(&fallback.canned_answers_file).is_file() {
+            errors.push(format!(
+                "fallback.canned_answers_file (FALLBACK_CANNED_ANSWERS_FILE): file {} does not exist",
+                fallback.canned_answers_file
+            ));
+        }
+    }
+    fn validate_auth(&self, errors: &mut Vec<String>) {
+        let auth = &self.auth;
+        match &auth.secret {
+            None => errors.push("auth.secret (AUTH_SECRET) is required".to_string()),
+            Some(secret) if secret.len() < MIN_AUTH_SECRET_LENGTH => errors.push(format!(
+                "auth.secret (AUTH_SECRET) must have at least {} characters",
+                MIN_AUTH_SECRET_LENGTH
+            )),
+            Some(_) => {}
+        }
+        if auth.guest_sessions && auth.guest_session_ttl_secs == 0 {
+            errors.push(
+                "auth.guest_session_ttl_secs (AUTH_GUEST_SESSION_TTL_SECS) must be greater than 0"
+                    .to_string(),
+            );
+        }
+        if auth.signed_user_id_ttl_secs == 0 {
+            errors.push(
+                "auth.signed_user_id_ttl_secs (AUTH_SIGNED_USER_ID_TTL_SECS) must be greater than 0"
+                    .to_string(),
+            );
+        }
+    }
+    fn validate_rate_limit(&self, errors: &mut Vec<String>) {
+        let rate_limit = &self.rate_limit;
+        for (name, value) in [
+            (
+                "rate_limit.user_per_minute (RATE_LIMIT_USER_PER_MINUTE)",
+                rate_limit.user_per_minute,
+            ),
+            (
+                "rate_limit.user_burst (RATE_LIMIT_USER_BURST)",
+                rate_limit.user_burst,
+            ),
+            (
+                "rate_limit.ip_per_minute (RATE_LIMIT_IP_PER_MINUTE)",
+                rate_limit.ip_per_minute,
+            ),
+            (
+                "rate_limit.ip_burst (RATE_LIMIT_IP_BURST)",
+                rate_limit.ip_burst,
+            ),
+            (
+                "rate_limit.daily_runs_per_user (RATE_LIMIT_DAILY_RUNS)",
+                rate_limit.daily_runs_per_user,
+            ),
+            (
+                "rate_limit.max_concurrent_runs (RATE_LIMIT_MAX_CONCURRENT_RUNS)",
+                rate_limit.max_concurrent_runs,
+            ),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+    }
+    fn validate_retrieval(&self, errors: &mut Vec<String>) {
+        let retrieval = &self.retrieval;
+        if !retrieval.database_url.starts_with("sqlite:") {
+            errors.push(
+                "retrieval.database_url (RETRIEVAL_DATABASE_URL) must be a sqlite: URL".to_string(),
+            );
+        }
+        if retrieval.embedder == EmbedderKind::OpenAi && retrieval.embedding_model.is_empty() {
+            errors.push(
+                "retrieval.embedding_model (RETRIEVAL_EMBEDDING_MODEL) must not be empty"
+                    .to_string(),
+            );
+        }
+        for (name, value) in [
+            ("retrieval.top_k (RETRIEVAL_TOP_K)", retrieval.top_k),
+            (
+                "retrieval.chunk_size (RETRIEVAL_CHUNK_SIZE)",
+                retrieval.chunk_size,
+            ),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+        if retrieval.chunk_overlap >= retrieval.chunk_size {
+            errors.push(
+                "retrieval.chunk_overlap (RETRIEVAL_CHUNK_OVERLAP) must be less than the chunk size"
+                    .to_string(),
+            );
+        }
+    }
+}
+
+// Reads the configuration file of CONFIG_FILE and the environment variables
+fn read_sources() -> Result<(Option<String>, HashMap<String, String>), ConfigError> {
+    let env: HashMap<String, String> = std::env::vars().collect();
+    let (path, required) = match env.get("CONFIG_FILE") {
+        Some(path) => (path.clone(), true),
+        None => (DEFAULT_CONFIG_FILE.to_string(), false),
+    };
+    let file = if required || Path::new(&path).exists() {
+        Some(fs::read_to_string(&path).map_err(|e| ConfigError {
+            errors: vec![format!("Failed to read config file {}: {}", path, e)],
+        })?)
+    } else {
+        None
+    };
+    Ok((file, env))
+}
+
+// Parses the configuration file, the defaults are used without a file
+fn parse_file(file: Option<&str>) -> Result<Config, ConfigError> {
+    match file {
+        Some(contents) => toml::from_str(contents).map_err(|e| ConfigError {
+            errors: vec![format!("Failed to parse config file: {}", e.message())],
+        }),
+        None => Ok(Config::default()),
+    }
+}
+
+// Overrides a setting with a parsed environment variable, a malformed value is reported
+fn parse<T: FromStr>(
+    env: &HashMap<String, String>,
+    name: &str,
+    target: &mut T,
+    errors: &mut Vec<String>,
+) {
+    if let Some(value) = env.get(name) {
+        match value.parse() {
+            Ok(parsed) => *target = parsed,
+            Err(_) => errors.push(format!(
+                "{} must be a non-negative number, got '{}'",
+                name, value
+            )),
+        }
+    }
+}
+
+// Overrides a flag with an environment variable, accepting true/false and on/off
+fn parse_bool(
+    env: &HashMap<String, String>,
+    name: &str,
+    target: &mut bool,
+    errors: &mut Vec<String>,
+) {
+    if let Some(value) = env.get(name) {
+        match value.to_lowercase().as_str() {
+            "true" | "on" | "1" => *target = true,
+            "false" | "off" | "0" => *target = false,
+            _ => errors.push(format!(
+                "{} must be true, false, on or off, got '{}'",
+                name, value
+            )),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::assistant::{AssistantChatInput, AssistantError, RunRecord, SimplifiedMessage, LOG};
+use crate::cache::normalize_question;
+use crate::completion::CompletionsBackend;
+use crate::config::FallbackConfig;
+use chrono::Utc;
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::{Duration, Instant};
+
+// Language of the canned answers used if there is none in the language of the user
+const DEFAULT_LANGUAGE: &str = "en";
+// Below this time the completion request is not sent, the canned answers are used right away
+const MIN_COMPLETIONS_TIME: Duration = Duration::from_secs(3);
+
+/// A canned answer of the fallback, given if the message contains one of the keywords.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CannedAnswer {
+    pub intent: String,
+    // Words or phrases of the normalized message, in any language
+    pub keywords: Vec<String>,
+    // Answer per language of the locale
+    pub answers: BTreeMap<String, String>,
+}
+
+/// Answers a message when a run of the assistant fails or does not finish in time:
+/// with Chat Completions on the instruction and the recent history of the chat if there
+/// is time left, else with the canned answer of the intent of the message. The reply is
+/// logged as a fallback message.
+pub struct Fallback {
+    config: FallbackConfig,
+    completions: Option<CompletionsBackend>,
+    canned_answers: Vec<CannedAnswer>,
+    log: LOG,
+}
+impl Fallback {
+    /// Creates the fallback, the completions backend is tried before the canned answers.
+    pub fn new(
+        config: FallbackConfig,
+        completions: Option<CompletionsBackend>,
+        log: LOG,
+    ) -> Result<Self, AssistantError> {
+        let canned_answers = if config.enabled {
+            load_canned_answers(&config.canned_answers_file)?
+        } else {
+            Vec::new()
+        };
+        Ok(Fallback {
+            config,
+            completions,
+            canned_answers,
+            log,
+        })
+    }
+    /// Whether the fallback is enabled, the input of a request is only kept for it then.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    /// Whether the result of a request is answered by the fallback: the OpenAI API failed
+    /// or the run did not complete. Invalid requests are not answered.
+    pub fn applies(
+        &self,
+        record: &RunRecord,
+        result: &Result<Vec<SimplifiedMessage>, AssistantError>,
+    ) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        match result {
+            Err(e) => matches!(e, AssistantError::OpenAIError(_)),
+            Ok(_) => ["timeout", "failed", "cancelled", "expired", "incomplete"]
+                .contains(&record.status.as_str()),
+        }
+    }
+    /// Answers the message within the time left of the request started at start_time.
+    /// Returns None if there is no answer, then the result of the run is kept.
+    pub async fn answer(
+        &self,
+        input: &AssistantChatInput,
+        record: &mut RunRecord,
+        start_time: Instant,
+    ) -> Option<Vec<SimplifiedMessage>> {
+        let budget = Duration::from_secs(self.config.time_budget_secs);
+        let remaining = budget.saturating_sub(start_time.elapsed());
+        let mut reply = None;
+        if let Some(completions) = &self.completions {
+            if remaining >= MIN_COMPLETIONS_TIME {
+                let chat_id = record.chat_id.clone();
+                let completed = tokio::time::timeout(
+                    remaining,
+                    completions.fallback_reply(input, chat_id.as_deref(), record),
+                )
+                .await;
+                match completed {
+                    Ok(Ok(text)) if !text.trim().is_empty() => reply = Some(text),
+                    Ok(Ok(_)) => error!("Empty reply of the completions fallback"),
+                    Ok(Err(e)) => error!("Completions fallback failed: {:?}", e),
+                    Err(_) => error!("Completions fallback did not finish in time"),
+                }
+            }
+        }
+        let reply = match reply {
+            Some(reply) => reply,
+            None => self.canned_answer(input)?,
+        };
+        info!(
+            "Message answered by the fallback after the run ended with status {}",
+            record.status
+        );
+        if let Some(chat_id) = &record.chat_id {
+            if let Err(e) = self.log.save_fallback_message_to_db(chat_id, &reply).await {
+                error!("Failed to save the fallback reply: {:?}", e);
+            }
+        }
+        Some(vec![SimplifiedMessage {
+            id: None,
+            created_at: Utc::now().timestamp(),
+            role: "assistant".to_string(),
+            text: reply,
+            citations: Vec::new(),
+            image_file_ids: Vec::new(),
+        }])
+    }
+    /// The canned answer of the intent with the most keywords in the message,
+    /// in the language of the locale or else in English.
+    pub fn canned_answer(&self, input: &AssistantChatInput) -> Option<String> {
+        let message = format!(" {} ", normalize_question(&input.form.message));
+        let context = input.form.context();
+        let language = match context.validate() {
+            Ok(()) => context.language(),
+            Err(_) => None,
+        }
+        .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+        let mut best: Option<(usize, &CannedAnswer)> = None;
+        for canned in &self.canned_answers {
+            let matches = canned
+                .keywords
+                .iter()
+                .filter(|keyword| message.contains(&format!(" {} ", normalize_question(keyword))))
+                .count();
+            if matches > 0 && best.is_none_or(|(most, _)| matches > most) {
+                best = Some((matches, canned));
+            }
+        }
+        let (_, canned) = best?;
+        info!("Canned answer of the intent {}", canned.intent);
+        canned
+            .answers
+            .get(&language)
+            .or_else(|| canned.answers.get(DEFAULT_LANGUAGE))
+            .cloned()
+    }
+}
+
+fn load_canned_answers(path: &str) -> Result<Vec<CannedAnswer>, AssistantError> {
+    let content = fs::read_to_string(path).map_err(|e| {
+        AssistantError::DatabaseError(format!("Failed to read canned answers file: {}", e))
+    })?;
+    serde_json::from_str(&content).map_err(|e| {
+        AssistantError::InvalidInput(format!("Invalid canned answers file {}: {}", path, e))
+    })
+}
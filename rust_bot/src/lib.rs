@@ -1 +1,12 @@
 pub mod assistant;
+pub mod auth;
+pub mod backend;
+pub mod cache;
+pub mod completion;
+pub mod config;
+pub mod fallback;
+pub mod limits;
+pub mod provider;
+pub mod redaction;
+pub mod retrieval;
+pub mod store;
@@ -0,0 +1,218 @@
+use crate::assistant::AssistantError;
+use crate::config::{Config, RateLimitConfig};
+use crate::store::{user_bucket, ConversationStore};
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use chrono::Utc;
+use openssl::rand::rand_bytes;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+// Seconds a run slot is leased beyond the longest run, a slot of a replica that stopped
+// without releasing it is free again after the lease
+const LEASE_MARGIN_SECS: u64 = 60;
+
+/// Limits the chat requests with token buckets per user and per IP address, a daily
+/// quota of runs per user and a cap on the runs answered at the same time. The state is
+/// kept in the log database so all replicas enforce the same limits, a failing database
+/// rejects the requests.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    store: Arc<dyn ConversationStore>,
+    lease_secs: u64,
+}
+impl RateLimiter {
+    /// Creates the limiter, a run slot is leased for the longest a run can take.
+    pub fn from_config(config: &Config, store: Arc<dyn ConversationStore>) -> Self {
+        let longest_run_secs = config
+            .assistant
+            .run_timeout_secs
+            .max(config.fallback.time_budget_secs);
+        RateLimiter {
+            config: config.rate_limit.clone(),
+            store,
+            lease_secs: longest_run_secs + LEASE_MARGIN_SECS,
+        }
+    }
+    /// Whether the requests are limited.
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+    /// Admits a run of a user: leases a run slot, takes a token of the user and counts the
+    /// run in the daily quota. The slot is leased first and released again if the user is
+    /// limited, so a busy assistant does not use up the tokens and quota of the user, and
+    /// rejected runs are not counted. The slot is held until the permit is dropped, None if
+    /// the limits are disabled.
+    pub async fn admit(&self, user_id: &str) -> Result<Option<RunPermit>, AssistantError> {
+        if !self.config.enabled {
+            return Ok(None);
+        }
+        let lease_id = new_lease_id()?;
+        let now = Utc::now();
+        let expires_at = now + std::time::Duration::from_secs(self.lease_secs);
+        if !self
+            .store
+            .lease_run_slot(
+                self.config.max_concurrent_runs as u64,
+                &lease_id,
+                expires_at,
+                now,
+            )
+            .await?
+        {
+            return Err(AssistantError::TooManyRequests(
+                "The assistant is busy, please try again in a moment".to_string(),
+            ));
+        }
+        // Released when the permit is dropped, also if the run is rejected below
+        let permit = RunPermit {
+            store: Arc::clone(&self.store),
+            lease_id,
+        };
+        if !self
+            .take_token(
+                &user_bucket(user_id),
+                self.config.user_burst,
+                self.config.user_per_minute,
+            )
+            .await?
+        {
+            return Err(AssistantError::TooManyRequests(
+                "Too many messages, please wait a moment".to_string(),
+            ));
+        }
+        if !self
+            .store
+            .take_daily_run(
+                user_id,
+                Utc::now().date_naive(),
+                self.config.daily_runs_per_user as i64,
+            )
+            .await?
+        {
+            return Err(AssistantError::TooManyRequests(
+                "Daily message limit reached, please try again tomorrow".to_string(),
+            ));
+        }
+        Ok(Some(permit))
+    }
+    /// Takes a token of an IP address, requests without an address are not limited by it.
+    pub async fn check_ip(&self, ip: Option<IpAddr>) -> Result<(), AssistantError> {
+        let Some(ip) = ip.filter(|_| self.config.enabled) else {
+            return Ok(());
+        };
+        if self
+            .take_token(
+                &format!("ip:{}", ip),
+                self.config.ip_burst,
+                self.config.ip_per_minute,
+            )
+            .await?
+        {
+            Ok(())
+        } else {
+            Err(AssistantError::TooManyRequests(
+                "Too many requests from this IP address, please try again later".to_string(),
+            ))
+        }
+    }
+    async fn take_token(
+        &self,
+        bucket: &str,
+        burst: u32,
+        per_minute: u32,
+    ) -> Result<bool, AssistantError> {
+        self.store
+            .take_rate_limit_token(bucket, burst as f64, per_minute as f64 / 60.0, Utc::now())
+            .await
+    }
+}
+
+/// Extracts the limiter of a request and checks the limit of the IP address of the client,
+/// before the body is read. The runs are admitted once the user of the request is known.
+pub struct Admission {
+    pub limiter: Arc<RateLimiter>,
+    pub ip: Option<IpAddr>,
+}
+#[async_trait]
+impl<S> FromRequestParts<S> for Admission
+where
+    S: Send + Sync,
+{
+    type Rejection = AssistantError;
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let limiter = parts
+            .extensions
+            .get::<Arc<RateLimiter>>()
+            .cloned()
+            .ok_or_else(|| {
+                AssistantError::DatabaseError("Rate limits are not configured".to_string())
+            })?;
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(peer)| *peer);
+        let ip = client_ip(&parts.headers, peer, limiter.config.trust_forwarded_for);
+        limiter.check_ip(ip).await?;
+        Ok(Admission { limiter, ip })
+    }
+}
+impl Admission {
+    /// Admits a run of the user, see [`RateLimiter::admit`].
+    pub async fn admit(&self, user_id: &str) -> Result<Option<RunPermit>, AssistantError> {
+        self.limiter.admit(user_id).await
+    }
+}
+
+/// A leased run slot, released when the permit is dropped at the end of the run.
+pub struct RunPermit {
+    store: Arc<dyn ConversationStore>,
+    lease_id: String,
+}
+impl Drop for RunPermit {
+    fn drop(&mut self) {
+        // Without a runtime the lease expires on its own
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let store = Arc::clone(&self.store);
+        let lease_id = std::mem::take(&mut self.lease_id);
+        runtime.spawn(async move {
+            if let Err(e) = store.release_run_slot(&lease_id).await {
+                log::error!("Failed to release run slot {}: {:?}", lease_id, e);
+            }
+        });
+    }
+}
+
+/// The IP address of the client: the peer of the connection, or the address the trusted
+/// proxy appended last to X-Forwarded-For, the entries before it are set by the client.
+pub fn client_ip(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_forwarded_for: bool,
+) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|address| address.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    peer.map(|peer| peer.ip())
+}
+
+fn new_lease_id() -> Result<String, AssistantError> {
+    let mut bytes = [0u8; 16];
+    rand_bytes(&mut bytes).map_err(|e| {
+        AssistantError::DatabaseError(format!("Failed to create run slot lease: {}", e))
+    })?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
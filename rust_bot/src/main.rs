@@ -1,128 +1,435 @@
-mod assistant;
-use assistant::{assistant_chat_handler_form, create_assistant, create_ressources, DB};
 use axum::{
-    extract::Extension,
+    extract::{DefaultBodyLimit, Extension},
     routing::{get, get_service, post},
     Router,
 };
 use chrono::prelude::*;
 use dotenv::dotenv;
+use rust_bot::assistant::{
+    aggregate_daily_stats, apply_retention, assistant_chat_handler_form,
+    assistant_chat_stream_handler, assistant_history_handler, assistant_image_handler,
+    chat_handler, create_assistant, create_ressources, daily_stats_export_handler,
+    guest_session_handler, message_feedback_handler, negative_feedback_export_handler,
+    user_data_erasure_handler, user_data_export_handler, AssistantsBackend, FileInfo, Ressources,
+    DB, LOG, MAX_IMAGE_SIZE,
+};
+use rust_bot::auth::Authenticator;
+use rust_bot::backend::ChatBackend;
+use rust_bot::cache::AnswerCache;
+use rust_bot::completion::CompletionsBackend;
+use rust_bot::config::{ChatBackendKind, Config};
+use rust_bot::fallback::Fallback;
+use rust_bot::limits::RateLimiter;
+use rust_bot::provider;
+use rust_bot::redaction::Redactor;
+use rust_bot::retrieval::{self, RetrievalIndex};
+use rust_bot::store::{self, ConversationStore};
 use sqlx::MySqlPool;
+use std::env;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::time::{sleep, Duration};
 use tower_http::services::ServeDir;
-use std::env;
 // Define the health check handler
 async fn health_check() -> &'static str {
     "OK"
 }
-// Define a function to create the Axum app with the database pool and assistant.
-async fn app(
+// Opens the retrieval index, builds it and rebuilds it in the rotation interval,
+// so it follows the bikes and the file search documents like the assistant does.
+// Cached answers are invalidated with every rebuild.
+async fn retrieval_index(
+    config: Arc<Config>,
     db_pool_buycycle: MySqlPool,
-    db_pool_log: MySqlPool,
-    assistant_id: Arc<RwLock<String>>,
-) -> Router {
+    cache: Arc<AnswerCache>,
+) -> RetrievalIndex {
+    let embedder = retrieval::embedder(&config.retrieval, &config.openai.api_key);
+    let index = match RetrievalIndex::connect(&config.retrieval, embedder).await {
+        Ok(index) => index,
+        Err(e) => {
+            log::error!("Failed to open the retrieval index: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    rebuild_retrieval_index(&index, &config, &db_pool_buycycle).await;
+    tokio::spawn({
+        let index = index.clone();
+        async move {
+            let interval = Duration::from_secs(config.assistant.rotation_interval_hours * 3600);
+            loop {
+                sleep(interval).await;
+                rebuild_retrieval_index(&index, &config, &db_pool_buycycle).await;
+                cache.invalidate().await;
+            }
+        }
+    });
+    index
+}
+// Exports the bikes and indexes them with the file search documents,
+// the previous index is kept if the rebuild fails
+async fn rebuild_retrieval_index(index: &RetrievalIndex, config: &Config, db_pool: &MySqlPool) {
+    let assistant = &config.assistant;
+    let ressources = Ressources::new(
+        db_pool.clone(),
+        config.openai.api_key.clone(),
+        assistant.file_search_folder.clone(),
+        assistant.code_interpreter_folder.clone(),
+        assistant.instruction_file.clone(),
+    );
+    if let Err(e) = ressources.bikes_db().await {
+        log::error!(
+            "Failed to export the bikes for the retrieval index: {:?}",
+            e
+        );
+    }
+    let folders = [
+        assistant.file_search_folder.as_str(),
+        assistant.code_interpreter_folder.as_str(),
+    ];
+    let result = match retrieval::load_documents(&folders) {
+        Ok(documents) => index.rebuild(&documents).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        log::error!("Failed to rebuild the retrieval index: {:?}", e);
+    }
+}
+// The shared state of the handlers, every field is added to the app as an extension layer
+struct AppState {
+    config: Arc<Config>,
+    db_pool_buycycle: MySqlPool,
+    store: Arc<dyn ConversationStore>,
+    backend: Arc<dyn ChatBackend>,
+    cache: Arc<AnswerCache>,
+    fallback: Arc<Fallback>,
+    citation_files: Arc<RwLock<Vec<FileInfo>>>,
+    redactor: Arc<Redactor>,
+    auth: Arc<Authenticator>,
+    limiter: Arc<RateLimiter>,
+}
+// Define a function to create the Axum app with the database pool and assistant.
+async fn app(state: AppState) -> Router {
     Router::new()
         .route("/health", get(health_check)) // Health check route
-        .route("/assistant", post(assistant_chat_handler_form)) // Existing route
+        .route(
+            "/v1/chat",
+            post(chat_handler).layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE + 64 * 1024)),
+        ) // JSON or form body, replies with JSON or with HTML for htmx
+        .route(
+            "/v1/chat/stream",
+            post(assistant_chat_stream_handler)
+                .layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE + 64 * 1024)),
+        ) // Same as /v1/chat, the reply is streamed as server-sent events
+        .route("/v1/history", get(assistant_history_handler)) // Conversation history of a user
+        .route("/v1/sessions/guest", post(guest_session_handler)) // Anonymous guest session
+        .route("/v1/messages/:id/feedback", post(message_feedback_handler)) // Feedback on answers
+        .route(
+            "/assistant",
+            post(assistant_chat_handler_form)
+                .layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE + 64 * 1024)),
+        ) // Compatibility alias of /v1/chat, always replies with JSON
+        .route(
+            "/assistant/stream",
+            post(assistant_chat_stream_handler)
+                .layer(DefaultBodyLimit::max(MAX_IMAGE_SIZE + 64 * 1024)),
+        ) // Same as /assistant, the reply is streamed as server-sent events
+        .route("/history", get(assistant_history_handler)) // Alias of /v1/history
+        .route("/images/:file_id", get(assistant_image_handler)) // Images uploaded by users
+        .route("/messages/:id/feedback", post(message_feedback_handler)) // Alias of the v1 route
+        .route(
+            "/admin/feedback/negative",
+            get(negative_feedback_export_handler),
+        ) // Export of negative feedback, requires the admin token
+        .route(
+            "/admin/users/:user_id",
+            get(user_data_export_handler).delete(user_data_erasure_handler),
+        ) // Export and erasure of the data of a user, requires the admin token
+        .route("/admin/stats", get(daily_stats_export_handler)) // Daily stats as JSON or CSV
         .nest_service(
             "/", // Serve static files at the root of the domain
-            get_service(ServeDir::new("static")),
+            get_service(ServeDir::new(&state.config.server.static_folder)),
         )
-        .layer(Extension(db_pool_buycycle))
-        .layer(Extension(db_pool_log))
-        .layer(Extension(assistant_id)) // Add the assistant ID as a layer
+        .layer(Extension(state.db_pool_buycycle))
+        .layer(Extension(state.store)) // Add the conversation log as a layer
+        .layer(Extension(state.backend)) // Add the chat backend of the deployment as a layer
+        .layer(Extension(state.cache)) // Add the answer cache as a layer
+        .layer(Extension(state.fallback)) // Add the fallback of failed runs as a layer
+        .layer(Extension(state.citation_files)) // Add the files the assistant can cite as a layer
+        .layer(Extension(state.redactor)) // Add the redaction of personal data as a layer
+        .layer(Extension(state.auth)) // Add the authentication of the sessions as a layer
+        .layer(Extension(state.limiter)) // Add the rate limits of the chat requests as a layer
+        .layer(Extension(state.config)) // Add the configuration as a layer
+}
+// Connects to the conversation log, MySQL or SQLite depending on the URL scheme
+async fn connect_log_store(log_url: &str) -> Arc<dyn ConversationStore> {
+    match store::connect(log_url).await {
+        Ok(store) => store,
+        Err(e) => {
+            log::error!("Failed to connect to the log database: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+// Applies the pending migrations of the log database
+async fn apply_migrations(store: &dyn ConversationStore) {
+    match store.migrate().await {
+        Ok(()) => log::info!("Migrations of the log database applied"),
+        Err(e) => {
+            log::error!("Failed to apply migrations: {:?}", e);
+            std::process::exit(1);
+        }
+    }
 }
 #[tokio::main]
 async fn main() {
     env_logger::init();
     dotenv().ok();
+    // The migrate subcommand only needs the log database, the rest is not configured then
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        let log_url = match Config::load_log_url() {
+            Ok(log_url) => log_url,
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let store = connect_log_store(&log_url).await;
+        apply_migrations(store.as_ref()).await;
+        return;
+    }
+    // Load and validate the configuration, every invalid setting is reported at once
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    // Personal data is redacted before anything is logged or persisted
+    let redactor = match Redactor::from_config(&config.privacy) {
+        Ok(redactor) => Arc::new(redactor),
+        Err(e) => {
+            log::error!("Invalid redaction configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
     // Create DB connection pools for log and buycycle DB
-    let database_url_buycycle =
-        env::var("DATABASE_URL_LOG").expect("DATABASE_URL must be set");
-    // Create a new database connection pool
-    let db_pool_buycycle = match DB::create_pool(&database_url_buycycle).await {
+    let db_pool_buycycle = match DB::create_pool(&config.database.buycycle_url).await {
         Ok(pool) => pool,
         Err(e) => {
             log::error!("Failed to create database pool buycycle: {:?}", e);
             std::process::exit(1);
         }
     };
-    let database_url_log = env::var("DATABASE_URL_LOG").expect("DATABASE_URL must be set");
-    // Create a new database connection pool
-    let db_pool_log = match DB::create_pool(&database_url_log).await {
-        Ok(pool) => pool,
+    let store = connect_log_store(&config.database.log_url).await;
+    if config.database.run_migrations {
+        apply_migrations(store.as_ref()).await;
+    }
+    // Delete old messages, inactive threads and stale rate limits once a day
+    tokio::spawn({
+        let store = Arc::clone(&store);
+        let config = Arc::clone(&config);
+        async move {
+            loop {
+                match apply_retention(store.as_ref(), &config).await {
+                    Ok(report) => log::info!(
+                        "Retention applied, deleted {} messages, {} tool calls, {} threads and {} rate limits, {} threads failed",
+                        report.messages,
+                        report.tool_calls,
+                        report.threads_deleted,
+                        report.rate_limits,
+                        report.threads_failed
+                    ),
+                    Err(e) => log::error!("Failed to apply retention: {:?}", e),
+                }
+                sleep(Duration::from_secs(24 * 3600)).await;
+            }
+        }
+    });
+    // Aggregate the stats of the previous and the current day in the configured interval
+    tokio::spawn({
+        let store = Arc::clone(&store);
+        let interval = Duration::from_secs(config.stats.interval_minutes * 60);
+        async move {
+            loop {
+                let today = Utc::now().date_naive();
+                for day in [today - chrono::Days::new(1), today] {
+                    if let Err(e) = aggregate_daily_stats(store.as_ref(), day).await {
+                        log::error!("Failed to aggregate the stats of {}: {:?}", day, e);
+                    }
+                }
+                sleep(interval).await;
+            }
+        }
+    });
+    // Answers of repeated questions, emptied when the resources are rotated
+    let cache = match AnswerCache::from_config(&config) {
+        Ok(cache) => Arc::new(cache),
         Err(e) => {
-            log::error!("Failed to create database pool log: {:?}", e);
+            log::error!("Failed to create the answer cache: {:?}", e);
             std::process::exit(1);
         }
     };
-    // Create the files for the assistant.
-    let mut ressources = match create_ressources(
-        db_pool_buycycle.clone(),
-        "context/file_search",
-        "context/code_interpreter",
-        Vec::new(),
-        "instruction/instruction.txt",
-    )
-    .await
+    // The resources and the assistant are only needed by the Assistants backend
+    let assistant_id = Arc::new(RwLock::new(String::new()));
+    let citation_files = Arc::new(RwLock::new(Vec::new()));
+    let current_assistant = if config.chat.backend == ChatBackendKind::Assistants {
+        // Create the files for the assistant.
+        let ressources = match create_ressources(db_pool_buycycle.clone(), &config).await {
+            Ok(ressources) => ressources,
+            Err(e) => {
+                log::error!("Failed to create ressources: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        let now = Utc::now();
+        let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
+        let assistant_name = format!("Assistant_{}", timestamp);
+        let assistant = match create_assistant(&assistant_name, &config, ressources.clone()).await {
+            Ok(assistant) => assistant,
+            Err(e) => {
+                log::error!("Failed to create assistant: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        *assistant_id.write().await = assistant.id.clone();
+        *citation_files.write().await = ressources.files_info_file_search.clone();
+        Some((assistant, ressources))
+    } else {
+        None
+    };
+    let log = LOG::new(Arc::clone(&store), Arc::clone(&redactor));
+    // Failed runs of the assistant are answered with Chat Completions, if enabled,
+    // or else with the canned answers
+    let fallback_completions = if config.chat.backend == ChatBackendKind::Assistants
+        && config.fallback.enabled
+        && config.fallback.completions
     {
-        Ok(ressources) => ressources,
-        Err(e) => {
-            log::error!("Failed to create ressources: {:?}", e);
-            std::process::exit(1);
+        match CompletionsBackend::new(
+            Arc::clone(&config),
+            provider::from_config(&config),
+            db_pool_buycycle.clone(),
+            log.clone(),
+        ) {
+            Ok(backend) => Some(backend),
+            Err(e) => {
+                log::error!("Failed to create the completions fallback: {:?}", e);
+                std::process::exit(1);
+            }
         }
+    } else {
+        None
     };
-    let now = Utc::now();
-    let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
-    let assistant_name = format!("Assistant_{}", timestamp);
-    let mut assistant = match create_assistant(&assistant_name, "gpt-4o", ressources.clone()).await {
-        Ok(assistant) => assistant,
+    let fallback = match Fallback::new(config.fallback.clone(), fallback_completions, log.clone()) {
+        Ok(fallback) => Arc::new(fallback),
         Err(e) => {
-            log::error!("Failed to create assistant: {:?}", e);
+            log::error!("Failed to create the fallback: {:?}", e);
             std::process::exit(1);
         }
     };
-    let assistant_id = Arc::new(RwLock::new(assistant.id.clone()));
+    let backend: Arc<dyn ChatBackend> = match config.chat.backend {
+        ChatBackendKind::Assistants => Arc::new(AssistantsBackend::new(
+            Arc::clone(&config),
+            db_pool_buycycle.clone(),
+            log,
+            Arc::clone(&assistant_id),
+            Arc::clone(&citation_files),
+        )),
+        ChatBackendKind::Completions => {
+            let backend = match CompletionsBackend::new(
+                Arc::clone(&config),
+                provider::from_config(&config),
+                db_pool_buycycle.clone(),
+                log,
+            ) {
+                Ok(backend) => backend,
+                Err(e) => {
+                    log::error!("Failed to create the completions backend: {:?}", e);
+                    std::process::exit(1);
+                }
+            };
+            if config.retrieval.enabled {
+                let index = retrieval_index(
+                    Arc::clone(&config),
+                    db_pool_buycycle.clone(),
+                    Arc::clone(&cache),
+                )
+                .await;
+                Arc::new(backend.with_retrieval(index))
+            } else {
+                Arc::new(backend)
+            }
+        }
+    };
+    log::info!(
+        "Chat requests are answered by the {:?} backend",
+        config.chat.backend
+    );
+    let auth = Arc::new(Authenticator::new(config.auth.clone()));
+    if !auth.enabled() {
+        log::warn!("Authentication is disabled, the user IDs sent by the clients are trusted and the account tools are not available");
+    }
+    let limiter = Arc::new(RateLimiter::from_config(&config, Arc::clone(&store)));
+    if !limiter.enabled() {
+        log::warn!("Rate limits are disabled, the chat requests are not limited");
+    }
     // Start the server in a separate async task
-    let server_assistant_id = Arc::clone(&assistant_id);
-    tokio::spawn({
+    let server = tokio::spawn({
         let db_pool_buycycle = db_pool_buycycle.clone();
-        let db_pool_log = db_pool_log.clone();
+        let store = Arc::clone(&store);
+        let config = Arc::clone(&config);
+        let citation_files = Arc::clone(&citation_files);
+        let cache = Arc::clone(&cache);
         async move {
-            let server = tokio::net::TcpListener::bind(&"0.0.0.0:3000")
+            let address = format!("{}:{}", config.server.host, config.server.port);
+            let server = tokio::net::TcpListener::bind(&address)
                 .await
                 .expect("Failed to bind server to address");
-            let router = app(db_pool_buycycle, db_pool_log, server_assistant_id).await; // Pass the assistant ID to the app
-            axum::serve(server, router.into_make_service())
-                .await
-                .expect("Failed to start server");
+            let router = app(AppState {
+                config,
+                db_pool_buycycle,
+                store,
+                backend,
+                cache,
+                fallback,
+                citation_files,
+                redactor,
+                auth,
+                limiter,
+            })
+            .await;
+            axum::serve(
+                server,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .expect("Failed to start server");
         }
     });
-    // Start a loop that runs every 24 hours to create a new resource and assistant
+    let Some((mut assistant, mut ressources)) = current_assistant else {
+        // Without an assistant there is nothing to rotate
+        server.await.expect("Server task failed");
+        return;
+    };
+    // Start a loop that creates a new resource and assistant in the configured interval
+    let rotation_interval = Duration::from_secs(config.assistant.rotation_interval_hours * 3600);
     loop {
-        // Wait for 24 hours
-        sleep(Duration::from_secs(24 * 3600)).await;
+        sleep(rotation_interval).await;
         // Attempt to create new resources and assistant
         let now = Utc::now();
         let timestamp = now.format("%Y%m%d_%H%M%S").to_string();
         let assistant_name = format!("Assistant_{}", timestamp);
-        match create_ressources(
-            db_pool_buycycle.clone(),
-            "context/file_search",
-            "context/code_interpreter",
-            Vec::new(),
-            "instruction/instruction.txt",
-        )
-        .await
-        {
+        match create_ressources(db_pool_buycycle.clone(), &config).await {
             Ok(new_ressources) => {
-                match create_assistant(&assistant_name, "gpt-4o", new_ressources.clone()).await {
+                match create_assistant(&assistant_name, &config, new_ressources.clone()).await {
                     Ok(new_assistant) => {
                         // Update the assistant ID in the shared state
                         let mut assistant_id_guard = assistant_id.write().await;
                         *assistant_id_guard = new_assistant.id.clone();
+                        *citation_files.write().await =
+                            new_ressources.files_info_file_search.clone();
+                        // Cached answers may cite the old files or follow the old instruction
+                        cache.invalidate().await;
                         // Delete the old assistant and resources after the last request with the old assistant_id is finished
                         tokio::spawn(async move {
                             assistant
@@ -0,0 +1,492 @@
+use crate::assistant::AssistantError;
+use crate::backend::{SseEvent, SseReader};
+use crate::config::{Config, ProviderKind};
+use axum::async_trait;
+use futures::stream::{BoxStream, StreamExt};
+use log::{error, info};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+// Version of the Anthropic Messages API the requests are written for
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// A chat completion request. Messages, tools and tool calls are in the format of the
+/// OpenAI Chat Completions API, providers with another format convert them.
+#[derive(Debug, Clone, Default)]
+pub struct CompletionRequest {
+    pub messages: Vec<Value>,
+    pub tools: Vec<Value>,
+    // If false the model has to answer, the tools are still sent so earlier calls are understood
+    pub allow_tools: bool,
+    pub max_tokens: Option<u64>,
+}
+/// A message of the model, tool calls in the OpenAI format.
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    pub id: String,
+    pub model: String,
+    pub content: String,
+    pub tool_calls: Vec<Value>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+}
+impl Completion {
+    /// The message of the model in the OpenAI format, to send it back with the tool outputs.
+    pub fn message(&self) -> Value {
+        json!({
+            "role": "assistant",
+            "content": Some(&self.content).filter(|content| !content.is_empty()),
+            "tool_calls": self.tool_calls,
+        })
+    }
+}
+/// An event of a streamed completion.
+#[derive(Debug)]
+pub enum CompletionEvent {
+    // A part of the text as it is generated
+    Delta(String),
+    // The complete message, sent once at the end of the stream
+    Done(Completion),
+}
+pub type CompletionStream = BoxStream<'static, Result<CompletionEvent, AssistantError>>;
+
+/// An API answering chat completion requests with tools, selected by `llm.provider`.
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    /// Sends the request and waits for the complete message.
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, AssistantError>;
+    /// Sends the request and streams the message, the stream ends with the complete message.
+    async fn stream(&self, request: &CompletionRequest)
+        -> Result<CompletionStream, AssistantError>;
+}
+
+/// Creates the provider selected by `llm.provider`, the model defaults to `openai.model`.
+pub fn from_config(config: &Config) -> Arc<dyn LlmProvider> {
+    let llm = &config.llm;
+    let model = llm
+        .model
+        .clone()
+        .unwrap_or_else(|| config.openai.model.clone());
+    let base_url = |default: &str| {
+        llm.base_url
+            .as_deref()
+            .unwrap_or(default)
+            .trim_end_matches('/')
+            .to_string()
+    };
+    match llm.provider {
+        ProviderKind::OpenAi => Arc::new(OpenAiProvider::new(
+            base_url(OPENAI_BASE_URL),
+            Some(config.openai.api_key.clone()),
+            model,
+        )),
+        ProviderKind::OpenAiCompatible => Arc::new(OpenAiProvider::new(
+            base_url(OPENAI_BASE_URL),
+            llm.api_key.clone(),
+            model,
+        )),
+        ProviderKind::Anthropic => Arc::new(AnthropicProvider::new(
+            base_url(ANTHROPIC_BASE_URL),
+            llm.api_key.clone().unwrap_or_default(),
+            model,
+            llm.max_tokens,
+        )),
+    }
+}
+
+/// The Chat Completions API of OpenAI or of a compatible server like vLLM or Ollama,
+/// local servers usually need no API key.
+pub struct OpenAiProvider {
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+}
+impl OpenAiProvider {
+    pub fn new(base_url: String, api_key: Option<String>, model: String) -> Self {
+        OpenAiProvider {
+            base_url,
+            api_key,
+            model,
+        }
+    }
+    fn payload(&self, request: &CompletionRequest, stream: bool) -> Value {
+        let mut payload = json!({
+            "model": self.model,
+            "messages": request.messages,
+        });
+        if !request.tools.is_empty() {
+            payload["tools"] = json!(request.tools);
+            if !request.allow_tools {
+                payload["tool_choice"] = json!("none");
+            }
+        }
+        if let Some(max_tokens) = request.max_tokens {
+            payload["max_tokens"] = json!(max_tokens);
+        }
+        if stream {
+            payload["stream"] = json!(true);
+            payload["stream_options"] = json!({ "include_usage": true });
+        }
+        payload
+    }
+    fn post(&self, payload: &Value) -> RequestBuilder {
+        let request = Client::new()
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(payload);
+        match self.api_key.as_deref().filter(|key| !key.is_empty()) {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, AssistantError> {
+        let response: Value = send(self.post(&self.payload(request, false)))
+            .await?
+            .json()
+            .await
+            .map_err(|_| {
+                AssistantError::OpenAIError("Failed to parse response from OpenAI".to_string())
+            })?;
+        let mut completion = Completion::default();
+        merge_openai_chunk(&mut completion, &response);
+        let message = &response["choices"][0]["message"];
+        completion.content = message["content"].as_str().unwrap_or_default().to_string();
+        completion.tool_calls = message["tool_calls"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        Ok(completion)
+    }
+    async fn stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<CompletionStream, AssistantError> {
+        let response = send(self.post(&self.payload(request, true))).await?;
+        Ok(completion_stream(response, |completion, event| {
+            if event.data == "[DONE]" {
+                return Ok(Step::Done);
+            }
+            let chunk: Value = serde_json::from_str(&event.data).map_err(|_| {
+                AssistantError::OpenAIError("Failed to parse response from OpenAI".to_string())
+            })?;
+            Ok(merge_openai_chunk(completion, &chunk))
+        }))
+    }
+}
+// Copies the ID, model, usage and the deltas of a completion or a streamed chunk,
+// returns the text delta of a chunk
+fn merge_openai_chunk(completion: &mut Completion, chunk: &Value) -> Step {
+    if let Some(id) = chunk["id"].as_str() {
+        completion.id = id.to_string();
+    }
+    if let Some(model) = chunk["model"].as_str() {
+        completion.model = model.to_string();
+    }
+    // Streams report the usage in the last chunk only
+    if let Some(usage) = chunk.get("usage").filter(|usage| !usage.is_null()) {
+        completion.prompt_tokens = usage["prompt_tokens"].as_i64();
+        completion.completion_tokens = usage["completion_tokens"].as_i64();
+    }
+    let delta = &chunk["choices"][0]["delta"];
+    for tool_call in delta["tool_calls"].as_array().into_iter().flatten() {
+        merge_tool_call_delta(&mut completion.tool_calls, tool_call);
+    }
+    match delta["content"].as_str().filter(|text| !text.is_empty()) {
+        Some(text) => {
+            completion.content.push_str(text);
+            Step::Delta(text.to_string())
+        }
+        None => Step::Skip,
+    }
+}
+// Merges a streamed part of a tool call into the tool calls of the completion,
+// the ID and name are sent once and the arguments in parts
+fn merge_tool_call_delta(tool_calls: &mut Vec<Value>, delta: &Value) {
+    let index = delta["index"].as_u64().unwrap_or_default() as usize;
+    while tool_calls.len() <= index {
+        tool_calls.push(tool_call("", "", ""));
+    }
+    let tool_call = &mut tool_calls[index];
+    if let Some(id) = delta["id"].as_str() {
+        tool_call["id"] = json!(id);
+    }
+    for field in ["name", "arguments"] {
+        if let Some(part) = delta["function"][field].as_str() {
+            append(&mut tool_call["function"][field], part);
+        }
+    }
+}
+
+/// The Messages API of Anthropic. System messages become the system prompt,
+/// tool calls and outputs become tool_use and tool_result blocks.
+pub struct AnthropicProvider {
+    base_url: String,
+    api_key: String,
+    model: String,
+    // Required by the Messages API, used if the request sets no limit
+    max_tokens: u64,
+}
+impl AnthropicProvider {
+    pub fn new(base_url: String, api_key: String, model: String, max_tokens: u64) -> Self {
+        AnthropicProvider {
+            base_url,
+            api_key,
+            model,
+            max_tokens,
+        }
+    }
+    fn payload(&self, request: &CompletionRequest, stream: bool) -> Value {
+        let mut system = Vec::new();
+        let mut messages: Vec<Value> = Vec::new();
+        for message in &request.messages {
+            let text = message["content"].as_str().unwrap_or_default();
+            let role = match message["role"].as_str().unwrap_or_default() {
+                "system" => {
+                    system.push(text);
+                    continue;
+                }
+                "assistant" => "assistant",
+                _ => "user",
+            };
+            let mut blocks = Vec::new();
+            if message["role"] == "tool" {
+                blocks.push(json!({
+                    "type": "tool_result",
+                    "tool_use_id": message["tool_call_id"],
+                    "content": text,
+                }));
+            } else if !text.is_empty() {
+                blocks.push(json!({ "type": "text", "text": text }));
+            }
+            for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+                let arguments = tool_call["function"]["arguments"].as_str().unwrap_or("{}");
+                blocks.push(json!({
+                    "type": "tool_use",
+                    "id": tool_call["id"],
+                    "name": tool_call["function"]["name"],
+                    "input": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})),
+                }));
+            }
+            // Consecutive messages of a role are merged, the roles have to alternate
+            match messages.last_mut() {
+                Some(last) if last["role"] == role => {
+                    if let Some(content) = last["content"].as_array_mut() {
+                        content.extend(blocks);
+                    }
+                }
+                _ => messages.push(json!({ "role": role, "content": blocks })),
+            }
+        }
+        // The conversation has to start with a message of the user
+        if messages
+            .first()
+            .is_some_and(|first| first["role"] != "user")
+        {
+            messages.insert(
+                0,
+                json!({
+                    "role": "user",
+                    "content": [{ "type": "text", "text": "(continued conversation)" }],
+                }),
+            );
+        }
+        let mut payload = json!({
+            "model": self.model,
+            "max_tokens": request.max_tokens.unwrap_or(self.max_tokens),
+            "messages": messages,
+        });
+        if !system.is_empty() {
+            payload["system"] = json!(system.join("\n\n"));
+        }
+        if !request.tools.is_empty() {
+            let tools: Vec<Value> = request
+                .tools
+                .iter()
+                .map(|tool| {
+                    json!({
+                        "name": tool["function"]["name"],
+                        "description": tool["function"]["description"],
+                        "input_schema": tool["function"]["parameters"],
+                    })
+                })
+                .collect();
+            payload["tools"] = json!(tools);
+            if !request.allow_tools {
+                payload["tool_choice"] = json!({ "type": "none" });
+            }
+        }
+        if stream {
+            payload["stream"] = json!(true);
+        }
+        payload
+    }
+    fn post(&self, payload: &Value) -> RequestBuilder {
+        Client::new()
+            .post(format!("{}/messages", self.base_url))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(payload)
+    }
+}
+#[async_trait]
+impl LlmProvider for AnthropicProvider {
+    async fn complete(&self, request: &CompletionRequest) -> Result<Completion, AssistantError> {
+        let response: Value = send(self.post(&self.payload(request, false)))
+            .await?
+            .json()
+            .await
+            .map_err(|_| {
+                AssistantError::OpenAIError("Failed to parse response from Anthropic".to_string())
+            })?;
+        let mut completion = Completion {
+            id: response["id"].as_str().unwrap_or_default().to_string(),
+            model: response["model"].as_str().unwrap_or_default().to_string(),
+            prompt_tokens: response["usage"]["input_tokens"].as_i64(),
+            completion_tokens: response["usage"]["output_tokens"].as_i64(),
+            ..Default::default()
+        };
+        for block in response["content"].as_array().into_iter().flatten() {
+            match block["type"].as_str() {
+                Some("text") => completion
+                    .content
+                    .push_str(block["text"].as_str().unwrap_or_default()),
+                Some("tool_use") => completion.tool_calls.push(tool_call(
+                    block["id"].as_str().unwrap_or_default(),
+                    block["name"].as_str().unwrap_or_default(),
+                    &block["input"].to_string(),
+                )),
+                _ => {}
+            }
+        }
+        Ok(completion)
+    }
+    async fn stream(
+        &self,
+        request: &CompletionRequest,
+    ) -> Result<CompletionStream, AssistantError> {
+        let response = send(self.post(&self.payload(request, true))).await?;
+        Ok(completion_stream(response, merge_anthropic_event))
+    }
+}
+// Merges an event of a streamed message into the completion, the input of a tool call
+// is streamed as parts of its JSON after the start of its block
+fn merge_anthropic_event(
+    completion: &mut Completion,
+    event: &SseEvent,
+) -> Result<Step, AssistantError> {
+    let data: Value = serde_json::from_str(&event.data).map_err(|_| {
+        AssistantError::OpenAIError("Failed to parse response from Anthropic".to_string())
+    })?;
+    match data["type"].as_str().unwrap_or_default() {
+        "message_start" => {
+            let message = &data["message"];
+            completion.id = message["id"].as_str().unwrap_or_default().to_string();
+            completion.model = message["model"].as_str().unwrap_or_default().to_string();
+            completion.prompt_tokens = message["usage"]["input_tokens"].as_i64();
+        }
+        "content_block_start" if data["content_block"]["type"] == "tool_use" => {
+            let block = &data["content_block"];
+            completion.tool_calls.push(tool_call(
+                block["id"].as_str().unwrap_or_default(),
+                block["name"].as_str().unwrap_or_default(),
+                "",
+            ));
+        }
+        "content_block_delta" => {
+            let delta = &data["delta"];
+            if let Some(text) = delta["text"].as_str() {
+                completion.content.push_str(text);
+                return Ok(Step::Delta(text.to_string()));
+            }
+            if let (Some(part), Some(tool_call)) = (
+                delta["partial_json"].as_str(),
+                completion.tool_calls.last_mut(),
+            ) {
+                append(&mut tool_call["function"]["arguments"], part);
+            }
+        }
+        "message_delta" => {
+            completion.completion_tokens = data["usage"]["output_tokens"].as_i64();
+        }
+        "message_stop" => return Ok(Step::Done),
+        "error" => {
+            return Err(AssistantError::OpenAIError(
+                data["error"]["message"]
+                    .as_str()
+                    .unwrap_or(&event.data)
+                    .to_string(),
+            ))
+        }
+        _ => {}
+    }
+    Ok(Step::Skip)
+}
+
+// Result of reading an event of a streamed completion
+enum Step {
+    Skip,
+    Delta(String),
+    Done,
+}
+// Reads the events of a streamed completion with the given handler, a stream without
+// a final event ends with the message read so far
+fn completion_stream(
+    response: reqwest::Response,
+    handle: fn(&mut Completion, &SseEvent) -> Result<Step, AssistantError>,
+) -> CompletionStream {
+    let state = (SseReader::new(response), Some(Completion::default()));
+    futures::stream::unfold(state, move |(mut events, completion)| async move {
+        let mut completion = completion?;
+        loop {
+            let event = match events.next_event().await {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(e) => return Some((Err(e), (events, None))),
+            };
+            match handle(&mut completion, &event) {
+                Ok(Step::Skip) => continue,
+                Ok(Step::Delta(text)) => {
+                    let event = CompletionEvent::Delta(text);
+                    return Some((Ok(event), (events, Some(completion))));
+                }
+                Ok(Step::Done) => break,
+                Err(e) => return Some((Err(e), (events, None))),
+            }
+        }
+        Some((Ok(CompletionEvent::Done(completion)), (events, None)))
+    })
+    .boxed()
+}
+
+// Sends a request to the provider, error responses are returned with their body
+async fn send(request: RequestBuilder) -> Result<reqwest::Response, AssistantError> {
+    let response = request.send().await.map_err(|e| {
+        AssistantError::OpenAIError(format!("Failed to send request to the LLM provider: {}", e))
+    })?;
+    if response.status().is_success() {
+        info!("Request to the LLM provider sent successfully");
+        Ok(response)
+    } else {
+        let error_message = response.text().await.unwrap_or_default();
+        error!("Chat completion failed: {}", error_message);
+        Err(AssistantError::OpenAIError(error_message))
+    }
+}
+
+fn tool_call(id: &str, name: &str, arguments: &str) -> Value {
+    json!({
+        "id": id,
+        "type": "function",
+        "function": { "name": name, "arguments": arguments },
+    })
+}
+
+fn append(target: &mut Value, part: &str) {
+    let value = format!("{}{}", target.as_str().unwrap_or_default(), part);
+    *target = json!(value);
+}
@@ -0,0 +1,216 @@
+use crate::config::PrivacyConfig;
+use openssl::base64;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use regex::{Captures, Regex};
+
+// Length of the AES-256-GCM nonce and tag in bytes
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// A kind of personal data that is detected in a text.
+struct PiiPattern {
+    label: &'static str,
+    regex: Regex,
+    // Capture group that contains the personal data, the rest of the match is kept
+    group: usize,
+}
+
+/// Redacts personal data (emails, phone numbers, IBANs, addresses and order numbers)
+/// from texts before they are logged or persisted.
+/// Logs are always masked irreversibly, the database copy is encrypted if a key is configured
+/// so it can be revealed again, e.g. for data subject requests.
+pub struct Redactor {
+    enabled: bool,
+    patterns: Vec<PiiPattern>,
+    // AES-256 key for the reversible encryption of the database copy
+    encryption_key: Option<Vec<u8>>,
+    encrypted_token: Regex,
+}
+
+impl Redactor {
+    /// Creates a redactor, without an encryption key the database copy is masked as well.
+    pub fn new(enabled: bool, encryption_key: Option<Vec<u8>>) -> Result<Self, String> {
+        if let Some(key) = &encryption_key {
+            if key.len() != 32 {
+                return Err("PII_ENCRYPTION_KEY must be 32 bytes (64 hex characters)".to_string());
+            }
+        }
+        // The order matters, e.g. IBANs have to be matched before phone numbers
+        let patterns = vec![
+            PiiPattern {
+                label: "EMAIL",
+                regex: Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap(),
+                group: 0,
+            },
+            PiiPattern {
+                label: "IBAN",
+                regex: Regex::new(r"\b[A-Z]{2}\d{2}(?: ?[A-Z0-9]{4}){2,7}(?: ?[A-Z0-9]{1,4})?\b")
+                    .unwrap(),
+                group: 0,
+            },
+            PiiPattern {
+                label: "ORDER",
+                regex: Regex::new(
+                    r"(?i)\b(?:order|bestellung|commande|ordine|pedido)\b[^\d\n]{0,20}(\d{4,})",
+                )
+                .unwrap(),
+                group: 1,
+            },
+            PiiPattern {
+                label: "PHONE",
+                regex: Regex::new(r"(?:\+|\b00|\b0)\d[\d /().-]{6,}\d\b").unwrap(),
+                group: 0,
+            },
+            PiiPattern {
+                label: "ADDRESS",
+                // A capitalized street name with a known suffix, so words like "bring 2" or
+                // "2 bikes to drive" are not matched
+                regex: Regex::new(
+                    r"\b\p{Lu}[\p{L}-]{2,}(?:straße|strasse|str\.|weg|gasse|platz|allee|ring)\s+\d{1,4}(?:\s?[a-z]\b|\b)|\b\p{Lu}[\p{L}-]+\s(?:Straße|Strasse|Str\.|Weg|Gasse|Platz|Allee|Ring)\s+\d{1,4}(?:\s?[a-z]\b|\b)|\b\d{1,5}\s+(?:\p{Lu}\p{L}*\s){1,3}(?i:street|st\.|road|rd\.|avenue|ave\.|lane|drive|boulevard|blvd\.)",
+                )
+                .unwrap(),
+                group: 0,
+            },
+        ];
+        Ok(Redactor {
+            enabled,
+            patterns,
+            encryption_key,
+            encrypted_token: Regex::new(r"\[([A-Z]+):enc:([A-Za-z0-9+/=]+)\]").unwrap(),
+        })
+    }
+    /// Creates a redactor from the privacy settings, the encryption key is hex encoded.
+    pub fn from_config(config: &PrivacyConfig) -> Result<Self, String> {
+        let encryption_key = match &config.pii_encryption_key {
+            Some(key) => Some(decode_hex(key)?),
+            None => None,
+        };
+        Redactor::new(config.pii_redaction, encryption_key)
+    }
+    /// Whether protected texts can be revealed again: redaction is disabled or the personal
+    /// data is encrypted. Masked data is lost.
+    pub fn reversible(&self) -> bool {
+        !self.enabled || self.encryption_key.is_some()
+    }
+    /// Whether personal data is detected in a text, always false if redaction is disabled.
+    pub fn contains_personal_data(&self, text: &str) -> bool {
+        self.enabled
+            && self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.regex.is_match(text))
+    }
+    /// Irreversibly masks the personal data of a text, e.g. "[EMAIL]". Used for logs.
+    pub fn mask(&self, text: &str) -> String {
+        self.replace(text, |label, _| format!("[{}]", label))
+    }
+    /// Protects the personal data of a text before it is persisted.
+    /// Encrypted as "[EMAIL:enc:...]" if an encryption key is configured, masked otherwise.
+    pub fn protect(&self, text: &str) -> String {
+        match &self.encryption_key {
+            Some(key) => self.replace(text, |label, value| match encrypt(key, value) {
+                Ok(encrypted) => format!("[{}:enc:{}]", label, encrypted),
+                Err(e) => {
+                    log::error!("Failed to encrypt personal data: {}", e);
+                    format!("[{}]", label)
+                }
+            }),
+            None => self.mask(text),
+        }
+    }
+    /// Decrypts the personal data protected by `protect`. Masked data can not be revealed
+    /// and tokens that can not be decrypted are kept as they are.
+    pub fn reveal(&self, text: &str) -> String {
+        let key = match &self.encryption_key {
+            Some(key) => key,
+            None => return text.to_string(),
+        };
+        self.encrypted_token
+            .replace_all(text, |caps: &Captures| {
+                decrypt(key, &caps[2]).unwrap_or_else(|_| caps[0].to_string())
+            })
+            .into_owned()
+    }
+    fn replace(&self, text: &str, replacement: impl Fn(&str, &str) -> String) -> String {
+        if !self.enabled {
+            return text.to_string();
+        }
+        // Collect the spans of personal data on the original text, so replacements are never
+        // matched again. Earlier patterns take precedence over overlapping later ones.
+        let mut spans: Vec<(usize, usize, &str)> = Vec::new();
+        for pattern in &self.patterns {
+            for caps in pattern.regex.captures_iter(text) {
+                let value = caps.get(pattern.group).unwrap();
+                let overlaps = spans
+                    .iter()
+                    .any(|(start, end, _)| value.start() < *end && *start < value.end());
+                if !overlaps {
+                    spans.push((value.start(), value.end(), pattern.label));
+                }
+            }
+        }
+        spans.sort_by_key(|(start, _, _)| *start);
+        let mut redacted = String::with_capacity(text.len());
+        let mut position = 0;
+        for (start, end, label) in spans {
+            redacted.push_str(&text[position..start]);
+            redacted.push_str(&replacement(label, &text[start..end]));
+            position = end;
+        }
+        redacted.push_str(&text[position..]);
+        redacted
+    }
+}
+
+fn encrypt(key: &[u8], value: &str) -> Result<String, String> {
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand_bytes(&mut nonce).map_err(|e| e.to_string())?;
+    let mut tag = [0u8; TAG_LENGTH];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &[],
+        value.as_bytes(),
+        &mut tag,
+    )
+    .map_err(|e| e.to_string())?;
+    Ok(base64::encode_block(
+        &[&nonce[..], &ciphertext, &tag].concat(),
+    ))
+}
+
+fn decrypt(key: &[u8], encoded: &str) -> Result<String, String> {
+    let data = base64::decode_block(encoded).map_err(|e| e.to_string())?;
+    if data.len() < NONCE_LENGTH + TAG_LENGTH {
+        return Err("Encrypted value is too short".to_string());
+    }
+    let (nonce, rest) = data.split_at(NONCE_LENGTH);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+    let plaintext = decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        &[],
+        ciphertext,
+        tag,
+    )
+    .map_err(|e| e.to_string())?;
+    String::from_utf8(plaintext).map_err(|e| e.to_string())
+}
+
+/// Decodes a hex encoded key.
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if !hex.len().is_multiple_of(2) {
+        return Err("Hex encoded key must have an even length".to_string());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("Invalid hex character in key at position {}", i))
+        })
+        .collect()
+}
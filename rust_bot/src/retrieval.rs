@@ -0,0 +1,407 @@
+use crate::assistant::AssistantError;
+use crate::config::{EmbedderKind, RetrievalConfig};
+use axum::async_trait;
+use log::info;
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Name of the search tool offered to the model
+pub const SEARCH_TOOL_NAME: &str = "search_knowledge";
+// Texts embedded with a single request
+const EMBEDDING_BATCH_SIZE: usize = 100;
+// Dimensions of the vectors of the hashing embedder
+const HASHING_DIMENSIONS: usize = 512;
+
+/// Computes the embedding vectors of texts, the vectors of a model can be compared by cosine.
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// Name of the model, vectors of different models are not compared.
+    fn model(&self) -> &str;
+    /// Returns a vector for every text, in the order of the texts.
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AssistantError>;
+}
+
+/// Embeddings API of OpenAI.
+pub struct OpenAiEmbedder {
+    api_key: String,
+    model: String,
+}
+impl OpenAiEmbedder {
+    pub fn new(api_key: String, model: String) -> Self {
+        OpenAiEmbedder { api_key, model }
+    }
+}
+#[async_trait]
+impl Embedder for OpenAiEmbedder {
+    fn model(&self) -> &str {
+        &self.model
+    }
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AssistantError> {
+        let response = Client::new()
+            .post("https://api.openai.com/v1/embeddings")
+            .bearer_auth(&self.api_key)
+            .json(&json!({ "model": self.model, "input": texts }))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let error_message = response.text().await.unwrap_or_default();
+            return Err(AssistantError::OpenAIError(error_message));
+        }
+        let response: Value = response.json().await?;
+        let mut data: Vec<(u64, Vec<f32>)> = response["data"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|item| {
+                let embedding = item["embedding"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|value| value.as_f64().map(|value| value as f32))
+                    .collect();
+                (item["index"].as_u64().unwrap_or_default(), embedding)
+            })
+            .collect();
+        if data.len() != texts.len() {
+            return Err(AssistantError::OpenAIError(format!(
+                "Expected {} embeddings, got {}",
+                texts.len(),
+                data.len()
+            )));
+        }
+        data.sort_by_key(|(index, _)| *index);
+        Ok(data.into_iter().map(|(_, embedding)| embedding).collect())
+    }
+}
+
+/// Hashes the words of a text into a fixed number of dimensions. It needs no API,
+/// so the index can be built offline and in tests, but it only matches equal words.
+pub struct HashingEmbedder;
+#[async_trait]
+impl Embedder for HashingEmbedder {
+    fn model(&self) -> &str {
+        "hashing"
+    }
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AssistantError> {
+        Ok(texts.iter().map(|text| hash_words(text)).collect())
+    }
+}
+fn hash_words(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; HASHING_DIMENSIONS];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        // FNV-1a, stable across builds unlike the hasher of the standard library
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            });
+        vector[(hash % HASHING_DIMENSIONS as u64) as usize] += 1.0;
+    }
+    vector
+}
+
+/// Creates the embedder selected by `retrieval.embedder`.
+pub fn embedder(config: &RetrievalConfig, api_key: &str) -> Arc<dyn Embedder> {
+    match config.embedder {
+        EmbedderKind::OpenAi => Arc::new(OpenAiEmbedder::new(
+            api_key.to_string(),
+            config.embedding_model.clone(),
+        )),
+        EmbedderKind::Hashing => Arc::new(HashingEmbedder),
+    }
+}
+
+/// A text to index, source is shown to the model with the search results.
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub source: String,
+    pub text: String,
+}
+/// A chunk found by a search, score is the cosine similarity to the query.
+#[derive(Serialize, Debug, Clone)]
+pub struct SearchResult {
+    pub source: String,
+    pub content: String,
+    pub score: f32,
+}
+
+/// Reads the documents of the folders, e.g. the file search documents and the exported bikes.
+/// Missing folders are skipped, the code interpreter folder only exists after the bike export.
+pub fn load_documents(folders: &[&str]) -> Result<Vec<Document>, AssistantError> {
+    let mut documents = Vec::new();
+    for folder in folders {
+        let path = Path::new(folder);
+        if !path.is_dir() {
+            continue;
+        }
+        let mut paths: Vec<_> = fs::read_dir(path)
+            .map_err(|e| AssistantError::DatabaseError(e.to_string()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        paths.sort();
+        for path in paths {
+            // Binary files like images can not be indexed
+            let Ok(text) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let source = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            documents.push(Document { source, text });
+        }
+    }
+    Ok(documents)
+}
+
+/// Splits a document into chunks of at most chunk_size characters, words are not split.
+/// The items of a JSON array, like the bikes or help articles, are chunked separately.
+pub fn chunk_document(document: &Document, chunk_size: usize, overlap: usize) -> Vec<String> {
+    match serde_json::from_str::<Value>(&document.text) {
+        Ok(Value::Array(items)) => items
+            .iter()
+            .flat_map(|item| {
+                let text = match item {
+                    Value::String(text) => text.clone(),
+                    item => serde_json::to_string_pretty(item).unwrap_or_default(),
+                };
+                chunk_text(&text, chunk_size, overlap)
+            })
+            .collect(),
+        _ => chunk_text(&document.text, chunk_size, overlap),
+    }
+}
+fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let mut end = start;
+        let mut length = 0;
+        while end < words.len() && (end == start || length + 1 + words[end].len() <= chunk_size) {
+            length += words[end].len() + usize::from(end > start);
+            end += 1;
+        }
+        chunks.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+        // The next chunk repeats the last words within the overlap
+        let mut next = end;
+        let mut repeated = 0;
+        while next > start + 1 && repeated + words[next - 1].len() < overlap {
+            repeated += words[next - 1].len() + 1;
+            next -= 1;
+        }
+        start = next;
+    }
+    chunks
+}
+
+/// Local retrieval index: chunks of the documents with their embeddings in a SQLite database,
+/// searched by cosine similarity. The chunks are few enough to be compared in memory.
+#[derive(Clone)]
+pub struct RetrievalIndex {
+    pool: SqlitePool,
+    embedder: Arc<dyn Embedder>,
+    top_k: usize,
+    chunk_size: usize,
+    chunk_overlap: usize,
+}
+impl RetrievalIndex {
+    /// Opens the index database and applies its migrations.
+    pub async fn connect(
+        config: &RetrievalConfig,
+        embedder: Arc<dyn Embedder>,
+    ) -> Result<Self, AssistantError> {
+        let connect_options =
+            SqliteConnectOptions::from_str(&config.database_url)?.create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(connect_options)
+            .await?;
+        sqlx::migrate!("./migrations/retrieval")
+            .run(&pool)
+            .await
+            .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
+        Ok(RetrievalIndex {
+            pool,
+            embedder,
+            top_k: config.top_k as usize,
+            chunk_size: config.chunk_size as usize,
+            chunk_overlap: config.chunk_overlap as usize,
+        })
+    }
+    /// Replaces the chunks of the index with the chunks of the documents and returns their
+    /// number. Embeddings of unchanged chunks are reused, only new chunks are embedded.
+    pub async fn rebuild(&self, documents: &[Document]) -> Result<usize, AssistantError> {
+        let model = self.embedder.model().to_string();
+        let mut chunks = Vec::new();
+        for document in documents {
+            for content in chunk_document(document, self.chunk_size, self.chunk_overlap) {
+                let hash = content_hash(&content);
+                chunks.push((document.source.clone(), content, hash));
+            }
+        }
+        let rows = sqlx::query("SELECT content_hash, embedding FROM chunks WHERE model = ?")
+            .bind(&model)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut embeddings: HashMap<String, Vec<u8>> = rows
+            .into_iter()
+            .map(|row| (row.get("content_hash"), row.get("embedding")))
+            .collect();
+        // Chunks repeated in the documents are embedded once
+        let mut missing_hashes: HashSet<&str> = HashSet::new();
+        let mut missing: Vec<String> = Vec::new();
+        for (_, content, hash) in &chunks {
+            if !embeddings.contains_key(hash) && missing_hashes.insert(hash) {
+                missing.push(content.clone());
+            }
+        }
+        for batch in missing.chunks(EMBEDDING_BATCH_SIZE) {
+            let vectors = self.embedder.embed(batch).await?;
+            for (content, vector) in batch.iter().zip(vectors) {
+                embeddings.insert(content_hash(content), encode_vector(&vector));
+            }
+        }
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM chunks").execute(&mut *tx).await?;
+        for (source, content, hash) in &chunks {
+            sqlx::query(
+                "INSERT INTO chunks (source, content, content_hash, model, embedding) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(source)
+            .bind(content)
+            .bind(hash)
+            .bind(&model)
+            .bind(&embeddings[hash])
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        info!(
+            "Indexed {} chunks of {} documents, {} embedded",
+            chunks.len(),
+            documents.len(),
+            missing.len()
+        );
+        Ok(chunks.len())
+    }
+    /// Returns the limit chunks most similar to the query, the most similar first.
+    pub async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>, AssistantError> {
+        let query_vector = self
+            .embedder
+            .embed(&[query.to_string()])
+            .await?
+            .pop()
+            .unwrap_or_default();
+        let rows = sqlx::query("SELECT source, content, embedding FROM chunks WHERE model = ?")
+            .bind(self.embedder.model())
+            .fetch_all(&self.pool)
+            .await?;
+        let mut results: Vec<SearchResult> = rows
+            .into_iter()
+            .map(|row| {
+                let embedding: Vec<u8> = row.get("embedding");
+                SearchResult {
+                    source: row.get("source"),
+                    content: row.get("content"),
+                    score: cosine(&query_vector, &decode_vector(&embedding)),
+                }
+            })
+            .filter(|result| result.score > 0.0)
+            .collect();
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        results.truncate(limit);
+        Ok(results)
+    }
+    /// Runs a search tool call of the model, the top k chunks are returned as JSON.
+    pub(crate) async fn call_search_tool(
+        &self,
+        arguments: &Value,
+    ) -> Result<String, AssistantError> {
+        let query = arguments
+            .get("query")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| AssistantError::InvalidInput("query is missing".to_string()))?;
+        info!("Searching the retrieval index");
+        let results = self.search(query, self.top_k).await?;
+        if results.is_empty() {
+            return Ok("No matching documents found".to_string());
+        }
+        Ok(json!(results).to_string())
+    }
+}
+
+/// Definition of the search tool, offered to the model if the index is enabled.
+pub fn tool_definition() -> Value {
+    json!({
+        "type": "function",
+        "function": {
+            "name": SEARCH_TOOL_NAME,
+            "description": "Search the help articles and the available bikes",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "What to search for, e.g. the question of the user"
+                    }
+                },
+                "required": ["query"]
+            }
+        }
+    })
+}
+
+pub(crate) fn content_hash(content: &str) -> String {
+    openssl::sha::sha256(content.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+pub(crate) fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
@@ -0,0 +1,258 @@
+use crate::assistant::{
+    AssistantError, ChatExport, ChatSummary, DailyStats, ErasureReport, LoggedMessage,
+    MessageFeedback, NegativeFeedback, RunRecord, ToolCallRecord,
+};
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+mod mysql;
+mod sqlite;
+
+pub use mysql::MySqlStore;
+pub use sqlite::SqliteStore;
+
+/// Storage of the conversation log: chats, messages, runs, tool calls, feedback and the
+/// aggregated stats. Texts are stored as given, personal data is protected by the caller.
+#[async_trait]
+pub trait ConversationStore: Send + Sync {
+    /// Applies the pending migrations of the backend, embedded into the binary at compile time.
+    async fn migrate(&self) -> Result<(), AssistantError>;
+
+    /// Retrieves the latest chat ID of a user whose thread was not deleted.
+    async fn get_chat_id(&self, user_id: &str) -> Result<Option<String>, AssistantError>;
+    async fn save_chat_id(&self, user_id: &str, chat_id: &str) -> Result<(), AssistantError>;
+    /// Retrieves all chats of a user with their messages, runs, tool calls and feedback.
+    async fn get_user_chats(&self, user_id: &str) -> Result<Vec<ChatExport>, AssistantError>;
+    /// Retrieves chats whose thread was not deleted yet and had no activity since the cutoff.
+    async fn get_inactive_chat_ids(
+        &self,
+        cutoff: DateTime<Utc>,
+        limit: u64,
+    ) -> Result<Vec<String>, AssistantError>;
+    /// Marks the thread of a chat as deleted, the summary of the chat is deleted with it.
+    async fn mark_thread_deleted(&self, chat_id: &str) -> Result<(), AssistantError>;
+    /// Deletes all data of a user in a single transaction, with the daily run quotas and the
+    /// rate limit bucket of the user, and records the erasure in the audit log.
+    async fn erase_user_data(
+        &self,
+        user_id: &str,
+        threads_deleted: u64,
+    ) -> Result<ErasureReport, AssistantError>;
+
+    /// Saves a message, message_id is the ID of the thread message if it was added to the thread.
+    async fn save_message(
+        &self,
+        chat_id: &str,
+        role: &str,
+        content: &str,
+        message_id: Option<&str>,
+    ) -> Result<(), AssistantError>;
+    /// Saves a reply of the fallback as an assistant message marked as fallback.
+    async fn save_fallback_message(
+        &self,
+        chat_id: &str,
+        content: &str,
+    ) -> Result<(), AssistantError>;
+    /// Saves a message of a user with an uploaded image, referenced by its file ID.
+    async fn save_image_message(
+        &self,
+        chat_id: &str,
+        content: &str,
+        image_file_id: &str,
+    ) -> Result<(), AssistantError>;
+    /// Whether the image with the file ID was uploaded in a chat of the user.
+    async fn has_image(&self, user_id: &str, image_file_id: &str) -> Result<bool, AssistantError>;
+    /// Retrieves the logged messages of a chat in the order they were saved.
+    async fn get_messages(&self, chat_id: &str) -> Result<Vec<LoggedMessage>, AssistantError>;
+    /// Retrieves the rolling summary of the older messages of a chat.
+    async fn get_chat_summary(&self, chat_id: &str) -> Result<Option<ChatSummary>, AssistantError>;
+    /// Saves the summary of a chat, replacing the previous one.
+    async fn save_chat_summary(
+        &self,
+        chat_id: &str,
+        summary: &ChatSummary,
+    ) -> Result<(), AssistantError>;
+    /// Retrieves the database ID of a logged assistant message by its thread message ID,
+    /// None if it is not in a chat of the user.
+    async fn get_assistant_message_id(
+        &self,
+        user_id: &str,
+        message_id: &str,
+    ) -> Result<Option<i64>, AssistantError>;
+    /// Deletes messages older than the cutoff in batches, feedback on them is deleted as well.
+    async fn delete_messages_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        batch_size: u64,
+    ) -> Result<u64, AssistantError>;
+
+    async fn save_run(&self, record: &RunRecord) -> Result<(), AssistantError>;
+    async fn save_tool_call(&self, record: &ToolCallRecord) -> Result<(), AssistantError>;
+    /// Deletes tool calls older than the cutoff in batches.
+    async fn delete_tool_calls_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        batch_size: u64,
+    ) -> Result<u64, AssistantError>;
+
+    /// Saves the rating of a user on a message, replacing the previous rating of the user.
+    async fn save_feedback(
+        &self,
+        message_db_id: i64,
+        user_id: &str,
+        feedback: &MessageFeedback,
+    ) -> Result<(), AssistantError>;
+    /// Retrieves the negative feedback given since the date, with the conversation
+    /// up to and including the rated message.
+    async fn get_negative_feedback(
+        &self,
+        since: NaiveDate,
+    ) -> Result<Vec<NegativeFeedback>, AssistantError>;
+
+    /// Computes the stats of a day from the chats, messages, runs and tool_calls tables.
+    async fn compute_daily_stats(&self, day: NaiveDate) -> Result<DailyStats, AssistantError>;
+    /// Saves the stats of a day, replacing earlier stats of the same day.
+    async fn save_daily_stats(&self, stats: &DailyStats) -> Result<(), AssistantError>;
+    /// Retrieves the saved stats of the days from `from` to `to`, both included.
+    async fn get_daily_stats(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyStats>, AssistantError>;
+
+    /// Takes a token of a rate limit bucket that is refilled with `refill_per_sec` tokens
+    /// up to `capacity`, a new bucket is full. Returns false if there was no token left.
+    async fn take_rate_limit_token(
+        &self,
+        bucket: &str,
+        capacity: f64,
+        refill_per_sec: f64,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AssistantError>;
+    /// Counts a run of a user on a day if the user had less than `limit` runs on it.
+    /// Returns false if the quota of the day is used up, the run is not counted then.
+    async fn take_daily_run(
+        &self,
+        user_id: &str,
+        day: NaiveDate,
+        limit: i64,
+    ) -> Result<bool, AssistantError>;
+    /// Leases one of the first `slots` run slots that is free or expired until `expires_at`.
+    /// Returns false if all of them are leased.
+    async fn lease_run_slot(
+        &self,
+        slots: u64,
+        lease_id: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AssistantError>;
+    async fn release_run_slot(&self, lease_id: &str) -> Result<(), AssistantError>;
+    /// Deletes the rate limit buckets not used since the cutoff and the daily runs of the
+    /// days before it, they are full or over.
+    async fn delete_rate_limits_before(&self, cutoff: DateTime<Utc>)
+        -> Result<u64, AssistantError>;
+}
+
+/// Connects to the store selected by the scheme of the database URL,
+/// `mysql://` for MySQL and `sqlite:` for SQLite.
+pub async fn connect(database_url: &str) -> Result<Arc<dyn ConversationStore>, AssistantError> {
+    if database_url.starts_with("mysql:") {
+        Ok(Arc::new(MySqlStore::connect(database_url).await?))
+    } else if database_url.starts_with("sqlite:") {
+        Ok(Arc::new(SqliteStore::connect(database_url).await?))
+    } else {
+        Err(AssistantError::DatabaseError(
+            "Unsupported database URL, expected a mysql:// or sqlite: URL".to_string(),
+        ))
+    }
+}
+
+// Counts of a day queried by the backends, combined into the stats by `daily_stats`
+struct DayCounts {
+    runs: i64,
+    conversations: i64,
+    timeouts: i64,
+    errors: i64,
+    new_chats: i64,
+    messages: i64,
+    chats_with_messages: i64,
+    latencies: Vec<i64>,
+    tool_usage: Vec<(String, i64)>,
+    locales: Vec<(Option<String>, i64)>,
+}
+
+fn daily_stats(day: NaiveDate, counts: DayCounts) -> DailyStats {
+    // Locales like en-CA and en-US are counted as the same language
+    let mut languages = BTreeMap::new();
+    for (locale, count) in counts.locales {
+        let language = locale
+            .and_then(|locale| locale.split(['-', '_']).next().map(str::to_lowercase))
+            .filter(|language| !language.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+        *languages.entry(language).or_insert(0) += count;
+    }
+    DailyStats {
+        day,
+        conversations: counts.conversations,
+        new_chats: counts.new_chats,
+        messages: counts.messages,
+        messages_per_chat: if counts.chats_with_messages > 0 {
+            counts.messages as f64 / counts.chats_with_messages as f64
+        } else {
+            0.0
+        },
+        runs: counts.runs,
+        timeouts: counts.timeouts,
+        errors: counts.errors,
+        median_latency_ms: median(counts.latencies),
+        tool_usage: counts.tool_usage.into_iter().collect(),
+        languages,
+    }
+}
+
+/// Median of the latencies, the mean of the two middle values for an even number.
+fn median(mut values: Vec<i64>) -> Option<i64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[middle - 1] + values[middle]) / 2)
+    } else {
+        Some(values[middle])
+    }
+}
+
+/// Refills a token bucket for the time since it was updated and takes a token if there is one.
+/// Returns the tokens left and whether a token was taken.
+fn take_token(
+    tokens: f64,
+    updated_at_ms: i64,
+    capacity: f64,
+    refill_per_sec: f64,
+    now_ms: i64,
+) -> (f64, bool) {
+    let elapsed_secs = (now_ms - updated_at_ms).max(0) as f64 / 1000.0;
+    let tokens = (tokens + elapsed_secs * refill_per_sec).min(capacity);
+    if tokens >= 1.0 {
+        (tokens - 1.0, true)
+    } else {
+        (tokens, false)
+    }
+}
+
+/// The rate limit bucket of a user.
+pub fn user_bucket(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+/// Hashes a user ID with SHA-256, so erasures can be audited without keeping the user ID.
+fn hash_user_id(user_id: &str) -> String {
+    openssl::sha::sha256(user_id.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,689 @@
+use super::{daily_stats, hash_user_id, take_token, user_bucket, ConversationStore, DayCounts};
+use crate::assistant::{
+    AssistantError, ChatExport, ChatSummary, DailyStats, DailyStatsRow, ErasureReport,
+    LoggedMessage, MessageFeedback, NegativeFeedback, RunRecord, ToolCallRecord,
+    FEEDBACK_CONVERSATION_LENGTH,
+};
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::json;
+use sqlx::{mysql::MySqlPoolOptions, MySqlPool};
+
+/// Conversation store in the buycycle_chatbot schema of a MySQL database.
+pub struct MySqlStore {
+    pool: MySqlPool,
+}
+
+impl MySqlStore {
+    pub async fn connect(database_url: &str) -> Result<Self, AssistantError> {
+        let pool = MySqlPoolOptions::new()
+            .connect(database_url)
+            .await
+            .map_err(|e| AssistantError::DatabaseError(e.to_string()))?;
+        Ok(MySqlStore { pool })
+    }
+    async fn lease_free_run_slot(
+        &self,
+        slots: u64,
+        lease_id: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AssistantError> {
+        let leased = sqlx::query(
+            "UPDATE buycycle_chatbot.run_slots SET lease_id = ?, expires_at_ms = ?
+             WHERE slot < ? AND (lease_id IS NULL OR expires_at_ms < ?) ORDER BY slot LIMIT 1",
+        )
+        .bind(lease_id)
+        .bind(expires_at.timestamp_millis())
+        .bind(slots as i64)
+        .bind(now.timestamp_millis())
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(leased > 0)
+    }
+}
+
+#[async_trait]
+impl ConversationStore for MySqlStore {
+    async fn migrate(&self) -> Result<(), AssistantError> {
+        sqlx::migrate!("./migrations/mysql")
+            .run(&self.pool)
+            .await
+            .map_err(|e| AssistantError::DatabaseError(e.to_string()))
+    }
+    async fn get_chat_id(&self, user_id: &str) -> Result<Option<String>, AssistantError> {
+        Ok(sqlx::query_scalar(
+            "SELECT id FROM buycycle_chatbot.chats WHERE user_id = ? AND thread_deleted_at IS NULL ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+    async fn save_chat_id(&self, user_id: &str, chat_id: &str) -> Result<(), AssistantError> {
+        sqlx::query("INSERT INTO buycycle_chatbot.chats (id, user_id) VALUES (?, ?)")
+            .bind(chat_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+    async fn get_user_chats(&self, user_id: &str) -> Result<Vec<ChatExport>, AssistantError> {
+        let mut chats: Vec<ChatExport> = sqlx::query_as(
+            "SELECT id AS chat_id, created_at FROM buycycle_chatbot.chats WHERE user_id = ? ORDER BY created_at ASC",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        for chat in chats.iter_mut() {
+            chat.messages = sqlx::query_as(
+                "SELECT id, message_id, role, content, fallback, created_at FROM buycycle_chatbot.messages WHERE chat_id = ? ORDER BY id ASC",
+            )
+            .bind(&chat.chat_id)
+            .fetch_all(&self.pool)
+            .await?;
+            chat.runs = sqlx::query_as(
+                "SELECT run_id, locale, model, prompt_tokens, completion_tokens, status, created_at FROM buycycle_chatbot.runs WHERE chat_id = ? ORDER BY id ASC",
+            )
+            .bind(&chat.chat_id)
+            .fetch_all(&self.pool)
+            .await?;
+            chat.tool_calls = sqlx::query_as(
+                "SELECT t.run_id, t.tool_name, t.arguments, t.output, t.error, t.created_at
+                 FROM buycycle_chatbot.tool_calls t
+                 JOIN buycycle_chatbot.runs r ON r.run_id = t.run_id
+                 WHERE r.chat_id = ? ORDER BY t.id ASC",
+            )
+            .bind(&chat.chat_id)
+            .fetch_all(&self.pool)
+            .await?;
+            chat.feedback = sqlx::query_as(
+                "SELECT f.message_id, f.rating, f.reason, f.comment, f.created_at
+                 FROM buycycle_chatbot.message_feedback f
+                 JOIN buycycle_chatbot.messages m ON m.id = f.message_id
+                 WHERE m.chat_id = ? ORDER BY f.id ASC",
+            )
+            .bind(&chat.chat_id)
+            .fetch_all(&self.pool)
+            .await?;
+            chat.summary = sqlx::query_scalar(
+                "SELECT summary FROM buycycle_chatbot.chat_summaries WHERE chat_id = ?",
+            )
+            .bind(&chat.chat_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        }
+        Ok(chats)
+    }
+    async fn get_inactive_chat_ids(
+        &self,
+        cutoff: DateTime<Utc>,
+        limit: u64,
+    ) -> Result<Vec<String>, AssistantError> {
+        Ok(sqlx::query_scalar(
+            "SELECT c.id FROM buycycle_chatbot.chats c
+             LEFT JOIN buycycle_chatbot.messages m ON m.chat_id = c.id
+             WHERE c.thread_deleted_at IS NULL
+             GROUP BY c.id, c.created_at
+             HAVING GREATEST(c.created_at, COALESCE(MAX(m.created_at), c.created_at)) < ?
+             LIMIT ?",
+        )
+        .bind(cutoff)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    async fn mark_thread_deleted(&self, chat_id: &str) -> Result<(), AssistantError> {
+        sqlx::query(
+            "UPDATE buycycle_chatbot.chats SET thread_deleted_at = CURRENT_TIMESTAMP WHERE id = ?",
+        )
+        .bind(chat_id)
+        .execute(&self.pool)
+        .await?;
+        // The summary is sent instead of the older messages, it ends with the chat
+        sqlx::query("DELETE FROM buycycle_chatbot.chat_summaries WHERE chat_id = ?")
+            .bind(chat_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+    async fn erase_user_data(
+        &self,
+        user_id: &str,
+        threads_deleted: u64,
+    ) -> Result<ErasureReport, AssistantError> {
+        let mut tx = self.pool.begin().await?;
+        let tool_calls = sqlx::query(
+            "DELETE FROM buycycle_chatbot.tool_calls WHERE run_id IN (
+                SELECT run_id FROM buycycle_chatbot.runs WHERE chat_id IN (
+                    SELECT id FROM buycycle_chatbot.chats WHERE user_id = ?))",
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        let feedback = sqlx::query(
+            "DELETE FROM buycycle_chatbot.message_feedback WHERE message_id IN (
+                SELECT id FROM buycycle_chatbot.messages WHERE chat_id IN (
+                    SELECT id FROM buycycle_chatbot.chats WHERE user_id = ?))",
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        let messages = sqlx::query(
+            "DELETE FROM buycycle_chatbot.messages WHERE chat_id IN (
+                SELECT id FROM buycycle_chatbot.chats WHERE user_id = ?)",
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        sqlx::query(
+            "DELETE FROM buycycle_chatbot.chat_summaries WHERE chat_id IN (
+                SELECT id FROM buycycle_chatbot.chats WHERE user_id = ?)",
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        let runs = sqlx::query(
+            "DELETE FROM buycycle_chatbot.runs WHERE chat_id IN (
+                SELECT id FROM buycycle_chatbot.chats WHERE user_id = ?)",
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        let chats = sqlx::query("DELETE FROM buycycle_chatbot.chats WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM buycycle_chatbot.run_quotas WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM buycycle_chatbot.rate_limit_buckets WHERE bucket = ?")
+            .bind(user_bucket(user_id))
+            .execute(&mut *tx)
+            .await?;
+        let report = ErasureReport {
+            chats,
+            messages,
+            runs,
+            tool_calls,
+            feedback,
+            threads_deleted,
+            threads_failed: Vec::new(),
+        };
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.erasures (user_hash, chats, messages, runs, tool_calls, feedback, threads_deleted) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(hash_user_id(user_id))
+        .bind(report.chats)
+        .bind(report.messages)
+        .bind(report.runs)
+        .bind(report.tool_calls)
+        .bind(report.feedback)
+        .bind(report.threads_deleted)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(report)
+    }
+    async fn save_message(
+        &self,
+        chat_id: &str,
+        role: &str,
+        content: &str,
+        message_id: Option<&str>,
+    ) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.messages (chat_id, role, content, message_id) VALUES (?, ?, ?, ?)",
+        )
+        .bind(chat_id)
+        .bind(role)
+        .bind(content)
+        .bind(message_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn save_fallback_message(
+        &self,
+        chat_id: &str,
+        content: &str,
+    ) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.messages (chat_id, role, content, fallback) VALUES (?, 'assistant', ?, TRUE)",
+        )
+        .bind(chat_id)
+        .bind(content)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn save_image_message(
+        &self,
+        chat_id: &str,
+        content: &str,
+        image_file_id: &str,
+    ) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.messages (chat_id, role, content, image_file_id) VALUES (?, 'user', ?, ?)",
+        )
+        .bind(chat_id)
+        .bind(content)
+        .bind(image_file_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn has_image(&self, user_id: &str, image_file_id: &str) -> Result<bool, AssistantError> {
+        let found: Option<i64> = sqlx::query_scalar(
+            "SELECT m.id FROM buycycle_chatbot.messages m JOIN buycycle_chatbot.chats c ON c.id = m.chat_id
+             WHERE m.image_file_id = ? AND c.user_id = ? LIMIT 1",
+        )
+        .bind(image_file_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(found.is_some())
+    }
+    async fn get_messages(&self, chat_id: &str) -> Result<Vec<LoggedMessage>, AssistantError> {
+        Ok(sqlx::query_as(
+            "SELECT id, message_id, role, content, fallback, created_at FROM buycycle_chatbot.messages WHERE chat_id = ? ORDER BY id ASC",
+        )
+        .bind(chat_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+    async fn get_chat_summary(&self, chat_id: &str) -> Result<Option<ChatSummary>, AssistantError> {
+        Ok(sqlx::query_as(
+            "SELECT summary, last_message_id FROM buycycle_chatbot.chat_summaries WHERE chat_id = ?",
+        )
+        .bind(chat_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+    async fn save_chat_summary(
+        &self,
+        chat_id: &str,
+        summary: &ChatSummary,
+    ) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.chat_summaries (chat_id, summary, last_message_id) VALUES (?, ?, ?)
+             ON DUPLICATE KEY UPDATE summary = VALUES(summary), last_message_id = VALUES(last_message_id)",
+        )
+        .bind(chat_id)
+        .bind(&summary.summary)
+        .bind(summary.last_message_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn get_assistant_message_id(
+        &self,
+        user_id: &str,
+        message_id: &str,
+    ) -> Result<Option<i64>, AssistantError> {
+        Ok(sqlx::query_scalar(
+            "SELECT m.id FROM buycycle_chatbot.messages m JOIN buycycle_chatbot.chats c ON c.id = m.chat_id
+             WHERE m.message_id = ? AND m.role = 'assistant' AND c.user_id = ? ORDER BY m.id DESC LIMIT 1",
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+    async fn delete_messages_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        batch_size: u64,
+    ) -> Result<u64, AssistantError> {
+        let mut deleted = 0;
+        loop {
+            let affected = sqlx::query(
+                "DELETE FROM buycycle_chatbot.messages WHERE created_at < ? ORDER BY id LIMIT ?",
+            )
+            .bind(cutoff)
+            .bind(batch_size)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+            deleted += affected;
+            if affected < batch_size {
+                return Ok(deleted);
+            }
+        }
+    }
+    async fn save_run(&self, record: &RunRecord) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.runs (run_id, chat_id, assistant_id, locale, model, prompt_tokens, completion_tokens, polls, tool_calls, status, latency_ms) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&record.run_id)
+        .bind(&record.chat_id)
+        .bind(&record.assistant_id)
+        .bind(&record.locale)
+        .bind(&record.model)
+        .bind(record.prompt_tokens)
+        .bind(record.completion_tokens)
+        .bind(record.polls)
+        .bind(record.tool_calls)
+        .bind(&record.status)
+        .bind(record.latency_ms)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn save_tool_call(&self, record: &ToolCallRecord) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.tool_calls (run_id, tool_call_id, tool_name, arguments, output, error, duration_ms, truncated) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&record.run_id)
+        .bind(&record.tool_call_id)
+        .bind(&record.tool_name)
+        .bind(&record.arguments)
+        .bind(&record.output)
+        .bind(&record.error)
+        .bind(record.duration_ms)
+        .bind(record.truncated)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn delete_tool_calls_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        batch_size: u64,
+    ) -> Result<u64, AssistantError> {
+        let mut deleted = 0;
+        loop {
+            let affected = sqlx::query(
+                "DELETE FROM buycycle_chatbot.tool_calls WHERE created_at < ? ORDER BY id LIMIT ?",
+            )
+            .bind(cutoff)
+            .bind(batch_size)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+            deleted += affected;
+            if affected < batch_size {
+                return Ok(deleted);
+            }
+        }
+    }
+    async fn save_feedback(
+        &self,
+        message_db_id: i64,
+        user_id: &str,
+        feedback: &MessageFeedback,
+    ) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.message_feedback (message_id, user_id, rating, reason, comment) VALUES (?, ?, ?, ?, ?)
+             ON DUPLICATE KEY UPDATE rating = VALUES(rating), reason = VALUES(reason), comment = VALUES(comment),
+                 created_at = CURRENT_TIMESTAMP",
+        )
+        .bind(message_db_id)
+        .bind(user_id)
+        .bind(&feedback.rating)
+        .bind(&feedback.reason)
+        .bind(&feedback.comment)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn get_negative_feedback(
+        &self,
+        since: NaiveDate,
+    ) -> Result<Vec<NegativeFeedback>, AssistantError> {
+        let mut feedback: Vec<NegativeFeedback> = sqlx::query_as(
+            "SELECT f.id AS feedback_id, f.reason, f.comment, f.created_at, m.id AS message_id, m.chat_id
+             FROM buycycle_chatbot.message_feedback f
+             JOIN buycycle_chatbot.messages m ON m.id = f.message_id
+             WHERE f.rating = 'down' AND f.created_at >= ?
+             ORDER BY f.created_at ASC",
+        )
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+        for entry in feedback.iter_mut() {
+            let mut conversation: Vec<LoggedMessage> = sqlx::query_as(
+                "SELECT id, message_id, role, content, fallback, created_at FROM buycycle_chatbot.messages
+                 WHERE chat_id = ? AND id <= ? ORDER BY id DESC LIMIT ?",
+            )
+            .bind(&entry.chat_id)
+            .bind(entry.message_id)
+            .bind(FEEDBACK_CONVERSATION_LENGTH)
+            .fetch_all(&self.pool)
+            .await?;
+            conversation.reverse();
+            entry.conversation = conversation;
+        }
+        Ok(feedback)
+    }
+    async fn compute_daily_stats(&self, day: NaiveDate) -> Result<DailyStats, AssistantError> {
+        let start = day.and_hms_opt(0, 0, 0).unwrap();
+        let end = start + chrono::Days::new(1);
+        let (runs, conversations, timeouts, errors): (i64, i64, i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COUNT(DISTINCT chat_id),
+                    CAST(COALESCE(SUM(status = 'timeout'), 0) AS SIGNED),
+                    CAST(COALESCE(SUM(status NOT IN ('completed', 'timeout')), 0) AS SIGNED)
+             FROM buycycle_chatbot.runs WHERE created_at >= ? AND created_at < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let new_chats: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM buycycle_chatbot.chats WHERE created_at >= ? AND created_at < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let (messages, chats_with_messages): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COUNT(DISTINCT chat_id) FROM buycycle_chatbot.messages
+             WHERE created_at >= ? AND created_at < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_one(&self.pool)
+        .await?;
+        let latencies: Vec<i64> = sqlx::query_scalar(
+            "SELECT latency_ms FROM buycycle_chatbot.runs WHERE created_at >= ? AND created_at < ?",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        let tool_usage: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT tool_name, COUNT(*) FROM buycycle_chatbot.tool_calls
+             WHERE created_at >= ? AND created_at < ? GROUP BY tool_name",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        let locales: Vec<(Option<String>, i64)> = sqlx::query_as(
+            "SELECT locale, COUNT(*) FROM buycycle_chatbot.runs
+             WHERE created_at >= ? AND created_at < ? GROUP BY locale",
+        )
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(daily_stats(
+            day,
+            DayCounts {
+                runs,
+                conversations,
+                timeouts,
+                errors,
+                new_chats,
+                messages,
+                chats_with_messages,
+                latencies,
+                tool_usage,
+                locales,
+            },
+        ))
+    }
+    async fn save_daily_stats(&self, stats: &DailyStats) -> Result<(), AssistantError> {
+        sqlx::query(
+            "INSERT INTO buycycle_chatbot.daily_stats (day, conversations, new_chats, messages, messages_per_chat, runs, timeouts, errors, median_latency_ms, tool_usage, languages)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON DUPLICATE KEY UPDATE conversations = VALUES(conversations), new_chats = VALUES(new_chats),
+                 messages = VALUES(messages), messages_per_chat = VALUES(messages_per_chat), runs = VALUES(runs),
+                 timeouts = VALUES(timeouts), errors = VALUES(errors), median_latency_ms = VALUES(median_latency_ms),
+                 tool_usage = VALUES(tool_usage), languages = VALUES(languages)",
+        )
+        .bind(stats.day)
+        .bind(stats.conversations)
+        .bind(stats.new_chats)
+        .bind(stats.messages)
+        .bind(stats.messages_per_chat)
+        .bind(stats.runs)
+        .bind(stats.timeouts)
+        .bind(stats.errors)
+        .bind(stats.median_latency_ms)
+        .bind(json!(stats.tool_usage).to_string())
+        .bind(json!(stats.languages).to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn get_daily_stats(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyStats>, AssistantError> {
+        let rows: Vec<DailyStatsRow> = sqlx::query_as(
+            "SELECT day, conversations, new_chats, messages, messages_per_chat, runs, timeouts, errors, median_latency_ms, tool_usage, languages
+             FROM buycycle_chatbot.daily_stats WHERE day >= ? AND day <= ? ORDER BY day ASC",
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(DailyStats::from).collect())
+    }
+    async fn take_rate_limit_token(
+        &self,
+        bucket: &str,
+        capacity: f64,
+        refill_per_sec: f64,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AssistantError> {
+        let now_ms = now.timestamp_millis();
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT IGNORE INTO buycycle_chatbot.rate_limit_buckets (bucket, tokens, updated_at_ms) VALUES (?, ?, ?)",
+        )
+        .bind(bucket)
+        .bind(capacity)
+        .bind(now_ms)
+        .execute(&mut *tx)
+        .await?;
+        let (tokens, updated_at_ms): (f64, i64) = sqlx::query_as(
+            "SELECT tokens, updated_at_ms FROM buycycle_chatbot.rate_limit_buckets WHERE bucket = ? FOR UPDATE",
+        )
+        .bind(bucket)
+        .fetch_one(&mut *tx)
+        .await?;
+        let (tokens, taken) = take_token(tokens, updated_at_ms, capacity, refill_per_sec, now_ms);
+        sqlx::query("UPDATE buycycle_chatbot.rate_limit_buckets SET tokens = ?, updated_at_ms = ? WHERE bucket = ?")
+            .bind(tokens)
+            .bind(now_ms.max(updated_at_ms))
+            .bind(bucket)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(taken)
+    }
+    async fn take_daily_run(
+        &self,
+        user_id: &str,
+        day: NaiveDate,
+        limit: i64,
+    ) -> Result<bool, AssistantError> {
+        sqlx::query(
+            "INSERT IGNORE INTO buycycle_chatbot.run_quotas (user_id, day, runs) VALUES (?, ?, 0)",
+        )
+        .bind(user_id)
+        .bind(day)
+        .execute(&self.pool)
+        .await?;
+        let counted = sqlx::query(
+            "UPDATE buycycle_chatbot.run_quotas SET runs = runs + 1
+             WHERE user_id = ? AND day = ? AND runs < ?",
+        )
+        .bind(user_id)
+        .bind(day)
+        .bind(limit)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(counted > 0)
+    }
+    async fn lease_run_slot(
+        &self,
+        slots: u64,
+        lease_id: &str,
+        expires_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, AssistantError> {
+        if self
+            .lease_free_run_slot(slots, lease_id, expires_at, now)
+            .await?
+        {
+            return Ok(true);
+        }
+        // The slots are created up to the limit when they are needed. All of them are
+        // created at once, so replicas creating them at the same time do not miss a slot
+        let created: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM buycycle_chatbot.run_slots WHERE slot < ?")
+                .bind(slots as i64)
+                .fetch_one(&self.pool)
+                .await?;
+        if created >= slots as i64 {
+            return Ok(false);
+        }
+        let values = vec!["(?)"; slots as usize].join(", ");
+        let sql = format!(
+            "INSERT IGNORE INTO buycycle_chatbot.run_slots (slot) VALUES {}",
+            values
+        );
+        let mut query = sqlx::query(&sql);
+        for slot in 0..slots {
+            query = query.bind(slot as i64);
+        }
+        query.execute(&self.pool).await?;
+        self.lease_free_run_slot(slots, lease_id, expires_at, now)
+            .await
+    }
+    async fn release_run_slot(&self, lease_id: &str) -> Result<(), AssistantError> {
+        sqlx::query("UPDATE buycycle_chatbot.run_slots SET lease_id = NULL, expires_at_ms = NULL WHERE lease_id = ?")
+            .bind(lease_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+    async fn delete_rate_limits_before(
+        &self,
+        cutoff: DateTime<Utc>,
+    ) -> Result<u64, AssistantError> {
+        let buckets =
+            sqlx::query("DELETE FROM buycycle_chatbot.rate_limit_buckets WHERE updated_at_ms < ?")
+                .bind(cutoff.timestamp_millis())
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        let quotas = sqlx::query("DELETE FROM buycycle_chatbot.run_quotas WHERE day < ?")
+            .bind(cutoff.date_naive())
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(buckets + quotas)
+    }
+}
@@ -1,16 +1,49 @@
-
+    // Session token of the requests, passed by the platform as ?token= or of a guest session.
+    // Without authentication on the server there is no guest session and no token is sent.
+    var sessionToken = new URLSearchParams(window.location.search).get('token');
+    var guestSession = JSON.parse(sessionStorage.getItem('guestSession') || 'null');
+    if (!sessionToken && guestSession && guestSession.expires_at > Date.now() / 1000) {
+        sessionToken = guestSession.token;
+        document.getElementById('user_id').value = guestSession.user_id;
+    } else if (!sessionToken) {
+        fetch('/v1/sessions/guest', { method: 'POST' })
+            .then(function(response) { return response.ok ? response.json() : null; })
+            .then(function(session) {
+                if (session) {
+                    sessionToken = session.token;
+                    sessionStorage.setItem('guestSession', JSON.stringify(session));
+                    document.getElementById('user_id').value = session.user_id;
+                }
+            });
+    }
+    function authHeaders(headers) {
+        if (sessionToken) {
+            headers['Authorization'] = 'Bearer ' + sessionToken;
+        }
+        return headers;
+    }
+    document.body.addEventListener('htmx:configRequest', function(event) {
+        authHeaders(event.detail.headers);
+    });
     document.getElementById('send').addEventListener('click', function() {
         sendMessage();
     });
     function sendMessage() {
         var userIdInput = document.getElementById('user_id');
         var messageInput = document.getElementById('message');
+        var imageInput = document.getElementById('image');
         var messagesList = document.getElementById('messages');
         var userMessage = document.createElement('li');
         var currentTime = new Date();
         var formattedUserText = messageInput.value.replace(/\n/g, '<br>');
         var userId = userIdInput.value || 'User';
         userMessage.innerHTML = '<strong>' + userId + ':</strong> ' + formattedUserText + '<br><small>Sent on: ' + currentTime.toLocaleString() + '</small>';
+        // Show a preview of the attached image
+        if (imageInput.files.length > 0) {
+            var preview = document.createElement('img');
+            preview.src = URL.createObjectURL(imageInput.files[0]);
+            userMessage.insertBefore(preview, userMessage.querySelector('small'));
+        }
         messagesList.appendChild(userMessage);
         // Add loading dots after the user message
         var loadingDots = document.createElement('div');
@@ -24,15 +57,119 @@
         messagesList.appendChild(loadingDots); // Append the dots to the messages list
         document.getElementById('chat-window').scrollTop = document.getElementById('chat-window').scrollHeight;
     }
+    // Render a message of the user or the assistant, including its images
+    function renderMessage(message) {
+        var messagesList = document.getElementById('messages');
+        var date = new Date(message.created_at * 1000);
+        var messageItem = document.createElement('li');
+        var sender = 'Assistant';
+        if (message.role === 'user') {
+            sender = document.getElementById('user_id').value || 'User';
+        } else {
+            messageItem.classList.add('assistant-message'); // Add class for Assistant's messages
+        }
+        // Format the response text as a list with bold links
+        var formattedText = message.text.replace(/\[(.*?)\]\((.*?)\)/g, function(match, text, url) {
+            return '<strong><a href="' + url + '" target="_blank">' + text + '</a></strong>';
+        });
+        formattedText = formattedText.replace(/(\d+\.\s)/g, '<br>$1'); // Add line breaks before list numbers
+        var images = (message.image_file_ids || []).map(function(fileId) {
+            return '<img data-file-id="' + encodeURIComponent(fileId) + '" alt="image">';
+        }).join('');
+        messageItem.innerHTML = '<strong>' + sender + ':</strong> ' + formattedText + images + '<br><small>Sent on: ' + date.toLocaleString() + '</small>';
+        loadImages(messageItem);
+        if (message.role === 'assistant' && message.id) {
+            messageItem.appendChild(createFeedbackButtons(message.id));
+        }
+        messagesList.appendChild(messageItem);
+    }
+    // Images are only served to the user of the chat, so they are loaded with the session
+    function loadImages(element) {
+        element.querySelectorAll('img[data-file-id]:not([src])').forEach(function(image) {
+            var userId = document.getElementById('user_id').value;
+            fetch('/images/' + image.dataset.fileId + '?user_id=' + encodeURIComponent(userId), { headers: authHeaders({}) })
+                .then(function(response) { return response.ok ? response.blob() : null; })
+                .then(function(blob) {
+                    if (blob) {
+                        image.src = URL.createObjectURL(blob);
+                    }
+                });
+        });
+    }
+    // Thumbs up and down buttons, thumbs down asks for a reason and an optional comment
+    function createFeedbackButtons(messageId) {
+        var feedback = document.createElement('div');
+        feedback.className = 'feedback';
+        feedback.innerHTML = '<button class="feedback-up" title="Helpful">&#x1F44D;</button>' +
+            '<button class="feedback-down" title="Not helpful">&#x1F44E;</button>' +
+            '<span class="feedback-details" style="display: none;">' +
+            '<select class="feedback-reason">' +
+            '<option value="incorrect">Incorrect</option>' +
+            '<option value="unhelpful">Not helpful</option>' +
+            '<option value="outdated">Outdated</option>' +
+            '<option value="inappropriate">Inappropriate</option>' +
+            '<option value="other">Other</option>' +
+            '</select>' +
+            '<input class="feedback-comment" type="text" placeholder="What was wrong? (optional)" />' +
+            '<button class="feedback-submit">Send</button>' +
+            '</span>';
+        feedback.querySelector('.feedback-up').addEventListener('click', function() {
+            sendFeedback(feedback, messageId, { rating: 'up' });
+        });
+        feedback.querySelector('.feedback-down').addEventListener('click', function() {
+            feedback.querySelector('.feedback-details').style.display = 'inline';
+        });
+        feedback.querySelector('.feedback-submit').addEventListener('click', function() {
+            var comment = feedback.querySelector('.feedback-comment').value;
+            sendFeedback(feedback, messageId, {
+                rating: 'down',
+                reason: feedback.querySelector('.feedback-reason').value,
+                comment: comment || null
+            });
+        });
+        return feedback;
+    }
+    function sendFeedback(feedback, messageId, body) {
+        // Ignored by the server if authentication is enabled, the user of the session is used then
+        body.user_id = document.getElementById('user_id').value;
+        fetch('/v1/messages/' + encodeURIComponent(messageId) + '/feedback', {
+            method: 'POST',
+            headers: authHeaders({ 'Content-Type': 'application/json' }),
+            body: JSON.stringify(body)
+        }).then(function(response) {
+            feedback.innerHTML = response.ok ? '<small>Thanks for your feedback!</small>' : '<small>Sorry, your feedback could not be saved.</small>';
+        });
+    }
+    // Pass the language of the browser as context of the conversation
+    if (/^[a-z]{2}(-[a-z]{2})?$/i.test(navigator.language)) {
+        document.getElementById('locale').value = navigator.language;
+    } else {
+        document.getElementById('locale').disabled = true;
+    }
     document.getElementById('message').addEventListener('keydown', function(event) {
         if (event.ctrlKey && event.key === 'Enter') {
             document.getElementById('send').click();
         }
     });
-    // Clear the message field after the htmx request is successfully processed
+    // Load the conversation history when the user identifier is entered
+    document.getElementById('user_id').addEventListener('change', function(event) {
+        var userId = event.target.value;
+        if (!userId) {
+            return;
+        }
+        fetch('/v1/history?user_id=' + encodeURIComponent(userId), { headers: authHeaders({}) })
+            .then(function(response) { return response.json(); })
+            .then(function(history) {
+                document.getElementById('messages').innerHTML = '';
+                (history.messages || []).forEach(renderMessage);
+                var chatWindow = document.getElementById('chat-window');
+                chatWindow.scrollTop = chatWindow.scrollHeight;
+            });
+    });
+    // Clear the message and image fields after the htmx request is successfully processed
     document.body.addEventListener('htmx:afterOnLoad', function(event) {
         document.getElementById('message').value = '';
-        document.getElementById('loading').style.display = 'none'; // Hide loading indicator
+        document.getElementById('image').value = '';
     });
     document.body.addEventListener('htmx:afterRequest', function(event) {
         // Remove loading dots after the response is received
@@ -40,23 +177,28 @@
         if (loadingDots) {
             loadingDots.remove();
         }
-        var xhr = event.detail.xhr;
-        var response = JSON.parse(xhr.responseText);
-        var chatWindow = document.getElementById('chat-window');
-        var messagesList = document.getElementById('messages');
-        var message = response.messages[0];
-        var date = new Date(message.created_at * 1000);
-        var assistantResponse = document.createElement('li');
-        assistantResponse.classList.add('assistant-message'); // Add class for Assistant's messages
-        // Format the response text as a list with bold links
-        var formattedResponseText = message.text.replace(/\[(.*?)\]\((.*?)\)/g, function(match, text, url) {
-            return '<strong><a href="' + url + '" target="_blank">' + text + '</a></strong>';
+        // Errors are returned as JSON and not swapped into the page
+        if (!event.detail.successful) {
+            var text = 'Sorry, your message could not be sent.';
+            try {
+                text = JSON.parse(event.detail.xhr.responseText).error || text;
+            } catch (e) {}
+            renderMessage({ created_at: Date.now() / 1000, role: 'error', text: text });
+            var chatWindow = document.getElementById('chat-window');
+            chatWindow.scrollTop = chatWindow.scrollHeight;
+        }
+    });
+    // The reply is rendered by the server, add the feedback buttons and show the local time
+    document.body.addEventListener('htmx:afterSwap', function(event) {
+        document.querySelectorAll('#messages li[data-message-id]').forEach(function(messageItem) {
+            if (messageItem.dataset.role === 'assistant' && !messageItem.querySelector('.feedback')) {
+                messageItem.appendChild(createFeedbackButtons(messageItem.dataset.messageId));
+            }
         });
-        formattedResponseText = formattedResponseText.replace(/(\d+\.\s)/g, '<br>$1'); // Add line breaks before list numbers
-        assistantResponse.innerHTML = '<strong>Assistant:</strong> ' + formattedResponseText + '<br><small>Sent on: ' + date.toLocaleString() + '</small>';
-        messagesList.appendChild(assistantResponse);
+        document.querySelectorAll('#messages time[datetime]').forEach(function(time) {
+            time.textContent = new Date(time.getAttribute('datetime')).toLocaleString();
+        });
+        loadImages(document.getElementById('messages'));
+        var chatWindow = document.getElementById('chat-window');
         chatWindow.scrollTop = chatWindow.scrollHeight;
     });
-    // Clear the message field after the htmx request is successfully processed
-    document.body.addEventListener('htmx:afterOnLoad', function(event) {
-    });
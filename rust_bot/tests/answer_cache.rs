@@ -0,0 +1,180 @@
+use rust_bot::assistant::{
+    AssistantChatForm, AssistantChatInput, ImageUpload, RunRecord, SimplifiedMessage,
+};
+use rust_bot::cache::{cacheable_answer, normalize_question, AnswerCache, CachedAnswer};
+use rust_bot::config::CacheConfig;
+use rust_bot::retrieval::{Embedder, HashingEmbedder};
+use std::sync::Arc;
+
+fn cache(similarity_threshold: f32, max_entries: u64) -> AnswerCache {
+    let config = CacheConfig {
+        enabled: true,
+        max_entries,
+        similarity_threshold,
+        ..Default::default()
+    };
+    let embedder: Option<Arc<dyn Embedder>> =
+        (similarity_threshold > 0.0).then(|| Arc::new(HashingEmbedder) as _);
+    AnswerCache::new(config, "instruction/instruction.txt".to_string(), embedder)
+        .expect("Failed to read the instruction file")
+}
+
+fn input(message: &str, locale: Option<&str>) -> AssistantChatInput {
+    AssistantChatInput {
+        form: AssistantChatForm {
+            user_id: "user_1".to_string(),
+            message: message.to_string(),
+            locale: locale.map(str::to_string),
+            ..Default::default()
+        },
+        image: None,
+    }
+}
+
+fn answer(text: &str) -> CachedAnswer {
+    CachedAnswer {
+        text: text.to_string(),
+        citations: Vec::new(),
+    }
+}
+
+async fn insert(cache: &AnswerCache, message: &str, locale: Option<&str>, text: &str) {
+    let key = cache.key(&input(message, locale)).await.unwrap();
+    cache.insert(key, answer(text)).await;
+}
+
+async fn lookup(cache: &AnswerCache, message: &str, locale: Option<&str>) -> Option<String> {
+    let key = cache.key(&input(message, locale)).await?;
+    cache.get(&key).await.map(|answer| answer.text)
+}
+
+#[test]
+fn test_normalize_question() {
+    assert_eq!(
+        normalize_question("  How does   SHIPPING work?! "),
+        "how does shipping work"
+    );
+    assert_eq!(
+        normalize_question("Wie läuft der Versand?"),
+        "wie läuft der versand"
+    );
+    assert_eq!(normalize_question("?!"), "");
+}
+
+#[tokio::test]
+async fn test_equal_questions() {
+    let cache = cache(0.0, 100);
+    insert(
+        &cache,
+        "How does shipping work?",
+        Some("en-US"),
+        "With DHL.",
+    )
+    .await;
+    assert_eq!(
+        lookup(&cache, "how does shipping work", Some("en-us")).await,
+        Some("With DHL.".to_string())
+    );
+    // Another locale or question is not answered from the cache
+    assert_eq!(
+        lookup(&cache, "How does shipping work?", Some("de-DE")).await,
+        None
+    );
+    assert_eq!(
+        lookup(&cache, "How do returns work?", Some("en-US")).await,
+        None
+    );
+    // Images and empty messages are never looked up
+    let mut with_image = input("How does shipping work?", Some("en-US"));
+    with_image.image = Some(ImageUpload {
+        file_name: "bike.png".to_string(),
+        bytes: b"\x89PNG\r\n\x1a\n".to_vec(),
+    });
+    assert!(cache.key(&with_image).await.is_none());
+    assert!(cache.key(&input(" ? ", Some("en-US"))).await.is_none());
+}
+
+#[tokio::test]
+async fn test_similar_questions() {
+    let cache = cache(0.7, 100);
+    insert(&cache, "How does shipping work?", None, "With DHL.").await;
+    insert(&cache, "How can I return a bike?", None, "Within 14 days.").await;
+    assert_eq!(
+        lookup(&cache, "how does the shipping work", None).await,
+        Some("With DHL.".to_string())
+    );
+    assert_eq!(
+        lookup(&cache, "Which frame size fits me?", None).await,
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_personal_data_is_not_cached() {
+    let cache = cache(0.7, 100);
+    // The question of a user with personal data is neither looked up nor cached
+    assert!(cache
+        .key(&input("Where is my order 123456?", None))
+        .await
+        .is_none());
+    // An answer repeating personal data is not given to a similar question
+    insert(
+        &cache,
+        "Where is my order?",
+        None,
+        "Your order goes to Hauptstraße 12.",
+    )
+    .await;
+    assert_eq!(lookup(&cache, "where is my order", None).await, None);
+    insert(&cache, "Where is my order?", None, "Please log in first.").await;
+    assert_eq!(
+        lookup(&cache, "where is my order", None).await,
+        Some("Please log in first.".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_eviction_and_invalidation() {
+    let cache = cache(0.0, 2);
+    insert(&cache, "first", None, "1").await;
+    insert(&cache, "second", None, "2").await;
+    insert(&cache, "third", None, "3").await;
+    // The oldest answer is evicted when the cache is full
+    assert_eq!(lookup(&cache, "first", None).await, None);
+    assert_eq!(lookup(&cache, "third", None).await, Some("3".to_string()));
+
+    let key = cache.key(&input("fourth", None)).await.unwrap();
+    cache.invalidate().await;
+    assert_eq!(lookup(&cache, "third", None).await, None);
+    // An answer of a request started before the invalidation is still cached
+    // if the instruction did not change
+    cache.insert(key, answer("4")).await;
+    assert_eq!(lookup(&cache, "fourth", None).await, Some("4".to_string()));
+}
+
+#[test]
+fn test_cacheable_answer() {
+    let message = |role: &str, text: &str| SimplifiedMessage {
+        id: None,
+        created_at: 0,
+        role: role.to_string(),
+        text: text.to_string(),
+        citations: Vec::new(),
+        image_file_ids: Vec::new(),
+    };
+    let mut record = RunRecord {
+        status: "completed".to_string(),
+        ..Default::default()
+    };
+    let reply = [message("assistant", "With DHL.")];
+    assert_eq!(
+        cacheable_answer(&record, &reply).map(|answer| answer.text),
+        Some("With DHL.".to_string())
+    );
+    // Answers depending on the account of the user are not cached
+    record.tool_calls = 1;
+    assert!(cacheable_answer(&record, &reply).is_none());
+    record.tool_calls = 0;
+    record.status = "timeout".to_string();
+    assert!(cacheable_answer(&record, &[message("error", "Sorry")]).is_none());
+}
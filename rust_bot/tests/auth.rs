@@ -0,0 +1,153 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use rust_bot::assistant::AssistantError;
+use rust_bot::auth::Authenticator;
+use rust_bot::config::AuthConfig;
+
+const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+fn authenticator(enabled: bool) -> Authenticator {
+    Authenticator::new(AuthConfig {
+        enabled,
+        secret: Some(SECRET.to_string()),
+        ..Default::default()
+    })
+}
+
+fn bearer(token: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+    );
+    headers
+}
+
+fn is_unauthorized<T>(result: Result<T, AssistantError>) -> bool {
+    matches!(result, Err(AssistantError::Unauthorized(_)))
+}
+
+#[test]
+fn test_signed_user_id() {
+    let auth = authenticator(true);
+    let token = auth
+        .sign_user_id("1234", chrono::Utc::now().timestamp())
+        .unwrap();
+    let session = auth.verify_token(&token).unwrap();
+    assert_eq!(session.user_id, "1234");
+    assert!(!session.guest());
+    // Another user ID or secret does not match the signature
+    let signature = token.split_once('.').unwrap().1;
+    assert!(is_unauthorized(
+        auth.verify_token(&format!("4321.{}", signature))
+    ));
+    let other = Authenticator::new(AuthConfig {
+        enabled: true,
+        secret: Some("another secret of at least 32 characters".to_string()),
+        ..Default::default()
+    });
+    assert!(is_unauthorized(other.verify_token(&token)));
+}
+
+#[test]
+fn test_signed_user_id_expires() {
+    let auth = authenticator(true);
+    let now = chrono::Utc::now().timestamp();
+    let ttl = AuthConfig::default().signed_user_id_ttl_secs as i64;
+    let token = auth.sign_user_id("1234", now - ttl + 60).unwrap();
+    assert_eq!(auth.verify_token(&token).unwrap().user_id, "1234");
+    for issued_at in [now - ttl - 3600, now + 3600] {
+        assert!(matches!(
+            auth.verify_token(&auth.sign_user_id("1234", issued_at).unwrap()),
+            Err(AssistantError::Unauthorized(message)) if message == "Session token expired"
+        ));
+    }
+    // The issued_at is signed, and user IDs signed without it are not accepted anymore
+    let (user_id, signature) = token.split_once('.').unwrap();
+    let signature = signature.split_once('.').unwrap().1;
+    assert!(is_unauthorized(
+        auth.verify_token(&format!("{}.{}.{}", user_id, now, signature))
+    ));
+    assert!(is_unauthorized(
+        auth.verify_token(&format!("{}.{}", user_id, signature))
+    ));
+}
+
+#[test]
+fn test_jwt() {
+    let auth = authenticator(true);
+    let now = chrono::Utc::now().timestamp();
+    let token = auth.issue_token("1234", now + 3600).unwrap();
+    assert_eq!(auth.verify_token(&token).unwrap().user_id, "1234");
+    assert!(matches!(
+        auth.verify_token(&auth.issue_token("1234", now - 3600).unwrap()),
+        Err(AssistantError::Unauthorized(message)) if message == "Session token expired"
+    ));
+    // Changed claims and unsigned tokens are rejected
+    let parts: Vec<&str> = token.split('.').collect();
+    let other = auth.issue_token("4321", now + 3600).unwrap();
+    let other_claims = other.split('.').nth(1).unwrap();
+    assert!(is_unauthorized(auth.verify_token(&format!(
+        "{}.{}.{}",
+        parts[0], other_claims, parts[2]
+    ))));
+    // {"alg":"none","typ":"JWT"}
+    let unsigned = format!("eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0.{}.", parts[1]);
+    assert!(is_unauthorized(auth.verify_token(&unsigned)));
+    assert!(is_unauthorized(auth.verify_token("not a token")));
+}
+
+#[test]
+fn test_authenticate_request() {
+    let auth = authenticator(true);
+    let token = auth
+        .sign_user_id("1234", chrono::Utc::now().timestamp())
+        .unwrap();
+    // The user ID of the session wins over the one sent by the client
+    assert_eq!(
+        auth.user_id(&bearer(&token), Some("4321".to_string()))
+            .unwrap(),
+        "1234"
+    );
+    assert!(is_unauthorized(
+        auth.user_id(&HeaderMap::new(), Some("4321".to_string()))
+    ));
+    // Without authentication the user ID of the client is used
+    let auth = authenticator(false);
+    assert!(auth.authenticate(&bearer(&token)).unwrap().is_none());
+    assert_eq!(
+        auth.user_id(&HeaderMap::new(), Some("4321".to_string()))
+            .unwrap(),
+        "4321"
+    );
+    assert!(matches!(
+        auth.user_id(&HeaderMap::new(), None),
+        Err(AssistantError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_guest_session() {
+    let auth = authenticator(true);
+    let guest = auth.guest_session().unwrap();
+    assert!(guest.user_id.starts_with("guest_"));
+    let session = auth.authenticate(&bearer(&guest.token)).unwrap().unwrap();
+    assert_eq!(session.user_id, guest.user_id);
+    assert!(session.guest());
+    assert_ne!(auth.guest_session().unwrap().user_id, guest.user_id);
+
+    let without_guests = Authenticator::new(AuthConfig {
+        enabled: true,
+        secret: Some(SECRET.to_string()),
+        guest_sessions: false,
+        ..Default::default()
+    });
+    assert!(matches!(
+        without_guests.guest_session(),
+        Err(AssistantError::NotFound(_))
+    ));
+    assert!(is_unauthorized(without_guests.verify_token(&guest.token)));
+    assert!(matches!(
+        authenticator(false).guest_session(),
+        Err(AssistantError::NotFound(_))
+    ));
+}
@@ -0,0 +1,63 @@
+// Fixtures shared by the integration tests, every test crate uses a part of them
+#![allow(dead_code)]
+
+use rust_bot::store::{connect, ConversationStore};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A unique path in the temporary folder. The file or folder at the path is deleted when
+/// it is dropped, with the journal files of a SQLite database.
+pub struct TempPath(PathBuf);
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        TempPath(std::env::temp_dir().join(format!(
+            "{}_{}_{}",
+            name,
+            std::process::id(),
+            chrono::Utc::now().timestamp_nanos_opt().unwrap()
+        )))
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+    /// The URL of a SQLite database at the path.
+    pub fn sqlite_url(&self) -> String {
+        format!("sqlite://{}", self.0.display())
+    }
+}
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = std::fs::remove_dir_all(&self.0);
+            return;
+        }
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let mut file = self.0.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+/// A migrated conversation store on a fresh SQLite database, deleted when it is dropped.
+pub struct TestStore {
+    pub store: Arc<dyn ConversationStore>,
+    path: TempPath,
+}
+impl TestStore {
+    pub async fn new(name: &str) -> Self {
+        let path = TempPath::new(name);
+        let store = connect(&path.sqlite_url())
+            .await
+            .expect("Failed to connect to store");
+        store.migrate().await.expect("Failed to apply migrations");
+        TestStore { store, path }
+    }
+}
+impl Deref for TestStore {
+    type Target = dyn ConversationStore;
+    fn deref(&self) -> &Self::Target {
+        self.store.as_ref()
+    }
+}
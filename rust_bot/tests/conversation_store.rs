@@ -0,0 +1,518 @@
+mod common;
+
+use chrono::{Days, Utc};
+use common::TempPath;
+use rust_bot::assistant::{ChatSummary, MessageFeedback, RunRecord, ToolCallRecord, LOG};
+use rust_bot::redaction::Redactor;
+use rust_bot::store::{connect, user_bucket, ConversationStore};
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Unique suffix for IDs, so the tests can share a MySQL database
+fn unique(prefix: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{}_{}_{}", prefix, std::process::id(), nanos)
+}
+
+// The same tests run against every backend: a fresh SQLite database and, if
+// TEST_DATABASE_URL_MYSQL is set, a disposable MySQL database. The SQLite database is
+// deleted with the returned path.
+async fn stores() -> (Vec<Arc<dyn ConversationStore>>, TempPath) {
+    let path = TempPath::new("conversation_store");
+    let mut urls = vec![path.sqlite_url()];
+    if let Ok(url) = std::env::var("TEST_DATABASE_URL_MYSQL") {
+        urls.push(url);
+    }
+    let mut stores = Vec::new();
+    for url in urls {
+        let store = connect(&url).await.expect("Failed to connect to store");
+        store.migrate().await.expect("Failed to apply migrations");
+        stores.push(store);
+    }
+    (stores, path)
+}
+
+fn run_record(chat_id: &str, run_id: &str, status: &str, latency_ms: i64) -> RunRecord {
+    RunRecord {
+        run_id: Some(run_id.to_string()),
+        chat_id: Some(chat_id.to_string()),
+        assistant_id: "asst_test".to_string(),
+        locale: Some("en-CA".to_string()),
+        model: Some("gpt-4o".to_string()),
+        prompt_tokens: Some(100),
+        completion_tokens: Some(20),
+        polls: 3,
+        tool_calls: 1,
+        status: status.to_string(),
+        latency_ms,
+    }
+}
+
+fn tool_call_record(run_id: &str) -> ToolCallRecord {
+    ToolCallRecord {
+        run_id: run_id.to_string(),
+        tool_call_id: unique("call"),
+        tool_name: "get_orders".to_string(),
+        arguments: "{}".to_string(),
+        output: Some("[]".to_string()),
+        error: None,
+        duration_ms: 12,
+        truncated: false,
+    }
+}
+
+#[tokio::test]
+async fn test_unsupported_url() {
+    assert!(connect("postgres://localhost/chatbot").await.is_err());
+}
+
+#[tokio::test]
+async fn test_chats() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let user_id = unique("user");
+        assert_eq!(store.get_chat_id(&user_id).await.unwrap(), None);
+        let chat_id = unique("thread");
+        store.save_chat_id(&user_id, &chat_id).await.unwrap();
+        assert_eq!(
+            store.get_chat_id(&user_id).await.unwrap(),
+            Some(chat_id.clone())
+        );
+        // Chats with a deleted thread are not continued
+        store.mark_thread_deleted(&chat_id).await.unwrap();
+        assert_eq!(store.get_chat_id(&user_id).await.unwrap(), None);
+    }
+}
+
+#[tokio::test]
+async fn test_images() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let user_id = unique("user");
+        let chat_id = unique("thread");
+        let file_id = unique("file-");
+        store.save_chat_id(&user_id, &chat_id).await.unwrap();
+        store
+            .save_image_message(&chat_id, "Is this frame damaged?", &file_id)
+            .await
+            .unwrap();
+        assert!(store.has_image(&user_id, &file_id).await.unwrap());
+        // Images of other users and unknown files are not found
+        assert!(!store.has_image(&unique("user"), &file_id).await.unwrap());
+        assert!(!store.has_image(&user_id, "file-unknown").await.unwrap());
+        let messages = store.get_messages(&chat_id).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, "user");
+    }
+}
+
+#[tokio::test]
+async fn test_messages_and_feedback() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let user_id = unique("user");
+        let chat_id = unique("thread");
+        let message_id = unique("msg");
+        store.save_chat_id(&user_id, &chat_id).await.unwrap();
+        store
+            .save_message(&chat_id, "user", "How does shipping work?", None)
+            .await
+            .unwrap();
+        store
+            .save_message(&chat_id, "assistant", "We ship by DHL.", Some(&message_id))
+            .await
+            .unwrap();
+        let message_db_id = store
+            .get_assistant_message_id(&user_id, &message_id)
+            .await
+            .unwrap()
+            .expect("Assistant message not found");
+        assert_eq!(
+            store
+                .get_assistant_message_id(&user_id, "msg_unknown")
+                .await
+                .unwrap(),
+            None
+        );
+        // Messages in the chats of other users are not found
+        assert_eq!(
+            store
+                .get_assistant_message_id(&unique("user"), &message_id)
+                .await
+                .unwrap(),
+            None
+        );
+        let feedback = MessageFeedback {
+            user_id: None,
+            rating: "down".to_string(),
+            reason: Some("outdated".to_string()),
+            comment: Some("We ship by GLS".to_string()),
+        };
+        store
+            .save_feedback(message_db_id, &user_id, &feedback)
+            .await
+            .unwrap();
+        // A new rating of the user replaces the previous one
+        let feedback = MessageFeedback {
+            user_id: None,
+            rating: "down".to_string(),
+            reason: Some("incorrect".to_string()),
+            comment: Some("We ship by UPS".to_string()),
+        };
+        store
+            .save_feedback(message_db_id, &user_id, &feedback)
+            .await
+            .unwrap();
+        let yesterday = Utc::now().date_naive() - Days::new(1);
+        let negative = store.get_negative_feedback(yesterday).await.unwrap();
+        let entries: Vec<_> = negative
+            .iter()
+            .filter(|entry| entry.message_id == message_db_id)
+            .collect();
+        assert_eq!(entries.len(), 1);
+        let entry = entries[0];
+        assert_eq!(entry.chat_id, chat_id);
+        assert_eq!(entry.comment.as_deref(), Some("We ship by UPS"));
+        let roles: Vec<&str> = entry.conversation.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant"]);
+    }
+}
+
+#[tokio::test]
+async fn test_negative_feedback_is_revealed() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let redactor = Redactor::new(true, Some(vec![7u8; 32])).unwrap();
+        let log = LOG::new(store.clone(), Arc::new(redactor));
+        let user_id = unique("user");
+        let chat_id = unique("thread");
+        let message_id = unique("msg");
+        log.save_chat_id(&user_id, &chat_id).await.unwrap();
+        log.save_message_to_db(&chat_id, "user", "Where is my order 123456?", None)
+            .await
+            .unwrap();
+        log.save_message_to_db(&chat_id, "assistant", "It is lost.", Some(&message_id))
+            .await
+            .unwrap();
+        let message_db_id = log
+            .get_assistant_message_id(&user_id, &message_id)
+            .await
+            .unwrap()
+            .expect("Assistant message not found");
+        let feedback = MessageFeedback {
+            user_id: None,
+            rating: "down".to_string(),
+            reason: None,
+            comment: Some("Write me at jane.doe@example.com".to_string()),
+        };
+        log.save_feedback(message_db_id, &user_id, &feedback)
+            .await
+            .unwrap();
+        // The personal data is stored encrypted and revealed for the review
+        let yesterday = Utc::now().date_naive() - Days::new(1);
+        let stored = store.get_negative_feedback(yesterday).await.unwrap();
+        let stored = stored
+            .iter()
+            .find(|entry| entry.message_id == message_db_id)
+            .unwrap();
+        assert!(stored.comment.as_deref().unwrap().contains("[EMAIL:enc:"));
+        let negative = log.get_negative_feedback(yesterday).await.unwrap();
+        let entry = negative
+            .iter()
+            .find(|entry| entry.message_id == message_db_id)
+            .unwrap();
+        assert_eq!(
+            entry.comment.as_deref(),
+            Some("Write me at jane.doe@example.com")
+        );
+        assert_eq!(entry.conversation[0].content, "Where is my order 123456?");
+    }
+}
+
+#[tokio::test]
+async fn test_chat_summaries() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let user_id = unique("user");
+        let chat_id = unique("chat");
+        store.save_chat_id(&user_id, &chat_id).await.unwrap();
+        assert!(store.get_chat_summary(&chat_id).await.unwrap().is_none());
+        for (summary, last_message_id) in
+            [("Looks for a gravel bike", 2), ("Looks for a size M", 5)]
+        {
+            let summary = ChatSummary {
+                summary: summary.to_string(),
+                last_message_id,
+            };
+            store.save_chat_summary(&chat_id, &summary).await.unwrap();
+        }
+        // Saving again replaces the summary
+        let summary = store.get_chat_summary(&chat_id).await.unwrap().unwrap();
+        assert_eq!(summary.summary, "Looks for a size M");
+        assert_eq!(summary.last_message_id, 5);
+        let chats = store.get_user_chats(&user_id).await.unwrap();
+        assert_eq!(chats[0].summary.as_deref(), Some("Looks for a size M"));
+        // The summary ends with the chat
+        store.mark_thread_deleted(&chat_id).await.unwrap();
+        assert!(store.get_chat_summary(&chat_id).await.unwrap().is_none());
+        store.save_chat_summary(&chat_id, &summary).await.unwrap();
+        store.erase_user_data(&user_id, 0).await.unwrap();
+        assert!(store.get_chat_summary(&chat_id).await.unwrap().is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_user_export_and_erasure() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let user_id = unique("user");
+        let chat_id = unique("thread");
+        let run_id = unique("run");
+        let message_id = unique("msg");
+        store.save_chat_id(&user_id, &chat_id).await.unwrap();
+        store
+            .save_message(&chat_id, "user", "Where is my order?", None)
+            .await
+            .unwrap();
+        store
+            .save_message(&chat_id, "assistant", "It was shipped.", Some(&message_id))
+            .await
+            .unwrap();
+        store
+            .save_run(&run_record(&chat_id, &run_id, "completed", 1000))
+            .await
+            .unwrap();
+        store
+            .save_tool_call(&tool_call_record(&run_id))
+            .await
+            .unwrap();
+        let message_db_id = store
+            .get_assistant_message_id(&user_id, &message_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let feedback = MessageFeedback {
+            user_id: None,
+            rating: "up".to_string(),
+            reason: None,
+            comment: None,
+        };
+        store
+            .save_feedback(message_db_id, &user_id, &feedback)
+            .await
+            .unwrap();
+        // The quota and the bucket of the user are used up
+        let today = Utc::now().date_naive();
+        let now = Utc::now();
+        assert!(store.take_daily_run(&user_id, today, 1).await.unwrap());
+        assert!(store
+            .take_rate_limit_token(&user_bucket(&user_id), 1.0, 0.0, now)
+            .await
+            .unwrap());
+
+        let chats = store.get_user_chats(&user_id).await.unwrap();
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].chat_id, chat_id);
+        assert_eq!(chats[0].messages.len(), 2);
+        assert_eq!(chats[0].runs.len(), 1);
+        assert_eq!(chats[0].tool_calls.len(), 1);
+        assert_eq!(chats[0].feedback.len(), 1);
+
+        let report = store.erase_user_data(&user_id, 1).await.unwrap();
+        assert_eq!(report.chats, 1);
+        assert_eq!(report.messages, 2);
+        assert_eq!(report.runs, 1);
+        assert_eq!(report.tool_calls, 1);
+        assert_eq!(report.feedback, 1);
+        assert_eq!(report.threads_deleted, 1);
+        assert!(store.get_user_chats(&user_id).await.unwrap().is_empty());
+        // The quota and the bucket of the user are deleted with the data
+        assert!(store.take_daily_run(&user_id, today, 1).await.unwrap());
+        assert!(store
+            .take_rate_limit_token(&user_bucket(&user_id), 1.0, 0.0, now)
+            .await
+            .unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_retention() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let user_id = unique("user");
+        let chat_id = unique("thread");
+        let run_id = unique("run");
+        store.save_chat_id(&user_id, &chat_id).await.unwrap();
+        for text in ["first", "second", "third"] {
+            store
+                .save_message(&chat_id, "user", text, None)
+                .await
+                .unwrap();
+        }
+        store
+            .save_tool_call(&tool_call_record(&run_id))
+            .await
+            .unwrap();
+        let past = Utc::now() - Days::new(1);
+        let future = Utc::now() + Days::new(1);
+        let inactive = store.get_inactive_chat_ids(past, 100).await.unwrap();
+        assert!(!inactive.contains(&chat_id));
+        let inactive = store.get_inactive_chat_ids(future, 100_000).await.unwrap();
+        assert!(inactive.contains(&chat_id));
+        // A batch size of 1 deletes the messages with several statements
+        assert_eq!(store.delete_messages_before(past, 1).await.unwrap(), 0);
+        assert!(store.delete_messages_before(future, 1).await.unwrap() >= 3);
+        assert!(store.delete_tool_calls_before(future, 1).await.unwrap() >= 1);
+        let chats = store.get_user_chats(&user_id).await.unwrap();
+        assert!(chats[0].messages.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let bucket = unique("user");
+        let now = Utc::now();
+        // A new bucket is full, 2 tokens refilled with 1 per second
+        for _ in 0..2 {
+            assert!(store
+                .take_rate_limit_token(&bucket, 2.0, 1.0, now)
+                .await
+                .unwrap());
+        }
+        assert!(!store
+            .take_rate_limit_token(&bucket, 2.0, 1.0, now)
+            .await
+            .unwrap());
+        let later = now + Duration::from_millis(1500);
+        assert!(store
+            .take_rate_limit_token(&bucket, 2.0, 1.0, later)
+            .await
+            .unwrap());
+        assert!(!store
+            .take_rate_limit_token(&bucket, 2.0, 1.0, later)
+            .await
+            .unwrap());
+
+        let user_id = unique("user");
+        let today = now.date_naive();
+        assert!(store.take_daily_run(&user_id, today, 2).await.unwrap());
+        assert!(store.take_daily_run(&user_id, today, 2).await.unwrap());
+        // A run over the quota is not counted
+        assert!(!store.take_daily_run(&user_id, today, 2).await.unwrap());
+        assert!(store.take_daily_run(&user_id, today, 3).await.unwrap());
+        let yesterday = today - Days::new(1);
+        assert!(store.take_daily_run(&user_id, yesterday, 1).await.unwrap());
+        assert!(!store.take_daily_run(&user_id, yesterday, 1).await.unwrap());
+
+        // The buckets updated and the days before the cutoff are deleted
+        let cutoff = later + Duration::from_secs(1);
+        assert!(store.delete_rate_limits_before(cutoff).await.unwrap() >= 2);
+        assert!(store.take_daily_run(&user_id, yesterday, 1).await.unwrap());
+        assert!(!store.take_daily_run(&user_id, today, 3).await.unwrap());
+        assert!(store.take_daily_run(&user_id, today, 4).await.unwrap());
+        assert!(store
+            .take_rate_limit_token(&bucket, 2.0, 1.0, later)
+            .await
+            .unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_run_slots() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let (first, second) = (unique("lease"), unique("lease"));
+        let now = Utc::now();
+        let expires_at = now + Duration::from_secs(60);
+        assert!(store
+            .lease_run_slot(1, &first, expires_at, now)
+            .await
+            .unwrap());
+        assert!(!store
+            .lease_run_slot(1, &second, expires_at, now)
+            .await
+            .unwrap());
+        // Released and expired slots are leased again
+        store.release_run_slot(&first).await.unwrap();
+        assert!(store
+            .lease_run_slot(1, &second, expires_at, now)
+            .await
+            .unwrap());
+        let after_expiry = expires_at + Duration::from_secs(1);
+        assert!(store
+            .lease_run_slot(1, &first, after_expiry, after_expiry)
+            .await
+            .unwrap());
+        store.release_run_slot(&first).await.unwrap();
+        store.release_run_slot(&second).await.unwrap();
+
+        // The slots up to a higher limit are created when they are needed
+        let leases: Vec<String> = (0..3).map(|_| unique("lease")).collect();
+        for lease in &leases {
+            assert!(store
+                .lease_run_slot(3, lease, expires_at, now)
+                .await
+                .unwrap());
+        }
+        assert!(!store
+            .lease_run_slot(3, &first, expires_at, now)
+            .await
+            .unwrap());
+        for lease in &leases {
+            store.release_run_slot(lease).await.unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_daily_stats() {
+    let (stores, _path) = stores().await;
+    for store in stores {
+        let chat_id = unique("thread");
+        store.save_chat_id(&unique("user"), &chat_id).await.unwrap();
+        store
+            .save_message(&chat_id, "user", "Hello", None)
+            .await
+            .unwrap();
+        let run_id = unique("run");
+        store
+            .save_run(&run_record(&chat_id, &run_id, "completed", 1000))
+            .await
+            .unwrap();
+        store
+            .save_run(&run_record(&chat_id, &unique("run"), "timeout", 3000))
+            .await
+            .unwrap();
+        store
+            .save_tool_call(&tool_call_record(&run_id))
+            .await
+            .unwrap();
+
+        let today = Utc::now().date_naive();
+        let stats = store.compute_daily_stats(today).await.unwrap();
+        assert!(stats.runs >= 2);
+        assert!(stats.conversations >= 1);
+        assert!(stats.new_chats >= 1);
+        assert!(stats.messages >= 1);
+        assert!(stats.timeouts >= 1);
+        assert!(stats.median_latency_ms.is_some());
+        assert!(stats.tool_usage.get("get_orders").copied().unwrap_or(0) >= 1);
+        assert!(stats.languages.get("en").copied().unwrap_or(0) >= 2);
+
+        // Saving the stats of a day twice replaces them
+        store.save_daily_stats(&stats).await.unwrap();
+        store.save_daily_stats(&stats).await.unwrap();
+        let saved = store.get_daily_stats(today, today).await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].runs, stats.runs);
+        assert_eq!(saved[0].median_latency_ms, stats.median_latency_ms);
+        assert_eq!(saved[0].tool_usage, stats.tool_usage);
+        assert_eq!(saved[0].languages, stats.languages);
+    }
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use axum::http::{HeaderMap, HeaderValue};
+use common::TestStore;
+use rust_bot::assistant::AssistantError;
+use rust_bot::config::{Config, RateLimitConfig};
+use rust_bot::limits::{client_ip, RateLimiter};
+use std::net::{IpAddr, SocketAddr};
+
+// A limiter on a fresh SQLite database, deleted with the store
+async fn rate_limiter(rate_limit: RateLimitConfig) -> (RateLimiter, TestStore) {
+    let store = TestStore::new("rate_limits").await;
+    let config = Config {
+        rate_limit,
+        ..Default::default()
+    };
+    (
+        RateLimiter::from_config(&config, store.store.clone()),
+        store,
+    )
+}
+
+fn too_many_requests<T>(result: Result<T, AssistantError>, message: &str) -> bool {
+    matches!(result, Err(AssistantError::TooManyRequests(m)) if m.contains(message))
+}
+
+#[tokio::test]
+async fn test_disabled() {
+    let (limiter, _store) = rate_limiter(RateLimitConfig::default()).await;
+    assert!(!limiter.enabled());
+    for _ in 0..100 {
+        assert!(limiter.admit("user_1").await.unwrap().is_none());
+        limiter.check_ip("10.0.0.1".parse().ok()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_user_limit_and_quota() {
+    let (limiter, _store) = rate_limiter(RateLimitConfig {
+        enabled: true,
+        user_burst: 2,
+        ..Default::default()
+    })
+    .await;
+    assert!(limiter.admit("user_1").await.unwrap().is_some());
+    assert!(limiter.admit("user_1").await.unwrap().is_some());
+    assert!(too_many_requests(
+        limiter.admit("user_1").await,
+        "Too many messages"
+    ));
+    // Other users have their own bucket and quota
+    assert!(limiter.admit("user_2").await.unwrap().is_some());
+
+    let (limiter, _store) = rate_limiter(RateLimitConfig {
+        enabled: true,
+        daily_runs_per_user: 1,
+        ..Default::default()
+    })
+    .await;
+    assert!(limiter.admit("user_1").await.unwrap().is_some());
+    assert!(too_many_requests(
+        limiter.admit("user_1").await,
+        "Daily message limit"
+    ));
+}
+
+#[tokio::test]
+async fn test_busy_runs_are_not_counted() {
+    let (limiter, _store) = rate_limiter(RateLimitConfig {
+        enabled: true,
+        user_burst: 1,
+        daily_runs_per_user: 1,
+        max_concurrent_runs: 1,
+        ..Default::default()
+    })
+    .await;
+    let permit = limiter.admit("user_1").await.unwrap();
+    // Rejected because the assistant is busy, the token and quota of the user are kept
+    assert!(too_many_requests(limiter.admit("user_2").await, "busy"));
+    drop(permit);
+    let mut permit = None;
+    for _ in 0..50 {
+        if let Ok(admitted) = limiter.admit("user_2").await {
+            permit = admitted;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(permit.is_some());
+    drop(permit);
+    // A limited user does not hold the slot
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(too_many_requests(
+        limiter.admit("user_2").await,
+        "Too many messages"
+    ));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(limiter.admit("user_3").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_concurrent_runs() {
+    let (limiter, _store) = rate_limiter(RateLimitConfig {
+        enabled: true,
+        max_concurrent_runs: 1,
+        ..Default::default()
+    })
+    .await;
+    let permit = limiter.admit("user_1").await.unwrap();
+    assert!(too_many_requests(limiter.admit("user_2").await, "busy"));
+    // The slot is released in a task spawned when the permit is dropped
+    drop(permit);
+    let mut admitted = false;
+    for _ in 0..50 {
+        if limiter.admit("user_2").await.is_ok() {
+            admitted = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(admitted);
+}
+
+#[tokio::test]
+async fn test_ip_limit() {
+    let (limiter, _store) = rate_limiter(RateLimitConfig {
+        enabled: true,
+        ip_burst: 1,
+        ..Default::default()
+    })
+    .await;
+    let ip: Option<IpAddr> = "10.0.0.1".parse().ok();
+    limiter.check_ip(ip).await.unwrap();
+    assert!(too_many_requests(limiter.check_ip(ip).await, "IP address"));
+    limiter.check_ip("10.0.0.2".parse().ok()).await.unwrap();
+    // Requests without an address are not limited by it
+    limiter.check_ip(None).await.unwrap();
+    limiter.check_ip(None).await.unwrap();
+}
+
+#[test]
+fn test_client_ip() {
+    let peer: SocketAddr = "192.168.1.10:50000".parse().unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "x-forwarded-for",
+        HeaderValue::from_static("1.2.3.4, 203.0.113.7"),
+    );
+    // The last address is the one the proxy appended, the others are set by the client
+    assert_eq!(
+        client_ip(&headers, Some(peer), true),
+        "203.0.113.7".parse().ok()
+    );
+    assert_eq!(client_ip(&headers, Some(peer), false), Some(peer.ip()));
+    headers.insert("x-forwarded-for", HeaderValue::from_static("unknown"));
+    assert_eq!(client_ip(&headers, Some(peer), true), Some(peer.ip()));
+    assert_eq!(client_ip(&HeaderMap::new(), None, true), None);
+}
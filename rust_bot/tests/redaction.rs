@@ -0,0 +1,101 @@
+use rust_bot::redaction::Redactor;
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn redactor_with_key() -> Redactor {
+    let key = rust_bot::redaction::decode_hex(KEY).expect("Invalid test key");
+    Redactor::new(true, Some(key)).expect("Failed to create redactor")
+}
+
+#[test]
+fn test_mask_personal_data() {
+    let redactor = Redactor::new(true, None).unwrap();
+    let masked = redactor.mask(
+        "Mail me at jane.doe@example.com or call +49 170 1234567, my order 123456 is late. \
+        IBAN DE89 3704 0044 0532 0130 00, I live at Hauptstraße 12.",
+    );
+    assert_eq!(
+        masked,
+        "Mail me at [EMAIL] or call [PHONE], my order [ORDER] is late. \
+        IBAN [IBAN], I live at [ADDRESS]."
+    );
+}
+
+#[test]
+fn test_addresses() {
+    let redactor = Redactor::new(true, None).unwrap();
+    for (text, masked) in [
+        ("Send it to Lindenweg 4a", "Send it to [ADDRESS]"),
+        ("I live Am Ring 5 in Köln", "I live [ADDRESS] in Köln"),
+        (
+            "Ship to 221 Baker Street, London",
+            "Ship to [ADDRESS], London",
+        ),
+    ] {
+        assert_eq!(redactor.mask(text), masked);
+    }
+}
+
+#[test]
+fn test_words_like_street_suffixes_are_kept() {
+    let redactor = Redactor::new(true, None).unwrap();
+    for text in [
+        "Can you bring 2 bikes to the shop?",
+        "Bring 2 helmets please",
+        "The bike was ridden during 2 weeks.",
+        "I need 2 bikes to drive to the race.",
+        "My way 3 times a week is 20 km.",
+    ] {
+        assert_eq!(redactor.mask(text), text);
+    }
+}
+
+#[test]
+fn test_text_without_personal_data_is_kept() {
+    let redactor = Redactor::new(true, None).unwrap();
+    let text = "I am looking for a gravel bike for 1500 EUR in size 56.";
+    assert_eq!(redactor.mask(text), text);
+    assert_eq!(redactor.protect(text), text);
+}
+
+#[test]
+fn test_protect_and_reveal() {
+    let redactor = redactor_with_key();
+    let text = "My email is jane.doe@example.com and my order number is 987654.";
+    let protected = redactor.protect(text);
+    assert!(!protected.contains("jane.doe@example.com"));
+    assert!(!protected.contains("987654"));
+    assert!(protected.contains("[EMAIL:enc:"));
+    assert!(protected.contains("[ORDER:enc:"));
+    assert_eq!(redactor.reveal(&protected), text);
+}
+
+#[test]
+fn test_protect_without_key_masks() {
+    let redactor = Redactor::new(true, None).unwrap();
+    let protected = redactor.protect("Contact jane.doe@example.com");
+    assert_eq!(protected, "Contact [EMAIL]");
+    assert_eq!(redactor.reveal(&protected), protected);
+}
+
+#[test]
+fn test_reveal_with_other_key_keeps_tokens() {
+    let protected = redactor_with_key().protect("Contact jane.doe@example.com");
+    let other_key = vec![7u8; 32];
+    let other = Redactor::new(true, Some(other_key)).unwrap();
+    assert_eq!(other.reveal(&protected), protected);
+}
+
+#[test]
+fn test_disabled_redaction() {
+    let redactor = Redactor::new(false, None).unwrap();
+    let text = "Contact jane.doe@example.com";
+    assert_eq!(redactor.mask(text), text);
+}
+
+#[test]
+fn test_invalid_key_length() {
+    assert!(Redactor::new(true, Some(vec![0u8; 16])).is_err());
+    assert!(rust_bot::redaction::decode_hex("abc").is_err());
+    assert!(rust_bot::redaction::decode_hex("zz").is_err());
+}
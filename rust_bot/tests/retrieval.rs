@@ -0,0 +1,131 @@
+mod common;
+
+use axum::async_trait;
+use common::TempPath;
+use rust_bot::assistant::AssistantError;
+use rust_bot::config::{EmbedderKind, RetrievalConfig};
+use rust_bot::retrieval::{
+    chunk_document, load_documents, Document, Embedder, HashingEmbedder, RetrievalIndex,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Counts the embedded texts, so the tests can check which chunks were embedded again
+#[derive(Default)]
+struct CountingEmbedder {
+    embedded: AtomicUsize,
+}
+#[async_trait]
+impl Embedder for CountingEmbedder {
+    fn model(&self) -> &str {
+        "hashing"
+    }
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, AssistantError> {
+        self.embedded.fetch_add(texts.len(), Ordering::SeqCst);
+        HashingEmbedder.embed(texts).await
+    }
+}
+
+// An index on a fresh SQLite database, deleted with the returned path
+async fn index(embedder: Arc<dyn Embedder>) -> (RetrievalIndex, TempPath) {
+    let path = TempPath::new("retrieval");
+    let config = RetrievalConfig {
+        enabled: true,
+        database_url: path.sqlite_url(),
+        embedder: EmbedderKind::Hashing,
+        top_k: 2,
+        ..Default::default()
+    };
+    let index = RetrievalIndex::connect(&config, embedder)
+        .await
+        .expect("Failed to open the index");
+    (index, path)
+}
+
+fn document(source: &str, text: &str) -> Document {
+    Document {
+        source: source.to_string(),
+        text: text.to_string(),
+    }
+}
+
+#[test]
+fn test_chunking() {
+    let words: Vec<String> = (0..100).map(|i| format!("word{:02}", i)).collect();
+    let chunks = chunk_document(&document("guide.txt", &words.join(" ")), 60, 14);
+    assert!(chunks.len() > 1);
+    assert!(chunks.iter().all(|chunk| chunk.len() <= 60), "{:?}", chunks);
+    // Consecutive chunks share the words within the overlap
+    assert!(
+        chunks[1].starts_with("word06 word07 word08"),
+        "{:?}",
+        chunks
+    );
+    assert!(chunks.last().unwrap().ends_with("word99"));
+
+    // Every item of a JSON array is chunked on its own
+    let bikes =
+        r#"[{"slug": "canyon-grail", "price": 1800}, {"slug": "trek-fuel", "price": 2500}]"#;
+    let chunks = chunk_document(&document("bikes.json", bikes), 1000, 200);
+    assert_eq!(chunks.len(), 2);
+    assert!(chunks[0].contains("canyon-grail"));
+    assert!(chunks[1].contains("trek-fuel"));
+}
+
+#[tokio::test]
+async fn test_search() {
+    let (index, _path) = index(Arc::new(HashingEmbedder)).await;
+    let documents = [
+        document(
+            "shipping.txt",
+            "We ship bikes with DHL, shipping takes 5 days.",
+        ),
+        document("returns.txt", "You can return a bike within 14 days."),
+        document(
+            "sizes.txt",
+            "Frame sizes depend on the height of the rider.",
+        ),
+    ];
+    assert_eq!(index.rebuild(&documents).await.unwrap(), 3);
+    let results = index.search("How does shipping work?", 2).await.unwrap();
+    assert_eq!(results[0].source, "shipping.txt");
+    assert!(results.len() <= 2);
+    assert!(results
+        .windows(2)
+        .all(|pair| pair[0].score >= pair[1].score));
+    // Chunks without a common word are not returned
+    assert!(index.search("xylophone", 2).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_rebuild_reuses_embeddings() {
+    let embedder = Arc::new(CountingEmbedder::default());
+    let (index, _path) = index(embedder.clone()).await;
+    let mut documents = vec![
+        document("shipping.txt", "We ship bikes with DHL."),
+        document("returns.txt", "You can return a bike within 14 days."),
+    ];
+    index.rebuild(&documents).await.unwrap();
+    assert_eq!(embedder.embedded.load(Ordering::SeqCst), 2);
+    // Only the changed document is embedded again, removed chunks are not found anymore
+    documents[1] = document("returns.txt", "Returns are free within 30 days.");
+    index.rebuild(&documents).await.unwrap();
+    assert_eq!(embedder.embedded.load(Ordering::SeqCst), 3);
+    let results = index.search("return within 14 days", 5).await.unwrap();
+    assert!(results.iter().all(|result| !result.content.contains("14")));
+}
+
+#[test]
+fn test_load_documents() {
+    let temp = TempPath::new("documents");
+    let folder = temp.path();
+    std::fs::create_dir_all(folder).unwrap();
+    std::fs::write(folder.join("b.txt"), "second").unwrap();
+    std::fs::write(folder.join("a.txt"), "first").unwrap();
+    // Binary files are skipped
+    std::fs::write(folder.join("logo.png"), [0x89, 0x50, 0xff, 0xfe]).unwrap();
+    let folder = folder.display().to_string();
+    let documents = load_documents(&[folder.as_str(), "missing/folder"]).unwrap();
+    let sources: Vec<&str> = documents.iter().map(|d| d.source.as_str()).collect();
+    assert_eq!(sources, vec!["a.txt", "b.txt"]);
+}
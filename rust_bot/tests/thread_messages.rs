@@ -0,0 +1,174 @@
+use rust_bot::assistant::{
+    resolve_citations, simplify_message, AssistantError, ChatMessage, FileInfo, MessageListQuery,
+    RunContext, TextContent,
+};
+use serde_json::json;
+
+const MARKER: &str = "【4:0†help_articles.json】";
+
+fn files() -> Vec<FileInfo> {
+    vec![FileInfo {
+        file_id: "file-help".to_string(),
+        file_name: "help_articles.json".to_string(),
+    }]
+}
+
+fn context(locale: Option<&str>, country: Option<&str>) -> RunContext {
+    RunContext {
+        locale: locale.map(str::to_string),
+        country: country.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+fn citation(marker: &str, file_id: &str) -> serde_json::Value {
+    json!({
+        "type": "file_citation",
+        "text": marker,
+        "file_citation": { "file_id": file_id },
+        "file_path": null,
+    })
+}
+
+fn text(value: &str, annotations: Vec<serde_json::Value>) -> TextContent {
+    serde_json::from_value(json!({ "value": value, "annotations": annotations })).unwrap()
+}
+
+fn message(content: serde_json::Value) -> ChatMessage {
+    serde_json::from_value(json!({
+        "id": "msg_1",
+        "created_at": 1700000000,
+        "role": "assistant",
+        "content": content,
+        "run_id": "run_1",
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_citations_link_the_market_of_the_user() {
+    let mut citations = Vec::new();
+    let resolved = resolve_citations(
+        text(
+            &format!("Returns take 14 days{}.", MARKER),
+            vec![citation(MARKER, "file-help")],
+        ),
+        &files(),
+        &context(Some("fr-CA"), None),
+        &mut citations,
+    );
+    assert_eq!(
+        resolved,
+        "Returns take 14 days[1](https://buycycle.com/fr-ca/help)."
+    );
+    assert_eq!(citations.len(), 1);
+    assert_eq!(citations[0].file_id, "file-help");
+    assert_eq!(
+        citations[0].file_name.as_deref(),
+        Some("help_articles.json")
+    );
+
+    // Without a market the default locale path is linked
+    let resolved = resolve_citations(
+        text(MARKER, vec![citation(MARKER, "file-help")]),
+        &files(),
+        &RunContext::default(),
+        &mut Vec::new(),
+    );
+    assert_eq!(resolved, "[1](https://buycycle.com/en-de/help)");
+}
+
+#[test]
+fn test_citations_of_rotated_files() {
+    // The files were uploaded again, the file ID of the old message is unknown
+    let second = "【4:1†help_articles.json】";
+    let mut citations = Vec::new();
+    let resolved = resolve_citations(
+        text(
+            &format!("Shipping{} and returns{}.", MARKER, second),
+            vec![citation(MARKER, "file-old"), citation(second, "file-old")],
+        ),
+        &files(),
+        &context(None, Some("US")),
+        &mut citations,
+    );
+    assert_eq!(
+        resolved,
+        "Shipping[1](https://buycycle.com/en-us/help) and returns[1](https://buycycle.com/en-us/help)."
+    );
+    assert_eq!(citations.len(), 1);
+
+    // Markers without a file name are resolved by the file ID, unknown files are not linked
+    let mut citations = Vec::new();
+    let resolved = resolve_citations(
+        text(
+            "Shipping【0】 and bikes【1】.",
+            vec![
+                citation("【0】", "file-help"),
+                citation("【1】", "file-other"),
+            ],
+        ),
+        &files(),
+        &RunContext::default(),
+        &mut citations,
+    );
+    assert_eq!(
+        resolved,
+        "Shipping[1](https://buycycle.com/en-de/help) and bikes[2]."
+    );
+    assert_eq!(citations[1].file_name, None);
+}
+
+#[test]
+fn test_simplify_message() {
+    let simplified = simplify_message(
+        message(json!([
+            {
+                "type": "text",
+                "text": { "value": format!("Returns{}.", MARKER), "annotations": [citation(MARKER, "file-help")] },
+            },
+            { "type": "image_file", "image_file": { "file_id": "file-image" } },
+            {
+                "type": "text",
+                "text": { "value": format!("Refunds{}.", MARKER), "annotations": [citation(MARKER, "file-help")] },
+            },
+        ])),
+        &files(),
+        &context(Some("de-DE"), None),
+    )
+    .unwrap();
+    assert_eq!(simplified.id.as_deref(), Some("msg_1"));
+    assert_eq!(simplified.role, "assistant");
+    // The text parts are joined and share the citations
+    assert_eq!(
+        simplified.text,
+        "Returns[1](https://buycycle.com/de-de/help).\n\nRefunds[1](https://buycycle.com/de-de/help)."
+    );
+    assert_eq!(simplified.citations.len(), 1);
+    assert_eq!(simplified.image_file_ids, vec!["file-image"]);
+
+    // Messages without text or image are skipped
+    assert!(simplify_message(message(json!([])), &files(), &RunContext::default()).is_none());
+}
+
+#[test]
+fn test_message_list_query() {
+    let query = |limit: Option<u32>, order: Option<&str>| MessageListQuery {
+        limit,
+        order: order.map(str::to_string),
+        ..Default::default()
+    };
+    assert!(MessageListQuery::default().validate().is_ok());
+    assert!(query(Some(1), Some("asc")).validate().is_ok());
+    assert!(query(Some(100), Some("desc")).validate().is_ok());
+    for invalid in [
+        query(Some(0), None),
+        query(Some(101), None),
+        query(None, Some("newest")),
+    ] {
+        assert!(matches!(
+            invalid.validate(),
+            Err(AssistantError::InvalidInput(_))
+        ));
+    }
+}